@@ -12,6 +12,10 @@ use atmega_hal::{
     prelude::*,
     usart::{Baudrate, Usart},
 };
+use sinodude_protocol::{
+    crc32_update, frame, AddressLength, Capabilities, Command, Failure, FlashRange, Reason,
+    Response, BAUD_CHECK_MS, BAUD_RATE, BAUD_RATES, MAX_DATA_LEN,
+};
 
 // ICP Pin assignments (matching reference implementation)
 // TDO - D2 (input)
@@ -21,37 +25,108 @@ use atmega_hal::{
 // Power - D6 (output)
 
 // Firmware version
-const VERSION_MAJOR: u8 = 2;
-const VERSION_MINOR: u8 = 0;
-
-// Serial protocol commands
-mod cmd {
-    // System commands
-    pub const CMD_PING: u8 = 0x01;
-    pub const CMD_GET_VERSION: u8 = 0x02;
-
-    // Connection
-    pub const CMD_CONNECT: u8 = 0x03;
-    pub const CMD_DISCONNECT: u8 = 0x04;
-
-    // Identification
-    pub const CMD_GET_ID: u8 = 0x05;
-    // Configuration
-    pub const CMD_SET_CONFIG: u8 = 0x06;
-    pub const CMD_GET_CONFIG: u8 = 0x07;
-
-    // Memory operations
-    pub const CMD_READ_FLASH: u8 = 0x08;
-    pub const CMD_WRITE_FLASH: u8 = 0x09;
-    pub const CMD_ERASE_FLASH_SECTOR: u8 = 0x0A;
-    pub const CMD_MASS_ERASE: u8 = 0x0B;
-    pub const CMD_READ_CUSTOM_REGION: u8 = 0x0C;
-    pub const CMD_WRITE_CUSTOM_REGION: u8 = 0x0D;
-
-    // Response codes
-    pub const RSP_OK: u8 = 0x00;
-    pub const RSP_ERR: u8 = 0xFF;
-    pub const RSP_DATA: u8 = 0x01;
+const VERSION_MAJOR: u8 = 3;
+const VERSION_MINOR: u8 = 11;
+
+// Time a target left in ICP or debug mode stays unpowered before it runs its firmware
+const RESTART_OFF_MS: u8 = 100;
+
+// Polls (5ms apart) for the end of a mass erase before giving up, about 10s
+const MASS_ERASE_TIMEOUT_POLLS: u16 = 2000;
+
+// Longest response payload replayed for a retransmitted request: errors, checksums and
+// the end of a stream
+const CACHED_RESPONSE_MAX: usize = 4;
+
+// Protocol commands that need registers not yet confirmed on hardware: the on-chip debug
+// registers and the EEPROM selection. The main loop answers them as unknown.
+const UNHANDLED_COMMANDS: [Command; 12] = [
+    Command::DebugHalt,
+    Command::DebugResume,
+    Command::DebugReadMemory,
+    Command::DebugWriteMemory,
+    Command::DebugStep,
+    Command::DebugSetBreakpoint,
+    Command::DebugClearBreakpoint,
+    Command::DebugStatus,
+    Command::DebugSetPc,
+    Command::ReadEeprom,
+    Command::WriteEeprom,
+    Command::EraseEepromSector,
+];
+
+// Commands reported by CMD_GET_CAPABILITIES, the main loop handles all of them
+const SUPPORTED_COMMANDS: u32 = Command::bits(&Command::ALL) & !Command::bits(&UNHANDLED_COMMANDS);
+
+// Chip types 0-7 all share the ICP sequences (bitmap by chip type)
+const SUPPORTED_CHIP_TYPES: u16 = 0x00FF;
+
+// Flash bytes per frame of a streaming read
+const STREAM_CHUNK: usize = 1024;
+
+// Polls of 10us to wait for a valid frame at a new rate before reverting to BAUD_RATE
+const BAUD_CHECK_POLLS: u32 = BAUD_CHECK_MS * 100;
+
+// Polls of 10us to wait for the next byte of a frame before giving up on it (50ms)
+const RX_TIMEOUT_POLLS: u16 = 5000;
+
+struct Request {
+    seq: u8,
+    cmd: u8,
+    len: usize,
+}
+
+/// Receive the rest of a frame after its SYNC byte, storing the payload in `buffer`.
+/// Returns None if a byte times out, the frame is too long or the CRC does not match.
+fn receive_frame(read: &mut impl FnMut() -> Option<u8>, buffer: &mut [u8]) -> Option<Request> {
+    let mut header = [0u8; frame::HEADER_LEN];
+    let mut crc: u16 = 0xFFFF;
+    for byte in header.iter_mut() {
+        *byte = read()?;
+        crc = frame::crc16_update(crc, *byte);
+    }
+
+    let header = frame::Header::decode(header);
+    let len = header.len as usize;
+    if len > buffer.len() {
+        return None;
+    }
+
+    for byte in buffer[..len].iter_mut() {
+        *byte = read()?;
+        crc = frame::crc16_update(crc, *byte);
+    }
+
+    let crc_low = read()?;
+    let crc_high = read()?;
+    if u16::from_le_bytes([crc_low, crc_high]) != crc {
+        return None;
+    }
+
+    Some(Request {
+        seq: header.seq,
+        cmd: header.code,
+        len,
+    })
+}
+
+fn send_frame(write: &mut impl FnMut(u8), seq: u8, code: Response, payload: &[u8]) {
+    let header = frame::Header {
+        len: payload.len() as u16,
+        seq,
+        code: code as u8,
+    };
+    let mut crc: u16 = 0xFFFF;
+
+    write(frame::SYNC);
+    for &byte in header.encode().iter().chain(payload) {
+        crc = frame::crc16_update(crc, byte);
+        write(byte);
+    }
+
+    let crc = crc.to_le_bytes();
+    write(crc[0]);
+    write(crc[1]);
 }
 
 // ICP Commands (from reference)
@@ -66,7 +141,28 @@ mod icp_cmd {
     pub const ICP_SET_XPAGE: u8 = 0x4C;
 }
 
+/// Memory reached through the ICP read and program sequences
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum IcpRegion {
+    Flash,
+    Custom,
+}
+
+impl IcpRegion {
+    fn for_command(cmd: Command) -> Self {
+        match cmd {
+            Command::ReadCustomRegion | Command::WriteCustomRegion => IcpRegion::Custom,
+            _ => IcpRegion::Flash,
+        }
+    }
+}
+
 mod jtag_instructions {
+    // 4-bit debug mode: 4 while configuring, 1 to let the core run
+    pub const JTAG_MODE: u8 = 2;
+    // 23-bit debug register write, the register in bits 22-16 and the value in bits 15-0
+    pub const JTAG_DEBUG_WRITE: u8 = 3;
+    pub const JTAG_RUN: u8 = 12;
     pub const JTAG_IDCODE: u8 = 14;
 }
 
@@ -119,6 +215,22 @@ impl IcpController {
     fn power_off(&mut self) {
         self.pins.power.set_high();
         self.connected = false;
+        // An unpowered target must not be fed through the ICP pins
+        self.pins.tck.set_low();
+        self.pins.tdi.set_low();
+        self.pins.tms.set_low();
+        self.mode = Mode::Unset;
+    }
+
+    /// Power the target with the ICP pins idle, so that it runs its own firmware. Any
+    /// target whose pins were driven since the last power off is restarted first, as
+    /// it may be in ICP or debug mode.
+    fn power_run(&mut self) {
+        if self.mode != Mode::Unset {
+            self.power_off();
+            self.delay.delay_ms(RESTART_OFF_MS);
+        }
+        self.power_on();
     }
 
     fn delay_us(&mut self, us: u32) {
@@ -157,7 +269,7 @@ impl IcpController {
         self.chip_type = Some(chip_type);
     }
 
-    fn connect(&mut self) -> bool {
+    fn connect(&mut self) -> Result<(), Failure> {
         self.power_on();
 
         // Wait for power stabilization
@@ -230,10 +342,10 @@ impl IcpController {
         // Verify connection with ping
         if self.check() {
             self.connected = true;
-            true
+            Ok(())
         } else {
             self.connected = false;
-            false
+            Err(Failure::new(Reason::ConnectFailed, 0))
         }
     }
 
@@ -345,10 +457,10 @@ impl IcpController {
                 self.jtag_next_state(true);
             }
 
-            self.jtag_send_instruction(2);
+            self.jtag_send_instruction(jtag_instructions::JTAG_MODE);
             self.jtag_send_data(4, 4u8);
 
-            self.jtag_send_instruction(3);
+            self.jtag_send_instruction(jtag_instructions::JTAG_DEBUG_WRITE);
             self.jtag_send_data(23, 0x403000u32);
             self.delay_us(50);
             self.jtag_send_data(23, 0x402000u32);
@@ -367,10 +479,10 @@ impl IcpController {
                 self.jtag_send_data(23, 0x7F0000u32);
             }
 
-            self.jtag_send_instruction(2);
+            self.jtag_send_instruction(jtag_instructions::JTAG_MODE);
             self.jtag_send_data(4, 1u8);
 
-            self.jtag_send_instruction(12);
+            self.jtag_send_instruction(jtag_instructions::JTAG_RUN);
         } else {
             panic!("Invalid mode switch");
         }
@@ -534,11 +646,29 @@ impl IcpController {
         value
     }
 
-    fn icp_read_flash(&mut self, addr: u32, buffer: &mut [u8], custom_block: bool) -> bool {
+    /// Point the ICP engine at `addr`. Chip types 4 and 7 take the address bits above 16
+    /// through XPAGE.
+    fn icp_set_address(&mut self, chip_type: u8, addr: u32) {
+        self.send_icp_byte(icp_cmd::ICP_SET_IB_OFFSET_L);
+        self.send_icp_byte((addr & 0xFF) as u8);
+        self.send_icp_byte(icp_cmd::ICP_SET_IB_OFFSET_H);
+        self.send_icp_byte(((addr & 0xFF00) >> 8) as u8);
+        if chip_type == 4 || chip_type == 7 {
+            self.send_icp_byte(icp_cmd::ICP_SET_XPAGE);
+            self.send_icp_byte(((addr & 0xFF0000) >> 16) as u8);
+        }
+    }
+
+    fn icp_read_region(
+        &mut self,
+        addr: u32,
+        buffer: &mut [u8],
+        region: IcpRegion,
+    ) -> Result<(), Failure> {
         self.switch_mode(Mode::Icp);
 
         let Some(chip_type) = self.chip_type else {
-            return false;
+            return Err(Failure::new(Reason::NoChipType, 0));
         };
 
         if chip_type != 1 {
@@ -547,22 +677,14 @@ impl IcpController {
             self.send_icp_byte(0xFF);
         }
 
-        self.send_icp_byte(icp_cmd::ICP_SET_IB_OFFSET_L);
-        self.send_icp_byte((addr & 0xFF) as u8);
-        self.send_icp_byte(icp_cmd::ICP_SET_IB_OFFSET_H);
-        self.send_icp_byte(((addr & 0xFF00) >> 8) as u8);
+        self.icp_set_address(chip_type, addr);
 
-        if chip_type == 4 || chip_type == 7 {
-            self.send_icp_byte(icp_cmd::ICP_SET_XPAGE);
-            self.send_icp_byte(((addr & 0xFF0000) >> 16) as u8);
-        }
-
-        let region = if custom_block {
+        let read_cmd = if region == IcpRegion::Custom {
             icp_cmd::ICP_READ_CUSTOM_BLOCK
         } else {
             icp_cmd::ICP_READ_FLASH
         };
-        self.send_icp_byte(region);
+        self.send_icp_byte(read_cmd);
 
         for byte in buffer.iter_mut() {
             *byte = self.receive_icp_byte();
@@ -570,14 +692,19 @@ impl IcpController {
 
         self.reset();
 
-        true
+        Ok(())
     }
 
-    fn icp_write_region(&mut self, addr: u32, data: &[u8], custom_block: bool) -> bool {
+    fn icp_write_region(
+        &mut self,
+        addr: u32,
+        data: &[u8],
+        region: IcpRegion,
+    ) -> Result<(), Failure> {
         self.switch_mode(Mode::Icp);
 
         let Some(chip_type) = self.chip_type else {
-            return false;
+            return Err(Failure::new(Reason::NoChipType, 0));
         };
 
         if chip_type != 1 {
@@ -586,20 +713,17 @@ impl IcpController {
             self.send_icp_byte(0xFF);
         }
 
-        self.send_icp_byte(icp_cmd::ICP_SET_IB_OFFSET_L);
-        self.send_icp_byte((addr & 0xFF) as u8);
-        self.send_icp_byte(icp_cmd::ICP_SET_IB_OFFSET_H);
-        self.send_icp_byte(((addr & 0xFF00) >> 8) as u8);
-        if chip_type == 4 || chip_type == 7 {
-            self.send_icp_byte(icp_cmd::ICP_SET_XPAGE);
-            self.send_icp_byte(((addr & 0xFF0000) >> 16) as u8);
-        }
+        self.icp_set_address(chip_type, addr);
 
         self.send_icp_byte(icp_cmd::ICP_SET_IB_DATA);
         self.send_icp_byte(data[0]);
 
         // Command byte: 0xa5 for custom region, 0x6e for flash
-        let cmd = if custom_block { 0xa5 } else { 0x6e };
+        let cmd = if region == IcpRegion::Custom {
+            0xa5
+        } else {
+            0x6e
+        };
         self.send_icp_byte(cmd);
         self.send_icp_byte(0x15);
         self.send_icp_byte(0x0a);
@@ -611,15 +735,15 @@ impl IcpController {
 
         self.send_icp_byte(0x00);
         if !self.tdo_read() {
-            return false;
+            return Err(Failure::new(Reason::NoAck, 1));
         }
 
-        for byte in data.iter().skip(2) {
+        for (i, byte) in data.iter().enumerate().skip(2) {
             self.send_icp_byte(*byte);
             self.delay_us(5);
             self.send_icp_byte(0x00);
             if !self.tdo_read() {
-                return false;
+                return Err(Failure::new(Reason::NoAck, i as u16));
             }
         }
 
@@ -627,25 +751,21 @@ impl IcpController {
         self.send_icp_byte(0xaa);
         // TDO must go high here to indicate success
         if !self.tdo_read() {
-            return false;
+            return Err(Failure::new(Reason::NoAck, data.len() as u16));
         }
         self.send_icp_byte(0x00);
         self.send_icp_byte(0x00);
 
         self.delay_us(5);
 
-        true
+        Ok(())
     }
 
-    fn icp_write_flash(&mut self, addr: u32, data: &[u8]) -> bool {
-        self.icp_write_region(addr, data, false)
-    }
-
-    fn icp_mass_erase(&mut self, alternate: bool) -> bool {
+    fn icp_mass_erase(&mut self, alternate: bool) -> Result<(), Failure> {
         self.switch_mode(Mode::Icp);
 
         let Some(chip_type) = self.chip_type else {
-            return false;
+            return Err(Failure::new(Reason::NoChipType, 0));
         };
 
         if chip_type != 1 {
@@ -654,14 +774,7 @@ impl IcpController {
             self.send_icp_byte(0xFF);
         }
 
-        self.send_icp_byte(icp_cmd::ICP_SET_IB_OFFSET_L);
-        self.send_icp_byte(0x00);
-        self.send_icp_byte(icp_cmd::ICP_SET_IB_OFFSET_H);
-        self.send_icp_byte(0x00);
-        if chip_type == 4 || chip_type == 7 {
-            self.send_icp_byte(icp_cmd::ICP_SET_XPAGE);
-            self.send_icp_byte(0x00);
-        }
+        self.icp_set_address(chip_type, 0);
 
         self.send_icp_byte(icp_cmd::ICP_SET_IB_DATA);
         self.send_icp_byte(0x00);
@@ -679,19 +792,24 @@ impl IcpController {
         self.pins.tdi.set_high(); // keep tdi line high
 
         self.delay.delay_ms(30u8);
-        while !self.tdo_read() {
+        for _ in 0..MASS_ERASE_TIMEOUT_POLLS {
+            if self.tdo_read() {
+                return Ok(());
+            }
             self.delay.delay_ms(5u8);
             self.send_icp_byte(0x00);
         }
 
-        true // TODO: at some point, time out and return false
+        // Leave the target in a known state instead of mid-erase
+        self.power_off();
+        Err(Failure::new(Reason::Timeout, 0))
     }
 
-    fn icp_erase_flash(&mut self, addr: u32) -> bool {
+    fn icp_erase_flash(&mut self, addr: u32) -> Result<(), Failure> {
         self.switch_mode(Mode::Icp);
 
         let Some(chip_type) = self.chip_type else {
-            return false;
+            return Err(Failure::new(Reason::NoChipType, 0));
         };
 
         if chip_type != 1 {
@@ -700,14 +818,7 @@ impl IcpController {
             self.send_icp_byte(0xFF);
         }
 
-        self.send_icp_byte(icp_cmd::ICP_SET_IB_OFFSET_L);
-        self.send_icp_byte((addr & 0xFF) as u8);
-        self.send_icp_byte(icp_cmd::ICP_SET_IB_OFFSET_H);
-        self.send_icp_byte(((addr & 0xFF00) >> 8) as u8);
-        if chip_type == 4 || chip_type == 7 {
-            self.send_icp_byte(icp_cmd::ICP_SET_XPAGE);
-            self.send_icp_byte(((addr & 0xFF0000) >> 16) as u8);
-        }
+        self.icp_set_address(chip_type, addr);
 
         self.send_icp_byte(icp_cmd::ICP_SET_IB_DATA);
         self.send_icp_byte(0x00);
@@ -724,11 +835,11 @@ impl IcpController {
         let status = self.pins.tdo.is_high();
         self.send_icp_byte(0x00);
 
-        status
-    }
-
-    fn icp_write_custom_region(&mut self, addr: u32, data: &[u8]) -> bool {
-        self.icp_write_region(addr, data, true)
+        if status {
+            Ok(())
+        } else {
+            Err(Failure::new(Reason::EraseStatus, 0))
+        }
     }
 }
 
@@ -745,7 +856,7 @@ fn main() -> ! {
         dp.USART0,
         pins.pd0,
         pins.pd1.into_output(),
-        Baudrate::<MHz16>::new(115200),
+        Baudrate::<MHz16>::new(BAUD_RATE),
     );
 
     let (mut rx, mut tx) = serial.split();
@@ -763,212 +874,330 @@ fn main() -> ! {
 
     icp.init();
 
-    // Buffer for flash operations
-    let mut buffer: [u8; 1024] = [0; 1024];
+    let mut delay = Delay::<MHz16>::new();
+
+    // Buffer for request payloads and flash operations
+    let mut buffer: [u8; frame::MAX_PAYLOAD] = [0; frame::MAX_PAYLOAD];
+
+    // SEQ, command, status and short payload of the last response. A retransmitted
+    // request (same SEQ) gets this response again instead of running twice. Requests
+    // answered with more data only read state, so those simply run again.
+    let mut last_response: Option<(u8, u8, Response, [u8; CACHED_RESPONSE_MAX], usize)> = None;
+
+    // Polls left for the host to send a valid frame after a rate change, 0 if none pending
+    let mut baud_check_polls: u32 = 0;
 
     loop {
-        // Wait for command
-        let Ok(cmd_byte) = nb::block!(rx.read());
-        match cmd_byte {
-            cmd::CMD_PING => {
+        // Wait for the start of a frame
+        let sync = if baud_check_polls > 0 {
+            match rx.read() {
+                Ok(byte) => byte,
+                Err(_) => {
+                    delay.delay_us(10u32);
+                    baud_check_polls -= 1;
+                    if baud_check_polls == 0 {
+                        // The host never got through, go back to the default rate
+                        let (usart, rx_pin, tx_pin) = rx.reunite(tx).release();
+                        let serial =
+                            Usart::new(usart, rx_pin, tx_pin, Baudrate::<MHz16>::new(BAUD_RATE));
+                        (rx, tx) = serial.split();
+                    }
+                    continue;
+                }
+            }
+        } else {
+            let Ok(byte) = nb::block!(rx.read());
+            byte
+        };
+        if sync != frame::SYNC {
+            // The host probes with a bare ping byte, which 2.x firmware answers unframed
+            if sync == Command::Ping as u8 {
+                let mut write = |byte: u8| {
+                    let _ = nb::block!(tx.write(byte));
+                };
+                send_frame(&mut write, 0, Response::Ok, b"SW");
+            }
+            continue;
+        }
+
+        let mut read = || {
+            for _ in 0..RX_TIMEOUT_POLLS {
+                if let Ok(byte) = rx.read() {
+                    return Some(byte);
+                }
+                delay.delay_us(10u32);
+            }
+            None
+        };
+        let mut write = |byte: u8| {
+            let _ = nb::block!(tx.write(byte));
+        };
+
+        let Some(request) = receive_frame(&mut read, &mut buffer) else {
+            // Let the rest of the garbled frame pass, then ask for a retransmit. Its SEQ
+            // cannot be trusted, so the host takes a FrameError as the NAK of whatever
+            // request it has in flight.
+            while read().is_some() {}
+            send_frame(&mut write, 0, Response::FrameError, &[]);
+            continue;
+        };
+
+        // A valid frame confirms the current rate
+        baud_check_polls = 0;
+
+        if let Some((seq, cmd, status, payload, len)) = last_response {
+            if seq == request.seq && cmd == request.cmd {
+                send_frame(&mut write, seq, status, &payload[..len]);
+                continue;
+            }
+        }
+
+        // Each command leaves its response payload at the start of the buffer
+        let payload_len = request.len;
+        let bad_parameters = Failure::new(Reason::BadParameters, payload_len as u16);
+        // Rate to switch to once the response has been sent
+        let mut new_baud = None;
+        let result = match Command::from_u8(request.cmd) {
+            Some(Command::Ping) => {
                 // Simple ping response
-                let _ = nb::block!(tx.write(cmd::RSP_OK));
-                let _ = nb::block!(tx.write(b'S'));
-                let _ = nb::block!(tx.write(b'W'));
+                buffer[0] = b'S';
+                buffer[1] = b'W';
+                Ok((Response::Ok, 2))
             }
 
-            cmd::CMD_GET_VERSION => {
+            Some(Command::GetVersion) => {
                 // Return firmware version (major, minor)
-                let _ = nb::block!(tx.write(cmd::RSP_DATA));
-                let _ = nb::block!(tx.write(VERSION_MAJOR));
-                let _ = nb::block!(tx.write(VERSION_MINOR));
+                buffer[0] = VERSION_MAJOR;
+                buffer[1] = VERSION_MINOR;
+                Ok((Response::Data, 2))
             }
 
-            cmd::CMD_CONNECT => {
-                if icp.connect() {
-                    let _ = nb::block!(tx.write(cmd::RSP_OK));
-                } else {
-                    let _ = nb::block!(tx.write(cmd::RSP_ERR));
-                }
-            }
+            Some(Command::Connect) => icp.connect().map(|()| (Response::Ok, 0)),
 
-            cmd::CMD_DISCONNECT => {
+            Some(Command::Disconnect) => {
                 icp.disconnect();
-                let _ = nb::block!(tx.write(cmd::RSP_OK));
+                Ok((Response::Ok, 0))
+            }
+
+            Some(Command::PowerOn) => {
+                icp.power_run();
+                Ok((Response::Ok, 0))
             }
 
-            cmd::CMD_GET_ID => {
+            Some(Command::PowerOff) => {
+                icp.power_off();
+                Ok((Response::Ok, 0))
+            }
+
+            Some(Command::GetId) => {
                 let id = icp.jtag_get_id();
-                let _ = nb::block!(tx.write(cmd::RSP_DATA));
-                let _ = nb::block!(tx.write((id & 0xFF) as u8));
-                let _ = nb::block!(tx.write((id >> 8) as u8));
+                buffer[..2].copy_from_slice(&id.to_le_bytes());
+                Ok((Response::Data, 2))
             }
 
-            cmd::CMD_SET_CONFIG => {
-                let chip_type = nb::block!(rx.read()).unwrap_or(0);
-                icp.set_chip_type(chip_type);
-                let _ = nb::block!(tx.write(cmd::RSP_OK));
+            Some(Command::SetConfig) => {
+                if payload_len == 1 {
+                    icp.set_chip_type(buffer[0]);
+                    Ok((Response::Ok, 0))
+                } else {
+                    Err(bad_parameters)
+                }
             }
 
-            cmd::CMD_GET_CONFIG => {
+            Some(Command::GetConfig) => {
                 if let Some(chip_type) = icp.chip_type {
-                    let _ = nb::block!(tx.write(cmd::RSP_DATA));
-                    let _ = nb::block!(tx.write(chip_type));
+                    buffer[0] = chip_type;
+                    Ok((Response::Data, 1))
                 } else {
-                    let _ = nb::block!(tx.write(cmd::RSP_ERR));
+                    Err(Failure::new(Reason::NoChipType, 0))
                 }
             }
 
-            cmd::CMD_READ_FLASH => {
-                // Read address (4 bytes) and length (2 bytes)
-                let addr = {
-                    let b0 = nb::block!(rx.read()).unwrap_or(0);
-                    let b1 = nb::block!(rx.read()).unwrap_or(0);
-                    let b2 = nb::block!(rx.read()).unwrap_or(0);
-                    let b3 = nb::block!(rx.read()).unwrap_or(0);
-                    u32::from_le_bytes([b0, b1, b2, b3])
-                };
-                let len = {
-                    let low = nb::block!(rx.read()).unwrap_or(0);
-                    let high = nb::block!(rx.read()).unwrap_or(0);
-                    u16::from_le_bytes([low, high]) as usize
-                };
-
-                // Clamp length to buffer size
-                let read_len = len.min(buffer.len());
-
-                if icp.icp_read_flash(addr, &mut buffer[..read_len], false) {
-                    let _ = nb::block!(tx.write(cmd::RSP_DATA));
-                    let _ = nb::block!(tx.write(read_len as u8));
-                    let _ = nb::block!(tx.write((read_len >> 8) as u8));
-                    for byte in buffer[..read_len].iter() {
-                        let _ = nb::block!(tx.write(*byte));
+            Some(cmd @ (Command::ReadFlash | Command::ReadCustomRegion)) => {
+                match AddressLength::decode(&buffer[..payload_len]) {
+                    Some(params) if params.len as usize > MAX_DATA_LEN => {
+                        Err(Failure::new(Reason::Length, MAX_DATA_LEN as u16))
                     }
-                } else {
-                    let _ = nb::block!(tx.write(cmd::RSP_ERR));
+                    Some(AddressLength { addr, len }) => {
+                        let len = len as usize;
+                        let region = IcpRegion::for_command(cmd);
+                        icp.icp_read_region(addr, &mut buffer[..len], region)
+                            .map(|()| (Response::Data, len))
+                    }
+                    None => Err(bad_parameters),
                 }
             }
 
-            cmd::CMD_READ_CUSTOM_REGION => {
-                // Read address (4 bytes) and length (2 bytes)
-                let addr = {
-                    let b0 = nb::block!(rx.read()).unwrap_or(0);
-                    let b1 = nb::block!(rx.read()).unwrap_or(0);
-                    let b2 = nb::block!(rx.read()).unwrap_or(0);
-                    let b3 = nb::block!(rx.read()).unwrap_or(0);
-                    u32::from_le_bytes([b0, b1, b2, b3])
-                };
-                let len = {
-                    let low = nb::block!(rx.read()).unwrap_or(0);
-                    let high = nb::block!(rx.read()).unwrap_or(0);
-                    u16::from_le_bytes([low, high]) as usize
-                };
-
-                // Clamp length to buffer size
-                let read_len = len.min(buffer.len());
-
-                if icp.icp_read_flash(addr, &mut buffer[..read_len], true) {
-                    let _ = nb::block!(tx.write(cmd::RSP_DATA));
-                    let _ = nb::block!(tx.write(read_len as u8));
-                    let _ = nb::block!(tx.write((read_len >> 8) as u8));
-                    for byte in buffer[..read_len].iter() {
-                        let _ = nb::block!(tx.write(*byte));
+            Some(cmd @ (Command::WriteFlash | Command::WriteCustomRegion)) => {
+                // Parameters followed by the data
+                match AddressLength::decode(&buffer[..payload_len]) {
+                    Some(AddressLength { addr, len })
+                        if len >= 2 && payload_len == AddressLength::LEN + len as usize =>
+                    {
+                        let data = &buffer[AddressLength::LEN..payload_len];
+                        let region = IcpRegion::for_command(cmd);
+                        icp.icp_write_region(addr, data, region)
+                            .map(|()| (Response::Ok, 0))
                     }
-                } else {
-                    let _ = nb::block!(tx.write(cmd::RSP_ERR));
+                    _ => Err(bad_parameters),
                 }
             }
 
-            cmd::CMD_WRITE_FLASH => {
-                // Read address (4 bytes) and length (2 bytes)
-                let addr = {
-                    let b0 = nb::block!(rx.read()).unwrap_or(0);
-                    let b1 = nb::block!(rx.read()).unwrap_or(0);
-                    let b2 = nb::block!(rx.read()).unwrap_or(0);
-                    let b3 = nb::block!(rx.read()).unwrap_or(0);
-                    u32::from_le_bytes([b0, b1, b2, b3])
-                };
-                let len = {
-                    let low = nb::block!(rx.read()).unwrap_or(0);
-                    let high = nb::block!(rx.read()).unwrap_or(0);
-                    u16::from_le_bytes([low, high]) as usize
-                };
-
-                // Clamp length to buffer size
-                let write_len = len.min(buffer.len());
-
-                // Read data to write
-                for byte in buffer[..write_len].iter_mut() {
-                    *byte = nb::block!(rx.read()).unwrap_or(0);
+            Some(Command::EraseFlashSector) => {
+                // Address (4 bytes)
+                if payload_len == 4 {
+                    let addr = u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+                    icp.icp_erase_flash(addr).map(|()| (Response::Ok, 0))
+                } else {
+                    Err(bad_parameters)
                 }
+            }
 
-                if icp.icp_write_flash(addr, &buffer[..write_len]) {
-                    let _ = nb::block!(tx.write(cmd::RSP_OK));
+            Some(Command::MassErase) => {
+                if payload_len == 1 {
+                    icp.icp_mass_erase(buffer[0] != 0)
+                        .map(|()| (Response::Ok, 0))
                 } else {
-                    let _ = nb::block!(tx.write(cmd::RSP_ERR));
+                    Err(bad_parameters)
                 }
             }
 
-            cmd::CMD_ERASE_FLASH_SECTOR => {
-                // Read address (4 bytes)
-                let addr = {
-                    let b0 = nb::block!(rx.read()).unwrap_or(0);
-                    let b1 = nb::block!(rx.read()).unwrap_or(0);
-                    let b2 = nb::block!(rx.read()).unwrap_or(0);
-                    let b3 = nb::block!(rx.read()).unwrap_or(0);
-                    u32::from_le_bytes([b0, b1, b2, b3])
-                };
-
-                if icp.icp_erase_flash(addr) {
-                    let _ = nb::block!(tx.write(cmd::RSP_OK));
+            Some(Command::ReadFlashStream) => {
+                if let Some(FlashRange { addr, len }) = FlashRange::decode(&buffer[..payload_len]) {
+                    let mut sent: u32 = 0;
+                    let mut streamed = Ok(());
+                    while sent < len {
+                        // Any byte from the host stops the stream
+                        if rx.read().is_ok() {
+                            streamed = Err(Failure::new(Reason::Aborted, 0));
+                            break;
+                        }
+
+                        let chunk_addr = addr + sent;
+                        let chunk_len = (len - sent).min(STREAM_CHUNK as u32) as usize;
+                        buffer[..4].copy_from_slice(&chunk_addr.to_le_bytes());
+                        streamed = icp.icp_read_region(
+                            chunk_addr,
+                            &mut buffer[4..4 + chunk_len],
+                            IcpRegion::Flash,
+                        );
+                        if streamed.is_err() {
+                            break;
+                        }
+                        send_frame(
+                            &mut write,
+                            request.seq,
+                            Response::Data,
+                            &buffer[..4 + chunk_len],
+                        );
+                        sent += chunk_len as u32;
+                    }
+                    streamed.map(|()| {
+                        buffer[..4].copy_from_slice(&sent.to_le_bytes());
+                        (Response::Ok, 4)
+                    })
                 } else {
-                    let _ = nb::block!(tx.write(cmd::RSP_ERR));
+                    Err(bad_parameters)
                 }
             }
 
-            cmd::CMD_MASS_ERASE => {
-                let alternate = nb::block!(rx.read()).unwrap_or(0) != 0;
-                if icp.icp_mass_erase(alternate) {
-                    let _ = nb::block!(tx.write(cmd::RSP_OK));
+            Some(Command::FlashCrc) => {
+                if let Some(FlashRange { addr, len }) = FlashRange::decode(&buffer[..payload_len]) {
+                    let mut done: u32 = 0;
+                    let mut crc: u32 = 0xFFFF_FFFF;
+                    let mut checksummed = Ok(());
+                    while done < len {
+                        let chunk_len = (len - done).min(buffer.len() as u32) as usize;
+                        checksummed = icp.icp_read_region(
+                            addr + done,
+                            &mut buffer[..chunk_len],
+                            IcpRegion::Flash,
+                        );
+                        if checksummed.is_err() {
+                            break;
+                        }
+                        crc = crc32_update(crc, &buffer[..chunk_len]);
+                        done += chunk_len as u32;
+                    }
+                    checksummed.map(|()| {
+                        buffer[..4].copy_from_slice(&(!crc).to_le_bytes());
+                        (Response::Data, 4)
+                    })
                 } else {
-                    let _ = nb::block!(tx.write(cmd::RSP_ERR));
+                    Err(bad_parameters)
                 }
             }
 
-            cmd::CMD_WRITE_CUSTOM_REGION => {
-                // Read address (4 bytes)
-                let addr = {
-                    let b0 = nb::block!(rx.read()).unwrap_or(0);
-                    let b1 = nb::block!(rx.read()).unwrap_or(0);
-                    let b2 = nb::block!(rx.read()).unwrap_or(0);
-                    let b3 = nb::block!(rx.read()).unwrap_or(0);
-                    u32::from_le_bytes([b0, b1, b2, b3])
+            Some(Command::GetCapabilities) => {
+                let capabilities = Capabilities {
+                    commands: SUPPORTED_COMMANDS,
+                    max_data_len: MAX_DATA_LEN as u16,
+                    chip_types: SUPPORTED_CHIP_TYPES,
+                    max_baud: BAUD_RATES[BAUD_RATES.len() - 1],
                 };
-                // Read length (2 bytes)
-                let len = {
-                    let low = nb::block!(rx.read()).unwrap_or(0);
-                    let high = nb::block!(rx.read()).unwrap_or(0);
-                    u16::from_le_bytes([low, high]) as usize
-                };
-
-                // Clamp length to buffer size
-                let write_len = len.min(buffer.len());
-
-                // Read data to write
-                for byte in buffer[..write_len].iter_mut() {
-                    *byte = nb::block!(rx.read()).unwrap_or(0);
-                }
+                buffer[..Capabilities::LEN].copy_from_slice(&capabilities.encode());
+                Ok((Response::Data, Capabilities::LEN))
+            }
 
-                if icp.icp_write_custom_region(addr, &buffer[..write_len]) {
-                    let _ = nb::block!(tx.write(cmd::RSP_OK));
+            Some(Command::SetBaud) => {
+                // Rate (4 bytes)
+                if payload_len == 4 {
+                    let baud = u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+                    if BAUD_RATES.contains(&baud) {
+                        new_baud = Some(baud);
+                        Ok((Response::Ok, 0))
+                    } else {
+                        Err(bad_parameters)
+                    }
                 } else {
-                    let _ = nb::block!(tx.write(cmd::RSP_ERR));
+                    Err(bad_parameters)
                 }
             }
 
-            _ => {
-                // Unknown command
-                let _ = nb::block!(tx.write(cmd::RSP_ERR));
+            Some(
+                Command::DebugHalt
+                | Command::DebugResume
+                | Command::DebugReadMemory
+                | Command::DebugWriteMemory
+                | Command::DebugStep
+                | Command::DebugSetBreakpoint
+                | Command::DebugClearBreakpoint
+                | Command::DebugStatus
+                | Command::DebugSetPc
+                | Command::ReadEeprom
+                | Command::WriteEeprom
+                | Command::EraseEepromSector,
+            )
+            | None => Err(Failure::new(Reason::UnknownCommand, request.cmd as u16)),
+        };
+
+        let (status, response_len) = match result {
+            Ok(response) => response,
+            Err(failure) => {
+                buffer[..Failure::LEN].copy_from_slice(&failure.encode());
+                (Response::Error, Failure::LEN)
+            }
+        };
+
+        last_response = if response_len <= CACHED_RESPONSE_MAX {
+            let mut payload = [0u8; CACHED_RESPONSE_MAX];
+            payload[..response_len].copy_from_slice(&buffer[..response_len]);
+            Some((request.seq, request.cmd, status, payload, response_len))
+        } else {
+            None
+        };
+        send_frame(&mut write, request.seq, status, &buffer[..response_len]);
+
+        if let Some(baud) = new_baud {
+            // Let the response go out at the old rate before switching. Flushing only
+            // empties the data register, so wait for the last byte to leave the shifter.
+            let _ = nb::block!(tx.flush());
+            delay.delay_us(100u32);
+            let (usart, rx_pin, tx_pin) = rx.reunite(tx).release();
+            let serial = Usart::new(usart, rx_pin, tx_pin, Baudrate::<MHz16>::new(baud));
+            (rx, tx) = serial.split();
+            if baud != BAUD_RATE {
+                baud_check_polls = BAUD_CHECK_POLLS;
             }
         }
     }
@@ -0,0 +1,792 @@
+//! Serial protocol between the sinodude host tool and the sinodude-serial firmware.
+//!
+//! Requests and responses travel in frames (see [`frame`]). Each request carries a
+//! [`Command`], each response a [`Response`] code; failures carry a [`Failure`].
+//! Multi-byte values are little endian throughout.
+
+#![cfg_attr(not(test), no_std)]
+
+// Rate the firmware starts at, and the one both ends fall back to
+pub const BAUD_RATE: u32 = 115200;
+// Rates CMD_SET_BAUD accepts, slowest first. All are exact at 16 MHz.
+pub const BAUD_RATES: [u32; 4] = [BAUD_RATE, 250_000, 500_000, 1_000_000];
+// How long the firmware waits for a valid frame at a new rate before reverting to
+// BAUD_RATE
+pub const BAUD_CHECK_MS: u32 = 1000;
+
+// Most flash bytes a single read or write frame carries
+pub const MAX_DATA_LEN: usize = 1024;
+
+// Hardware breakpoints of the on-chip debugger
+pub const BREAKPOINT_COUNT: usize = 8;
+
+/// Request command byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Command {
+    // System commands
+    Ping = 0x01,
+    /// Answers with the firmware version (major, minor)
+    GetVersion = 0x02,
+
+    // Connection
+    Connect = 0x03,
+    Disconnect = 0x04,
+
+    // Identification
+    GetId = 0x05,
+
+    // Configuration
+    SetConfig = 0x06,
+    GetConfig = 0x07,
+
+    // Memory operations, parameters are an [`AddressLength`]
+    ReadFlash = 0x08,
+    WriteFlash = 0x09,
+    /// Parameter is the address (u32 LE)
+    EraseFlashSector = 0x0A,
+    /// Parameter is 1 for the alternate erase sequence, 0 otherwise
+    MassErase = 0x0B,
+    ReadCustomRegion = 0x0C,
+    WriteCustomRegion = 0x0D,
+
+    /// Parameter is the new baud rate (u32 LE), one of [`BAUD_RATES`]
+    SetBaud = 0x0E,
+
+    /// Streaming read of a [`FlashRange`]: [`Response::Data`] frames of address (u32 LE)
+    /// and data, then a final frame with the status and the number of bytes sent (u32 LE)
+    ReadFlashStream = 0x0F,
+
+    /// CRC32 of a [`FlashRange`], see [`crc32_update`]
+    FlashCrc = 0x10,
+
+    /// Answers with the firmware's [`Capabilities`]
+    GetCapabilities = 0x11,
+
+    // On-chip debugging through JTAG
+    /// Stop the core, answers with its program counter (u16 LE)
+    DebugHalt = 0x12,
+    DebugResume = 0x13,
+    /// Read memory of the halted core, parameters are a [`MemoryAccess`]
+    DebugReadMemory = 0x14,
+    /// Write memory of the halted core, a [`MemoryAccess`] followed by the data
+    DebugWriteMemory = 0x15,
+    /// Run one instruction of the halted core, answers with its new program counter
+    /// (u16 LE)
+    DebugStep = 0x16,
+    /// Parameters are a [`Breakpoint`]
+    DebugSetBreakpoint = 0x17,
+    /// Parameter is the breakpoint index
+    DebugClearBreakpoint = 0x18,
+    /// Answers with the [`CoreStatus`]
+    DebugStatus = 0x19,
+    /// Parameter is the new program counter (u16 LE) of the halted core
+    DebugSetPc = 0x1A,
+
+    // EEPROM (data flash) of parts that have one, parameters as for the flash commands.
+    // Reserved until the EEPROM selection has been confirmed on hardware.
+    ReadEeprom = 0x1B,
+    WriteEeprom = 0x1C,
+    /// Parameter is the address (u32 LE)
+    EraseEepromSector = 0x1D,
+
+    /// Power the target with the ICP pins idle, so that it runs its own firmware. A
+    /// target left in ICP or debug mode is restarted.
+    PowerOn = 0x1E,
+    PowerOff = 0x1F,
+}
+
+impl Command {
+    pub const ALL: [Command; 31] = [
+        Command::Ping,
+        Command::GetVersion,
+        Command::Connect,
+        Command::Disconnect,
+        Command::GetId,
+        Command::SetConfig,
+        Command::GetConfig,
+        Command::ReadFlash,
+        Command::WriteFlash,
+        Command::EraseFlashSector,
+        Command::MassErase,
+        Command::ReadCustomRegion,
+        Command::WriteCustomRegion,
+        Command::SetBaud,
+        Command::ReadFlashStream,
+        Command::FlashCrc,
+        Command::GetCapabilities,
+        Command::DebugHalt,
+        Command::DebugResume,
+        Command::DebugReadMemory,
+        Command::DebugWriteMemory,
+        Command::DebugStep,
+        Command::DebugSetBreakpoint,
+        Command::DebugClearBreakpoint,
+        Command::DebugStatus,
+        Command::DebugSetPc,
+        Command::ReadEeprom,
+        Command::WriteEeprom,
+        Command::EraseEepromSector,
+        Command::PowerOn,
+        Command::PowerOff,
+    ];
+
+    pub const fn from_u8(byte: u8) -> Option<Self> {
+        let mut i = 0;
+        while i < Self::ALL.len() {
+            if Self::ALL[i] as u8 == byte {
+                return Some(Self::ALL[i]);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    /// Bit of this command in the [`Capabilities::commands`] bitmap
+    pub const fn bit(self) -> u32 {
+        1 << self as u8
+    }
+
+    /// Bitmap of `commands`, as reported in [`Capabilities::commands`]
+    pub const fn bits(commands: &[Command]) -> u32 {
+        let mut bits = 0;
+        let mut i = 0;
+        while i < commands.len() {
+            bits |= commands[i].bit();
+            i += 1;
+        }
+        bits
+    }
+}
+
+/// Response code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Response {
+    Ok = 0x00,
+    Data = 0x01,
+    /// Request frame arrived corrupted, the host should retransmit it. Sent with SEQ 0,
+    /// as the SEQ of a corrupted frame cannot be trusted.
+    FrameError = 0xFE,
+    /// Followed by a [`Failure`]
+    Error = 0xFF,
+}
+
+impl Response {
+    pub const fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            0x00 => Some(Response::Ok),
+            0x01 => Some(Response::Data),
+            0xFE => Some(Response::FrameError),
+            0xFF => Some(Response::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Why a command failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Reason {
+    /// SET_CONFIG has not been sent since reset
+    NoChipType = 0x01,
+    /// TDO did not acknowledge a write, detail is the index of the data byte
+    NoAck = 0x02,
+    /// Sector erase status (TDO) stayed low
+    EraseStatus = 0x03,
+    /// Requested length exceeds the buffer, detail is the largest accepted length
+    Length = 0x04,
+    /// Detail is the command byte
+    UnknownCommand = 0x05,
+    /// Target did not answer the ICP check after entering ICP mode
+    ConnectFailed = 0x06,
+    /// Payload has the wrong size or an unsupported value, detail is the payload length
+    BadParameters = 0x07,
+    /// Target did not finish the operation in time and has been powered off
+    Timeout = 0x08,
+    /// Host stopped a stream
+    Aborted = 0x09,
+    /// Memory access needs the core halted first
+    NotHalted = 0x0A,
+}
+
+impl Reason {
+    pub const fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            0x01 => Some(Reason::NoChipType),
+            0x02 => Some(Reason::NoAck),
+            0x03 => Some(Reason::EraseStatus),
+            0x04 => Some(Reason::Length),
+            0x05 => Some(Reason::UnknownCommand),
+            0x06 => Some(Reason::ConnectFailed),
+            0x07 => Some(Reason::BadParameters),
+            0x08 => Some(Reason::Timeout),
+            0x09 => Some(Reason::Aborted),
+            0x0A => Some(Reason::NotHalted),
+            _ => None,
+        }
+    }
+}
+
+/// Payload of [`Response::Error`]: reason code and detail (u16 LE). The reason is kept
+/// as a byte so that reasons added by newer firmware still decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Failure {
+    pub reason: u8,
+    pub detail: u16,
+}
+
+impl Failure {
+    pub const LEN: usize = 3;
+
+    pub const fn new(reason: Reason, detail: u16) -> Self {
+        Self {
+            reason: reason as u8,
+            detail,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let detail = self.detail.to_le_bytes();
+        [self.reason, detail[0], detail[1]]
+    }
+
+    /// Firmware without reason codes sends an empty payload, which does not decode
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        let [reason, d0, d1] = *payload else {
+            return None;
+        };
+        Some(Self {
+            reason,
+            detail: u16::from_le_bytes([d0, d1]),
+        })
+    }
+}
+
+/// Address (u32 LE) and length (u16 LE) parameters of the memory commands. Writes
+/// append the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressLength {
+    pub addr: u32,
+    pub len: u16,
+}
+
+impl AddressLength {
+    pub const LEN: usize = 6;
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut params = [0u8; Self::LEN];
+        params[..4].copy_from_slice(&self.addr.to_le_bytes());
+        params[4..].copy_from_slice(&self.len.to_le_bytes());
+        params
+    }
+
+    /// Decode the parameters at the start of `payload`, ignoring any data after them
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        let [a0, a1, a2, a3, l0, l1, ..] = *payload else {
+            return None;
+        };
+        Some(Self {
+            addr: u32::from_le_bytes([a0, a1, a2, a3]),
+            len: u16::from_le_bytes([l0, l1]),
+        })
+    }
+}
+
+/// Address (u32 LE) and length (u32 LE) of a flash range, for commands covering more than
+/// one frame of data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashRange {
+    pub addr: u32,
+    pub len: u32,
+}
+
+impl FlashRange {
+    pub const LEN: usize = 8;
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut params = [0u8; Self::LEN];
+        params[..4].copy_from_slice(&self.addr.to_le_bytes());
+        params[4..].copy_from_slice(&self.len.to_le_bytes());
+        params
+    }
+
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        let [a0, a1, a2, a3, l0, l1, l2, l3] = *payload else {
+            return None;
+        };
+        Some(Self {
+            addr: u32::from_le_bytes([a0, a1, a2, a3]),
+            len: u32::from_le_bytes([l0, l1, l2, l3]),
+        })
+    }
+}
+
+/// Memory space of the 8051 core, as seen by the debug commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemorySpace {
+    /// Internal RAM, all 256 bytes (the upper half through indirect addressing)
+    Iram = 0x00,
+    /// Special function registers, 0x80-0xFF
+    Sfr = 0x01,
+    /// External RAM
+    Xdata = 0x02,
+    /// Program memory, read only
+    Code = 0x03,
+}
+
+impl MemorySpace {
+    pub const fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            0x00 => Some(MemorySpace::Iram),
+            0x01 => Some(MemorySpace::Sfr),
+            0x02 => Some(MemorySpace::Xdata),
+            0x03 => Some(MemorySpace::Code),
+            _ => None,
+        }
+    }
+
+    /// Addresses of this space
+    pub const fn range(self) -> core::ops::Range<u32> {
+        match self {
+            MemorySpace::Iram => 0x00..0x100,
+            MemorySpace::Sfr => 0x80..0x100,
+            MemorySpace::Xdata | MemorySpace::Code => 0x0000..0x1_0000,
+        }
+    }
+
+    /// True if `len` bytes at `addr` lie within this space
+    pub const fn contains(self, addr: u16, len: usize) -> bool {
+        let range = self.range();
+        addr as u32 >= range.start && addr as usize + len <= range.end as usize
+    }
+
+    pub const fn is_writable(self) -> bool {
+        !matches!(self, MemorySpace::Code)
+    }
+}
+
+/// Memory space (u8), address (u16 LE) and length (u16 LE) parameters of the debug
+/// memory commands. Writes append the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub space: MemorySpace,
+    pub addr: u16,
+    pub len: u16,
+}
+
+impl MemoryAccess {
+    pub const LEN: usize = 5;
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let addr = self.addr.to_le_bytes();
+        let len = self.len.to_le_bytes();
+        [self.space as u8, addr[0], addr[1], len[0], len[1]]
+    }
+
+    /// Decode the parameters at the start of `payload`, ignoring any data after them
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        let [space, a0, a1, l0, l1, ..] = *payload else {
+            return None;
+        };
+        Some(Self {
+            space: MemorySpace::from_u8(space)?,
+            addr: u16::from_le_bytes([a0, a1]),
+            len: u16::from_le_bytes([l0, l1]),
+        })
+    }
+}
+
+/// Index (u8) and code address (u16 LE) of a hardware breakpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub index: u8,
+    pub addr: u16,
+}
+
+impl Breakpoint {
+    pub const LEN: usize = 3;
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let addr = self.addr.to_le_bytes();
+        [self.index, addr[0], addr[1]]
+    }
+
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        let [index, a0, a1] = *payload else {
+            return None;
+        };
+        Some(Self {
+            index,
+            addr: u16::from_le_bytes([a0, a1]),
+        })
+    }
+}
+
+/// State of the core, answered to [`Command::DebugStatus`]: halted flag (u8), program
+/// counter (u16 LE, 0 while running) and the index of the breakpoint that stopped the
+/// core (u8, 0xFF for none)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoreStatus {
+    pub halted: bool,
+    pub pc: u16,
+    pub breakpoint: Option<u8>,
+}
+
+impl CoreStatus {
+    pub const LEN: usize = 4;
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let pc = self.pc.to_le_bytes();
+        [
+            self.halted as u8,
+            pc[0],
+            pc[1],
+            self.breakpoint.unwrap_or(0xFF),
+        ]
+    }
+
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let [halted, p0, p1, breakpoint] = *data else {
+            return None;
+        };
+        Some(Self {
+            halted: halted != 0,
+            pc: u16::from_le_bytes([p0, p1]),
+            breakpoint: (breakpoint != 0xFF).then_some(breakpoint),
+        })
+    }
+}
+
+/// What the firmware supports, answered to [`Command::GetCapabilities`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Bitmap of supported commands, by command byte
+    pub commands: u32,
+    /// Most flash bytes a single read or write frame carries
+    pub max_data_len: u16,
+    /// Bitmap of supported chip types
+    pub chip_types: u16,
+    pub max_baud: u32,
+}
+
+impl Capabilities {
+    pub const LEN: usize = 12;
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut data = [0u8; Self::LEN];
+        data[..4].copy_from_slice(&self.commands.to_le_bytes());
+        data[4..6].copy_from_slice(&self.max_data_len.to_le_bytes());
+        data[6..8].copy_from_slice(&self.chip_types.to_le_bytes());
+        data[8..].copy_from_slice(&self.max_baud.to_le_bytes());
+        data
+    }
+
+    /// Newer firmware may append fields, which are ignored
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let [c0, c1, c2, c3, l0, l1, t0, t1, b0, b1, b2, b3, ..] = *data else {
+            return None;
+        };
+        Some(Self {
+            commands: u32::from_le_bytes([c0, c1, c2, c3]),
+            max_data_len: u16::from_le_bytes([l0, l1]),
+            chip_types: u16::from_le_bytes([t0, t1]),
+            max_baud: u32::from_le_bytes([b0, b1, b2, b3]),
+        })
+    }
+
+    pub fn supports(&self, command: Command) -> bool {
+        self.commands & command.bit() != 0
+    }
+
+    pub fn supports_chip_type(&self, chip_type: u8) -> bool {
+        chip_type < 16 && self.chip_types & (1 << chip_type) != 0
+    }
+}
+
+/// CRC-32 (IEEE 802.3, reflected poly 0xEDB88320) update for flash checksums. Start
+/// with 0xFFFFFFFF and invert the result.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+// Frame layout:
+//   SYNC | LEN (u16 LE, payload length) | SEQ | CMD or RSP code | payload | CRC16 (u16 LE)
+// The CRC covers everything between SYNC and the CRC itself. Responses echo the
+// request's SEQ; the firmware replays the short response of a repeated SEQ instead of running
+// the command again, so retransmitting a write or erase is safe.
+pub mod frame {
+    pub const SYNC: u8 = 0xA5;
+    // LEN, SEQ and code
+    pub const HEADER_LEN: usize = 4;
+    // Largest payload: flash write of MAX_DATA_LEN bytes plus address and length
+    pub const MAX_PAYLOAD: usize = super::MAX_DATA_LEN + super::AddressLength::LEN;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Header {
+        /// Payload length
+        pub len: u16,
+        pub seq: u8,
+        /// Command byte of a request, response code of a response
+        pub code: u8,
+    }
+
+    impl Header {
+        pub fn encode(&self) -> [u8; HEADER_LEN] {
+            let len = self.len.to_le_bytes();
+            [len[0], len[1], self.seq, self.code]
+        }
+
+        pub fn decode(bytes: [u8; HEADER_LEN]) -> Self {
+            Self {
+                len: u16::from_le_bytes([bytes[0], bytes[1]]),
+                seq: bytes[2],
+                code: bytes[3],
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum FrameError {
+        /// Frame does not start with SYNC
+        NoSync,
+        /// Frame is shorter than its header says
+        Truncated,
+        /// Payload length exceeds MAX_PAYLOAD
+        TooLong(usize),
+        CrcMismatch {
+            expected: u16,
+            actual: u16,
+        },
+    }
+
+    /// CRC-16/CCITT-FALSE (poly 0x1021, init 0xFFFF), one byte at a time
+    pub fn crc16_update(mut crc: u16, byte: u8) -> u16 {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    }
+
+    pub fn crc16(data: &[u8]) -> u16 {
+        data.iter()
+            .fold(0xFFFF, |crc, &byte| crc16_update(crc, byte))
+    }
+
+    /// Length of the frame carrying `payload_len` bytes of payload
+    pub const fn frame_len(payload_len: usize) -> usize {
+        1 + HEADER_LEN + payload_len + 2
+    }
+
+    /// Write the frame carrying `payload` to the start of `out`, returning its length.
+    /// Panics if `out` is shorter than [`frame_len`].
+    pub fn encode(seq: u8, code: u8, payload: &[u8], out: &mut [u8]) -> usize {
+        let header = Header {
+            len: payload.len() as u16,
+            seq,
+            code,
+        };
+        let end = 1 + HEADER_LEN + payload.len();
+        out[0] = SYNC;
+        out[1..1 + HEADER_LEN].copy_from_slice(&header.encode());
+        out[1 + HEADER_LEN..end].copy_from_slice(payload);
+        let crc = crc16(&out[1..end]);
+        out[end..end + 2].copy_from_slice(&crc.to_le_bytes());
+        end + 2
+    }
+
+    /// Check a complete frame and return its header and payload
+    pub fn decode(frame: &[u8]) -> Result<(Header, &[u8]), FrameError> {
+        let [SYNC, h0, h1, h2, h3, rest @ ..] = frame else {
+            return Err(if frame.first().is_some_and(|&b| b != SYNC) {
+                FrameError::NoSync
+            } else {
+                FrameError::Truncated
+            });
+        };
+        let header = Header::decode([*h0, *h1, *h2, *h3]);
+        let len = header.len as usize;
+        if len > MAX_PAYLOAD {
+            return Err(FrameError::TooLong(len));
+        }
+        if rest.len() < len + 2 {
+            return Err(FrameError::Truncated);
+        }
+
+        let (payload, crc) = rest.split_at(len);
+        let actual = u16::from_le_bytes([crc[0], crc[1]]);
+        let expected = crc16(&frame[1..1 + HEADER_LEN + len]);
+        if actual != expected {
+            return Err(FrameError::CrcMismatch { expected, actual });
+        }
+        Ok((header, payload))
+    }
+}
+
+#[test]
+fn test_command_round_trip() {
+    for command in Command::ALL {
+        assert_eq!(Command::from_u8(command as u8), Some(command));
+    }
+    assert_eq!(Command::from_u8(0x00), None);
+    assert_eq!(Command::from_u8(0x20), None);
+    assert_eq!(Command::PowerOff as u8, 0x1F);
+    assert_eq!(Command::bits(&Command::ALL), 0xFFFF_FFFE);
+
+    for code in [
+        Response::Ok,
+        Response::Data,
+        Response::FrameError,
+        Response::Error,
+    ] {
+        assert_eq!(Response::from_u8(code as u8), Some(code));
+    }
+    for byte in 0x01..=0x0A {
+        assert_eq!(Reason::from_u8(byte).unwrap() as u8, byte);
+    }
+    assert_eq!(Reason::from_u8(0x0B), None);
+}
+
+#[test]
+fn test_parameters_round_trip() {
+    let failure = Failure::new(Reason::NoAck, 0x0311);
+    assert_eq!(failure.encode(), [0x02, 0x11, 0x03]);
+    assert_eq!(Failure::decode(&failure.encode()), Some(failure));
+    assert_eq!(Failure::decode(&[]), None);
+
+    let params = AddressLength {
+        addr: 0x0001_2345,
+        len: 1024,
+    };
+    assert_eq!(params.encode(), [0x45, 0x23, 0x01, 0x00, 0x00, 0x04]);
+    let mut write = params.encode().to_vec();
+    write.extend_from_slice(&[0xAA; 4]);
+    assert_eq!(AddressLength::decode(&write), Some(params));
+    assert_eq!(AddressLength::decode(&write[..5]), None);
+
+    let range = FlashRange {
+        addr: 0x4000,
+        len: 0x0001_0000,
+    };
+    assert_eq!(FlashRange::decode(&range.encode()), Some(range));
+    assert_eq!(FlashRange::decode(&range.encode()[..7]), None);
+
+    let access = MemoryAccess {
+        space: MemorySpace::Sfr,
+        addr: 0x80,
+        len: 0x80,
+    };
+    assert_eq!(access.encode(), [0x01, 0x80, 0x00, 0x80, 0x00]);
+    assert_eq!(MemoryAccess::decode(&access.encode()), Some(access));
+    assert_eq!(MemoryAccess::decode(&[0x04, 0, 0, 1, 0]), None);
+    assert!(MemorySpace::Sfr.contains(0x80, 0x80));
+    assert!(!MemorySpace::Sfr.contains(0x7F, 1));
+    assert!(!MemorySpace::Iram.contains(0xFF, 2));
+    assert!(MemorySpace::Xdata.contains(0xFFFF, 1));
+
+    let breakpoint = Breakpoint {
+        index: 7,
+        addr: 0x1234,
+    };
+    assert_eq!(breakpoint.encode(), [0x07, 0x34, 0x12]);
+    assert_eq!(Breakpoint::decode(&breakpoint.encode()), Some(breakpoint));
+
+    let status = CoreStatus {
+        halted: true,
+        pc: 0x0100,
+        breakpoint: Some(2),
+    };
+    assert_eq!(CoreStatus::decode(&status.encode()), Some(status));
+    assert_eq!(
+        CoreStatus::decode(&[0x00, 0x00, 0x00, 0xFF])
+            .unwrap()
+            .breakpoint,
+        None
+    );
+
+    let capabilities = Capabilities {
+        commands: Command::bits(&[Command::Ping, Command::GetCapabilities]),
+        max_data_len: 512,
+        chip_types: 0x0080,
+        max_baud: 1_000_000,
+    };
+    let mut data = capabilities.encode().to_vec();
+    assert_eq!(Capabilities::decode(&data), Some(capabilities));
+    data.push(0xFF);
+    assert_eq!(Capabilities::decode(&data), Some(capabilities));
+    assert_eq!(Capabilities::decode(&data[..11]), None);
+    assert!(capabilities.supports(Command::Ping));
+    assert!(!capabilities.supports(Command::Connect));
+    assert!(capabilities.supports_chip_type(0x07));
+    assert!(!capabilities.supports_chip_type(0x02));
+}
+
+#[test]
+fn test_crc() {
+    // Check values of CRC-16/CCITT-FALSE and CRC-32
+    assert_eq!(frame::crc16(b"123456789"), 0x29b1);
+    assert_eq!(!crc32_update(0xFFFF_FFFF, b"123456789"), 0xCBF4_3926);
+}
+
+#[test]
+fn test_frame_round_trip() {
+    let payload = [0x5Au8; 300];
+    let mut out = [0u8; frame::frame_len(frame::MAX_PAYLOAD)];
+    let len = frame::encode(7, Command::WriteFlash as u8, &payload, &mut out);
+    assert_eq!(len, frame::frame_len(payload.len()));
+    assert_eq!(out[..5], [frame::SYNC, 0x2C, 0x01, 7, 0x09]);
+
+    let (header, decoded) = frame::decode(&out[..len]).unwrap();
+    assert_eq!(
+        header,
+        frame::Header {
+            len: 300,
+            seq: 7,
+            code: Command::WriteFlash as u8
+        }
+    );
+    assert_eq!(decoded, payload);
+
+    let len = frame::encode(8, Response::Ok as u8, &[], &mut out);
+    assert_eq!(frame::decode(&out[..len]).unwrap().1, []);
+
+    let mut corrupted = out[..len].to_vec();
+    corrupted[3] ^= 1;
+    assert!(matches!(
+        frame::decode(&corrupted),
+        Err(frame::FrameError::CrcMismatch { .. })
+    ));
+    assert_eq!(frame::decode(&out[1..len]), Err(frame::FrameError::NoSync));
+    assert_eq!(
+        frame::decode(&out[..len - 1]),
+        Err(frame::FrameError::Truncated)
+    );
+
+    let too_long = frame::Header {
+        len: frame::MAX_PAYLOAD as u16 + 1,
+        seq: 0,
+        code: 0,
+    };
+    let mut bytes = vec![frame::SYNC];
+    bytes.extend_from_slice(&too_long.encode());
+    assert_eq!(
+        frame::decode(&bytes),
+        Err(frame::FrameError::TooLong(frame::MAX_PAYLOAD + 1))
+    );
+}
@@ -0,0 +1,643 @@
+//! GDB remote serial protocol server, mapping a debugger's register, memory, step and
+//! breakpoint requests onto the programmer's JTAG debug commands.
+//!
+//! GDB sees one flat address space, so each 8051 memory space is placed at its own
+//! 64 KiB base, see [`MEMORY_MAP`]. Registers are R0-R7 of the current bank, A, B,
+//! PSW, SP, DPL, DPH and the 16-bit PC, described to the debugger in [`TARGET_XML`].
+
+use super::programmer::{
+    parse_hex, to_hex, CoreStatus, MemorySpace, Programmer, ProgrammerError, Registers,
+    BREAKPOINT_COUNT,
+};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use thiserror::Error;
+
+/// GDB address of each memory space
+pub const MEMORY_MAP: [(u32, MemorySpace); 4] = [
+    (0x0000_0000, MemorySpace::Code),
+    (0x0001_0000, MemorySpace::Xdata),
+    (0x0002_0000, MemorySpace::Iram),
+    (0x0003_0000, MemorySpace::Sfr),
+];
+
+// Register number of the PC, after R0-R7 and the SFRs
+const PC_REGISTER: usize = 8 + Registers::SFRS.len();
+// Bytes of a `g` packet: one per 8-bit register, two for the PC
+const REGISTERS_LEN: usize = PC_REGISTER + 2;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.sinodude.mcs51.core">
+    <reg name="r0" bitsize="8" type="uint8"/>
+    <reg name="r1" bitsize="8" type="uint8"/>
+    <reg name="r2" bitsize="8" type="uint8"/>
+    <reg name="r3" bitsize="8" type="uint8"/>
+    <reg name="r4" bitsize="8" type="uint8"/>
+    <reg name="r5" bitsize="8" type="uint8"/>
+    <reg name="r6" bitsize="8" type="uint8"/>
+    <reg name="r7" bitsize="8" type="uint8"/>
+    <reg name="a" bitsize="8" type="uint8"/>
+    <reg name="b" bitsize="8" type="uint8"/>
+    <reg name="psw" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="8" type="data_ptr"/>
+    <reg name="dpl" bitsize="8" type="uint8"/>
+    <reg name="dph" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// Largest packet we accept, announced in qSupported
+const PACKET_SIZE: usize = 0x1000;
+// How often the core is polled while it runs
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+// Sent by GDB to stop a running target
+const INTERRUPT: u8 = 0x03;
+
+#[derive(Debug, Error)]
+pub enum GdbError {
+    #[error("GDB connection I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Programmer(#[from] ProgrammerError),
+    #[error("Register {0} does not exist or has another size")]
+    InvalidRegister(usize),
+    #[error("Address {0:#x} is not mapped to a memory space")]
+    UnmappedAddress(u32),
+    #[error("All {BREAKPOINT_COUNT} hardware breakpoints are in use")]
+    NoFreeBreakpoint,
+}
+
+/// What the session loop does with a packet
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Reply(String),
+    /// Resume the core and reply once it stops
+    Continue,
+    /// Reply OK and end the session, leaving the core running
+    Detach,
+    /// End the session without a reply
+    Kill,
+}
+
+/// One unit of input from GDB
+#[derive(Debug, PartialEq, Eq)]
+enum Packet {
+    Data(Vec<u8>),
+    /// Checksum mismatch, to be answered with a NAK
+    Corrupt,
+    /// Ctrl+C outside of a packet
+    Interrupt,
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Frame `data` as `$data#checksum`, escaping the protocol's special characters
+fn encode_packet(data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(data.len());
+    for &byte in data {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            body.extend_from_slice(&[b'}', byte ^ 0x20]);
+        } else {
+            body.push(byte);
+        }
+    }
+    let mut packet = vec![b'$'];
+    packet.extend_from_slice(&body);
+    packet.extend_from_slice(format!("#{:02x}", checksum(&body)).as_bytes());
+    packet
+}
+
+/// Read the next packet, skipping acknowledgements. None once GDB has disconnected.
+fn read_packet(reader: &mut impl Read) -> std::io::Result<Option<Packet>> {
+    let mut next = || -> std::io::Result<Option<u8>> {
+        let mut byte = [0u8];
+        Ok(match reader.read(&mut byte)? {
+            0 => None,
+            _ => Some(byte[0]),
+        })
+    };
+
+    loop {
+        match next()? {
+            None => return Ok(None),
+            Some(b'$') => break,
+            Some(INTERRUPT) => return Ok(Some(Packet::Interrupt)),
+            // Acknowledgements and noise between packets
+            Some(_) => {}
+        }
+    }
+
+    let mut body = Vec::new();
+    loop {
+        match next()? {
+            None => return Ok(None),
+            Some(b'#') => break,
+            Some(byte) => body.push(byte),
+        }
+    }
+    let (Some(high), Some(low)) = (next()?, next()?) else {
+        return Ok(None);
+    };
+    let expected = std::str::from_utf8(&[high, low])
+        .ok()
+        .and_then(|s| u8::from_str_radix(s, 16).ok());
+    if expected != Some(checksum(&body)) {
+        return Ok(Some(Packet::Corrupt));
+    }
+
+    // Unescape binary data
+    let mut data = Vec::with_capacity(body.len());
+    let mut bytes = body.into_iter();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'}' => data.push(bytes.next().unwrap_or_default() ^ 0x20),
+            _ => data.push(byte),
+        }
+    }
+    Ok(Some(Packet::Data(data)))
+}
+
+/// Parse the `addr,len` arguments of memory packets
+fn parse_addr_len(args: &str) -> Option<(u32, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((
+        u32::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+/// Memory space and 16-bit address behind a GDB address
+fn map_address(addr: u32) -> Option<(MemorySpace, u16)> {
+    MEMORY_MAP
+        .iter()
+        .find(|(base, _)| addr >> 16 == base >> 16)
+        .map(|&(base, space)| (space, (addr - base) as u16))
+}
+
+/// A debugging session with one GDB connection
+struct GdbSession<'a> {
+    programmer: &'a mut dyn Programmer,
+    /// Code address of each hardware breakpoint in use
+    breakpoints: [Option<u16>; BREAKPOINT_COUNT],
+    no_ack: bool,
+    /// GDB understands `hwbreak` stop reasons
+    hwbreak: bool,
+}
+
+impl<'a> GdbSession<'a> {
+    fn new(programmer: &'a mut dyn Programmer) -> Self {
+        Self {
+            programmer,
+            breakpoints: [None; BREAKPOINT_COUNT],
+            no_ack: false,
+            hwbreak: false,
+        }
+    }
+
+    fn stop_reply(&self, status: &CoreStatus) -> String {
+        match status.breakpoint {
+            Some(_) if self.hwbreak => "T05hwbreak:;".to_string(),
+            _ => "S05".to_string(),
+        }
+    }
+
+    /// Registers in GDB's numbering, see [`TARGET_XML`]
+    fn read_registers(&mut self) -> Result<Vec<u8>, ProgrammerError> {
+        let registers = self.programmer.read_registers()?;
+        let mut values = registers.r.to_vec();
+        values.extend_from_slice(&[registers.a, registers.b, registers.psw, registers.sp]);
+        values.extend_from_slice(&registers.dptr.to_le_bytes());
+        values.extend_from_slice(&registers.pc.to_le_bytes());
+        Ok(values)
+    }
+
+    fn write_register(&mut self, number: usize, value: &[u8]) -> Result<(), GdbError> {
+        match (number, value) {
+            (0..=7, &[byte]) => {
+                let psw = self.programmer.read_registers()?.psw;
+                Ok(self.programmer.write_memory(
+                    MemorySpace::Iram,
+                    Registers::bank_base(psw) + number as u16,
+                    &[byte],
+                )?)
+            }
+            (8..PC_REGISTER, &[byte]) => {
+                let sfr = Registers::SFRS[number - 8];
+                Ok(self
+                    .programmer
+                    .write_memory(MemorySpace::Sfr, sfr as u16, &[byte])?)
+            }
+            (PC_REGISTER, &[low, high]) => {
+                Ok(self.programmer.set_pc(u16::from_le_bytes([low, high]))?)
+            }
+            _ => Err(GdbError::InvalidRegister(number)),
+        }
+    }
+
+    fn write_registers(&mut self, values: &[u8]) -> Result<(), GdbError> {
+        if values.len() != REGISTERS_LEN {
+            return Err(GdbError::InvalidRegister(values.len()));
+        }
+        // SFRs first, as PSW selects the bank R0-R7 are written to
+        for number in (8..PC_REGISTER).chain(0..8) {
+            self.write_register(number, &values[number..number + 1])?;
+        }
+        self.write_register(PC_REGISTER, &values[PC_REGISTER..])
+    }
+
+    fn read_memory(&mut self, addr: u32, len: usize) -> Result<Vec<u8>, GdbError> {
+        let (space, addr) = map_address(addr).ok_or(GdbError::UnmappedAddress(addr))?;
+        Ok(self.programmer.read_memory(space, addr, len)?)
+    }
+
+    fn write_memory(&mut self, addr: u32, data: &[u8]) -> Result<(), GdbError> {
+        let (space, addr) = map_address(addr).ok_or(GdbError::UnmappedAddress(addr))?;
+        Ok(self.programmer.write_memory(space, addr, data)?)
+    }
+
+    fn insert_breakpoint(&mut self, addr: u16) -> Result<(), GdbError> {
+        if self.breakpoints.contains(&Some(addr)) {
+            return Ok(());
+        }
+        let index = self
+            .breakpoints
+            .iter()
+            .position(Option::is_none)
+            .ok_or(GdbError::NoFreeBreakpoint)?;
+        self.programmer.set_breakpoint(index, addr)?;
+        self.breakpoints[index] = Some(addr);
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, addr: u16) -> Result<(), GdbError> {
+        if let Some(index) = self.breakpoints.iter().position(|&b| b == Some(addr)) {
+            self.programmer.clear_breakpoint(index)?;
+            self.breakpoints[index] = None;
+        }
+        Ok(())
+    }
+
+    /// Handle a packet, with errors from the target answered as `E01`
+    fn handle(&mut self, packet: &str) -> Action {
+        match self.dispatch(packet) {
+            Ok(action) => action,
+            Err(e) => {
+                eprintln!("GDB request {:?} failed: {}", packet, e);
+                Action::Reply("E01".to_string())
+            }
+        }
+    }
+
+    fn dispatch(&mut self, packet: &str) -> Result<Action, GdbError> {
+        let reply = |s: &str| Ok(Action::Reply(s.to_string()));
+        // Malformed packets
+        let invalid = || Ok(Action::Reply("E02".to_string()));
+
+        let (command, args) = packet.split_at(packet.len().min(1));
+        match command {
+            "?" => {
+                let status = self.programmer.core_status()?;
+                Ok(Action::Reply(self.stop_reply(&status)))
+            }
+            "g" => Ok(Action::Reply(to_hex(&self.read_registers()?))),
+            "G" => match parse_hex(args) {
+                Some(values) => {
+                    self.write_registers(&values)?;
+                    reply("OK")
+                }
+                None => invalid(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(number) if number <= PC_REGISTER => {
+                    let registers = self.read_registers()?;
+                    let value = if number == PC_REGISTER {
+                        &registers[PC_REGISTER..]
+                    } else {
+                        &registers[number..number + 1]
+                    };
+                    Ok(Action::Reply(to_hex(value)))
+                }
+                _ => invalid(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(number, value)| {
+                    Some((usize::from_str_radix(number, 16).ok()?, parse_hex(value)?))
+                });
+                match parsed {
+                    Some((number, value)) => {
+                        self.write_register(number, &value)?;
+                        reply("OK")
+                    }
+                    None => invalid(),
+                }
+            }
+            "m" => match parse_addr_len(args) {
+                Some((addr, len)) => Ok(Action::Reply(to_hex(&self.read_memory(addr, len)?))),
+                None => invalid(),
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = parse_addr_len(range)?;
+                    let data = parse_hex(data)?;
+                    (data.len() == len).then_some((addr, data))
+                });
+                match parsed {
+                    Some((addr, data)) => {
+                        self.write_memory(addr, &data)?;
+                        reply("OK")
+                    }
+                    None => invalid(),
+                }
+            }
+            "s" | "c" => {
+                if !args.is_empty() {
+                    match u32::from_str_radix(args, 16) {
+                        Ok(addr) => self.programmer.set_pc(addr as u16)?,
+                        Err(_) => return invalid(),
+                    }
+                }
+                if command == "c" {
+                    return Ok(Action::Continue);
+                }
+                self.programmer.step()?;
+                let status = self.programmer.core_status()?;
+                Ok(Action::Reply(self.stop_reply(&status)))
+            }
+            // Software breakpoints would need flash writes, so both kinds use the
+            // hardware breakpoints
+            "Z" | "z" => {
+                let parsed = args.split(',').collect::<Vec<_>>();
+                let addr = match parsed[..] {
+                    ["0" | "1", addr, _] => u32::from_str_radix(addr, 16).ok(),
+                    // Watchpoints are not supported
+                    _ => return reply(""),
+                };
+                let Some(addr) = addr else {
+                    return invalid();
+                };
+                if command == "Z" {
+                    self.insert_breakpoint(addr as u16)?;
+                } else {
+                    self.remove_breakpoint(addr as u16)?;
+                }
+                reply("OK")
+            }
+            "D" => Ok(Action::Detach),
+            "k" => Ok(Action::Kill),
+            "H" => reply("OK"),
+            _ => self.query(packet),
+        }
+    }
+
+    /// General queries and settings
+    fn query(&mut self, packet: &str) -> Result<Action, GdbError> {
+        let reply = |s: &str| Ok(Action::Reply(s.to_string()));
+
+        if let Some(features) = packet.strip_prefix("qSupported") {
+            self.hwbreak = features
+                .trim_start_matches(':')
+                .split(';')
+                .any(|f| f == "hwbreak+");
+            let mut supported = format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+",
+                PACKET_SIZE
+            );
+            if self.hwbreak {
+                supported.push_str(";hwbreak+");
+            }
+            return Ok(Action::Reply(supported));
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_addr_len(args) {
+                Some((offset, len)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = (offset + len).min(TARGET_XML.len());
+                    let marker = if end == TARGET_XML.len() { "l" } else { "m" };
+                    reply(&format!("{}{}", marker, &TARGET_XML[offset..end]))
+                }
+                None => reply("E02"),
+            };
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                reply("OK")
+            }
+            // Attached to a running target, so detaching leaves it running
+            "qAttached" => reply("1"),
+            "qSymbol::" => reply("OK"),
+            // Anything else is unsupported
+            _ => reply(""),
+        }
+    }
+
+    /// Resume the core until it stops at a breakpoint, GDB interrupts it or the user
+    /// cancels, and return the stop reply
+    fn run_until_stop(&mut self, stream: &mut TcpStream) -> Result<String, GdbError> {
+        self.programmer.resume()?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let result = loop {
+            let status = self.programmer.core_status()?;
+            if status.halted {
+                break Ok(self.stop_reply(&status));
+            }
+            self.programmer.check_cancelled()?;
+
+            let mut byte = [0u8];
+            match stream.read(&mut byte) {
+                Ok(1) if byte[0] == INTERRUPT => {
+                    self.programmer.halt()?;
+                    break Ok("S02".to_string());
+                }
+                Ok(0) => break Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => break Err(e.into()),
+            }
+        };
+        stream.set_read_timeout(None)?;
+        result
+    }
+
+    fn send(&self, stream: &mut TcpStream, reply: &str) -> std::io::Result<()> {
+        stream.write_all(&encode_packet(reply.as_bytes()))
+    }
+
+    fn serve(&mut self, mut stream: TcpStream) -> Result<(), GdbError> {
+        while let Some(packet) = read_packet(&mut stream)? {
+            let packet = match packet {
+                Packet::Data(data) => data,
+                Packet::Corrupt => {
+                    stream.write_all(b"-")?;
+                    continue;
+                }
+                // The core only runs during run_until_stop, which handles interrupts
+                Packet::Interrupt => continue,
+            };
+            if !self.no_ack {
+                stream.write_all(b"+")?;
+            }
+
+            let packet = String::from_utf8_lossy(&packet);
+            match self.handle(&packet) {
+                Action::Reply(reply) => self.send(&mut stream, &reply)?,
+                Action::Continue => {
+                    let reply = self.run_until_stop(&mut stream)?;
+                    self.send(&mut stream, &reply)?;
+                }
+                Action::Detach => {
+                    self.send(&mut stream, "OK")?;
+                    break;
+                }
+                Action::Kill => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Clear the breakpoints in use and let the core run
+    fn release(&mut self) -> Result<(), ProgrammerError> {
+        if !self.programmer.core_status()?.halted {
+            self.programmer.halt()?;
+        }
+        for index in 0..BREAKPOINT_COUNT {
+            if self.breakpoints[index].take().is_some() {
+                self.programmer.clear_breakpoint(index)?;
+            }
+        }
+        self.programmer.resume()
+    }
+}
+
+/// Wait for GDB to connect on `port` of the loopback interface and serve it until it
+/// detaches or disconnects. The core is halted while GDB is attached and left running
+/// afterwards.
+pub fn serve(programmer: &mut dyn Programmer, port: u16) -> Result<(), GdbError> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    // Poll, so that Ctrl+C still cancels while nobody connects
+    listener.set_nonblocking(true)?;
+    eprintln!("Waiting for GDB on 127.0.0.1:{}...", port);
+    let (stream, peer) = loop {
+        match listener.accept() {
+            Ok(connection) => break connection,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                programmer.check_cancelled()?;
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    eprintln!("GDB connected from {}", peer);
+
+    let pc = programmer.halt()?;
+    eprintln!("Target halted at {:#06x}", pc);
+
+    let mut session = GdbSession::new(programmer);
+    let served = session.serve(stream);
+    // A failure to let the core run must not hide why the session ended
+    match session.release() {
+        Ok(()) => eprintln!("GDB session ended, target running"),
+        Err(e) => eprintln!("GDB session ended, could not let the target run: {}", e),
+    }
+    served
+}
+
+#[cfg(test)]
+fn sim_session_programmer() -> super::programmer::SimProgrammer {
+    let mut programmer = super::programmer::sim_programmer(&super::parts::sh68f90::PART);
+    programmer.halt().unwrap();
+    programmer
+}
+
+#[test]
+fn test_packet_round_trip() {
+    let packet = encode_packet(b"OK");
+    assert_eq!(packet, b"$OK#9a");
+    assert_eq!(
+        read_packet(&mut &b"+$m0,4#fd"[..]).unwrap(),
+        Some(Packet::Data(b"m0,4".to_vec()))
+    );
+    assert_eq!(
+        read_packet(&mut &encode_packet(b"a}#b")[..]).unwrap(),
+        Some(Packet::Data(b"a}#b".to_vec()))
+    );
+    assert_eq!(
+        read_packet(&mut &b"$m0,4#00"[..]).unwrap(),
+        Some(Packet::Corrupt)
+    );
+    assert_eq!(
+        read_packet(&mut &b"\x03"[..]).unwrap(),
+        Some(Packet::Interrupt)
+    );
+    assert_eq!(read_packet(&mut &b"$m0"[..]).unwrap(), None);
+}
+
+#[test]
+fn test_session_registers_and_memory() {
+    let mut programmer = sim_session_programmer();
+    let mut session = GdbSession::new(&mut programmer);
+    let reply = |session: &mut GdbSession, packet: &str| match session.handle(packet) {
+        Action::Reply(reply) => reply,
+        action => panic!("unexpected {:?}", action),
+    };
+
+    // Select bank 1, then write R2 there and read everything back
+    assert_eq!(reply(&mut session, "P0a=08"), "OK");
+    assert_eq!(reply(&mut session, "P2=5a"), "OK");
+    assert_eq!(reply(&mut session, "m2000a,1"), "5a");
+    assert_eq!(reply(&mut session, "Pe=3412"), "OK");
+    assert_eq!(reply(&mut session, "g"), "00005a00000000000000080000003412");
+    assert_eq!(reply(&mut session, "pe"), "3412");
+
+    assert_eq!(reply(&mut session, "M10000,2:abcd"), "OK");
+    assert_eq!(reply(&mut session, "m10000,2"), "abcd");
+    assert_eq!(reply(&mut session, "M0,1:00"), "E01");
+    assert_eq!(reply(&mut session, "m40000,1"), "E01");
+    assert_eq!(reply(&mut session, "M10000,2:ab"), "E02");
+
+    assert!(reply(&mut session, "qXfer:features:read:target.xml:0,10").starts_with("m<?xml"));
+    assert_eq!(reply(&mut session, "vMustReplyEmpty"), "");
+}
+
+#[test]
+fn test_session_breakpoints() {
+    let mut programmer = sim_session_programmer();
+    let mut session = GdbSession::new(&mut programmer);
+    session.handle("qSupported:hwbreak+;multiprocess+");
+
+    assert_eq!(session.handle("Z1,120,1"), Action::Reply("OK".to_string()));
+    assert_eq!(session.handle("Z0,80,1"), Action::Reply("OK".to_string()));
+    assert_eq!(session.handle("z0,80,1"), Action::Reply("OK".to_string()));
+    assert_eq!(session.breakpoints[..2], [Some(0x120), None]);
+    assert_eq!(session.handle("Z2,10,1"), Action::Reply(String::new()));
+
+    // The simulated core runs straight to the breakpoint
+    assert_eq!(session.handle("c"), Action::Continue);
+    session.programmer.resume().unwrap();
+    let status = session.programmer.core_status().unwrap();
+    assert_eq!((status.pc, status.breakpoint), (0x120, Some(0)));
+    assert_eq!(
+        session.handle("?"),
+        Action::Reply("T05hwbreak:;".to_string())
+    );
+    assert_eq!(session.handle("s"), Action::Reply("S05".to_string()));
+    assert_eq!(session.programmer.core_status().unwrap().pc, 0x121);
+
+    for addr in 0x201..0x200 + BREAKPOINT_COUNT as u16 {
+        session.insert_breakpoint(addr).unwrap();
+    }
+    assert!(matches!(
+        session.insert_breakpoint(0x300),
+        Err(GdbError::NoFreeBreakpoint)
+    ));
+    session.release().unwrap();
+    assert!(!session.programmer.core_status().unwrap().halted);
+}
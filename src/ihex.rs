@@ -21,9 +21,16 @@ pub enum ConversionError {
 
 pub fn to_ihex(byte_array: Vec<u8>) -> Result<String, ConversionError> {
     let mut result: Vec<Record> = vec![];
+    let mut upper_address = 0;
     for (i, chunk) in byte_array.chunks(16).enumerate() {
+        let addr = i * 16;
+        // Images over 64 KiB need an extended linear address record per 64 KiB bank
+        if (addr >> 16) != upper_address {
+            upper_address = addr >> 16;
+            result.push(Record::ExtendedLinearAddress(upper_address as u16));
+        }
         result.push(Record::Data {
-            offset: (i as u16) * 16,
+            offset: addr as u16,
             value: chunk.to_vec(),
         });
     }
@@ -41,11 +48,14 @@ fn unpack_records(
     max_length: usize,
 ) -> Result<Vec<u8>, UnpackingError> {
     let mut result: Vec<u8> = vec![];
+    // Base address set by the last extended segment/linear address record
+    let mut base: usize = 0;
     for rec in records {
         match rec {
             Ok(rec) => match rec {
                 Record::Data { offset, value } => {
-                    let end_addr = offset as usize + value.len();
+                    let start_addr = base + offset as usize;
+                    let end_addr = start_addr + value.len();
                     if end_addr > max_length {
                         return Err(UnpackingError::AddressTooHigh {
                             addr: end_addr,
@@ -56,16 +66,10 @@ fn unpack_records(
                         result.resize(end_addr, 0);
                     }
 
-                    for (n, b) in value.iter().enumerate() {
-                        result[offset as usize + n] = *b;
-                    }
-                }
-                Record::ExtendedSegmentAddress(_base) => {
-                    return Err(UnpackingError::UnsupportedRecordType(rec))
-                }
-                Record::ExtendedLinearAddress(_base) => {
-                    return Err(UnpackingError::UnsupportedRecordType(rec))
+                    result[start_addr..end_addr].copy_from_slice(&value);
                 }
+                Record::ExtendedSegmentAddress(segment) => base = (segment as usize) << 4,
+                Record::ExtendedLinearAddress(upper) => base = (upper as usize) << 16,
                 Record::EndOfFile => break,
                 Record::StartLinearAddress(_) | Record::StartSegmentAddress { .. } => {}
             },
@@ -94,8 +98,7 @@ fn test_from_ihex_address_start_at_0x0001() {
         ":100010000200660227BD010A32646402CB9053DA03\n:00000001FF",
         32,
     );
-    let mut expected: Vec<u8> = Vec::new();
-    expected.resize(16, 0);
+    let mut expected: Vec<u8> = vec![0; 16];
     expected.extend_from_slice(&[
         2, 0, 102, 2, 39, 189, 1, 10, 50, 100, 100, 2, 203, 144, 83, 218,
     ]);
@@ -126,3 +129,32 @@ fn test_from_ihex_err_address_too_high() {
     }));
     assert_eq!(result, expected);
 }
+
+#[test]
+fn test_from_ihex_extended_linear_address() {
+    // Toolchains often start with a type-04 record even for small images
+    let result = from_ihex(
+        ":020000040000FA\n:100000000200660227BD010A32646402CB9053DA13\n:00000001FF",
+        16,
+    )
+    .unwrap();
+    assert_eq!(result[0..4], [2, 0, 102, 2]);
+
+    let result = from_ihex(":020000040001F9\n:0400100001020304E2\n:00000001FF", 0x20000).unwrap();
+    assert_eq!(result.len(), 0x10014);
+    assert_eq!(result[0x10010..], [1, 2, 3, 4]);
+}
+
+#[test]
+fn test_from_ihex_extended_segment_address() {
+    let result = from_ihex(":020000021000EC\n:0400100001020304E2\n:00000001FF", 0x20000).unwrap();
+    assert_eq!(result[0x10010..], [1, 2, 3, 4]);
+}
+
+#[test]
+fn test_ihex_round_trip_128k() {
+    let image: Vec<u8> = (0..0x20000).map(|i| (i * 7 + i / 256) as u8).collect();
+    let ihex = to_ihex(image.clone()).unwrap();
+    assert!(ihex.contains(":020000040001F9"));
+    assert_eq!(from_ihex(&ihex, image.len()).unwrap(), image);
+}
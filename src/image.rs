@@ -0,0 +1,119 @@
+use super::ihex::{from_ihex, to_ihex, ConversionError};
+use std::fs;
+use std::path::Path;
+use thiserror::Error;
+
+/// Values accepted by `--format`
+pub const IMAGE_FORMATS: [&str; 3] = ["auto", "bin", "ihex"];
+
+#[derive(Debug, Error)]
+pub enum ImageError {
+    #[error("Image I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Conversion(#[from] ConversionError),
+    #[error("Unknown image format: {0}")]
+    UnknownFormat(String),
+    #[error("Binary image of {len} bytes at offset {offset:#x} does not fit in {size:#x} bytes of flash")]
+    TooLarge {
+        offset: usize,
+        len: usize,
+        size: usize,
+    },
+    #[error("A load offset is only supported for binary images")]
+    OffsetNotSupported,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// Raw bytes, starting at flash address 0 (or the load offset)
+    Bin,
+    /// Intel HEX
+    Ihex,
+}
+
+impl ImageFormat {
+    /// Resolve a `--format` value; `auto` picks binary for `.bin` files and Intel HEX otherwise
+    pub fn from_arg(format: &str, path: &str) -> Result<Self, ImageError> {
+        match format {
+            "bin" => Ok(ImageFormat::Bin),
+            "ihex" => Ok(ImageFormat::Ihex),
+            "auto" => Ok(Self::from_path(path)),
+            _ => Err(ImageError::UnknownFormat(format.to_string())),
+        }
+    }
+
+    pub fn from_path(path: &str) -> Self {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("bin") | Some("raw") => ImageFormat::Bin,
+            _ => ImageFormat::Ihex,
+        }
+    }
+}
+
+/// Load an image, padded to `flash_size`. Binary images are placed at `offset`.
+pub fn load_image(
+    path: &str,
+    format: ImageFormat,
+    flash_size: usize,
+    offset: Option<usize>,
+) -> Result<Vec<u8>, ImageError> {
+    let data = fs::read(path)?;
+    let mut image = match format {
+        ImageFormat::Bin => {
+            let offset = offset.unwrap_or(0);
+            if offset + data.len() > flash_size {
+                return Err(ImageError::TooLarge {
+                    offset,
+                    len: data.len(),
+                    size: flash_size,
+                });
+            }
+            let mut image = vec![0; offset];
+            image.extend_from_slice(&data);
+            image
+        }
+        ImageFormat::Ihex => {
+            if offset.is_some() {
+                return Err(ImageError::OffsetNotSupported);
+            }
+            from_ihex(&String::from_utf8_lossy(&data), flash_size)?
+        }
+    };
+
+    if image.len() < flash_size {
+        image.resize(flash_size, 0);
+    }
+
+    Ok(image)
+}
+
+/// Save a flash dump; binary output is the exact bytes read
+pub fn save_image(path: &str, format: ImageFormat, contents: Vec<u8>) -> Result<(), ImageError> {
+    match format {
+        ImageFormat::Bin => fs::write(path, contents)?,
+        ImageFormat::Ihex => fs::write(path, to_ihex(contents)?)?,
+    }
+    Ok(())
+}
+
+#[test]
+fn test_image_format_from_arg() {
+    assert_eq!(
+        ImageFormat::from_arg("auto", "dump.BIN").unwrap(),
+        ImageFormat::Bin
+    );
+    assert_eq!(
+        ImageFormat::from_arg("auto", "dump.hex").unwrap(),
+        ImageFormat::Ihex
+    );
+    assert_eq!(
+        ImageFormat::from_arg("ihex", "dump.bin").unwrap(),
+        ImageFormat::Ihex
+    );
+    assert!(ImageFormat::from_arg("elf", "dump.elf").is_err());
+}
@@ -1,25 +1,21 @@
 use clap::*;
 use log::info;
 use simple_logger::SimpleLogger;
+use std::env;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::{env, fs, io::Read};
 
+mod gdb;
 mod ihex;
+mod image;
 pub mod parts;
 mod programmer;
 
-pub use crate::{ihex::*, parts::*, programmer::*};
+pub use crate::{ihex::*, image::*, parts::*, programmer::*};
 
-fn parse_hex(s: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let s = s.trim_start_matches("0x").trim_start_matches("0X");
-    if !s.len().is_multiple_of(2) {
-        return Err("Hex string must have even length".into());
-    }
-    (0..s.len())
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| e.into()))
-        .collect()
+fn parse_hex_string(s: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    parse_hex(s).ok_or_else(|| format!("Invalid hex string {:?}", s).into())
 }
 
 fn parse_addr(s: &str) -> Result<usize, Box<dyn std::error::Error>> {
@@ -31,6 +27,50 @@ fn parse_addr(s: &str) -> Result<usize, Box<dyn std::error::Error>> {
     }
 }
 
+/// Value of `--part` that detects the part from the target's JTAG ID and part number
+const AUTO_PART: &str = "auto";
+
+/// Actions of the `power` step
+const POWER_ACTIONS: [&str; 3] = ["on", "off", "cycle"];
+
+fn part_names() -> Vec<&'static str> {
+    PARTS.keys().copied().collect()
+}
+
+fn programmer_args() -> [Arg; 6] {
+    [
+        arg!(-c --programmer <PROGRAMMER>)
+            .value_parser(PROGRAMMERS.keys().copied().collect::<Vec<_>>())
+            .required(true),
+        arg!(-p --part <PART> "Target part, or \"auto\" to detect it from the target")
+            .value_parser(
+                std::iter::once(AUTO_PART)
+                    .chain(part_names())
+                    .collect::<Vec<_>>(),
+            )
+            .required(true),
+        arg!(--port <PORT> "Serial port for sinodude-serial programmer (e.g., /dev/ttyUSB0). If omitted, every port with a known USB bridge is probed, and those that do not answer are reset with --reset, which also restarts any other Arduino or CH340 device attached")
+            .required(false),
+        reset_arg(),
+        arg!(--sim_state <SIM_STATE> "State file loaded and saved by the sim programmer")
+            .value_parser(value_parser!(PathBuf))
+            .required(false),
+        arg!(--sim_part <SIM_PART> "Part simulated by the sim programmer (defaults to --part)")
+            .value_parser(part_names())
+            .required(false),
+    ]
+}
+
+fn reset_arg() -> Arg {
+    arg!(--reset <MODE> "How to reset the sinodude-serial board after opening the port, none for boards with auto-reset disabled")
+        .value_parser(RESET_MODES)
+        .default_value("dtr")
+}
+
+fn get_reset(sub_matches: &ArgMatches) -> Result<ResetMode, SinodudeSerialProgrammerError> {
+    ResetMode::from_arg(sub_matches.get_one::<String>("reset").unwrap())
+}
+
 fn cli() -> Command {
     Command::new("sinodude")
         .about("programming tool for sinowealth devices")
@@ -38,354 +78,1138 @@ fn cli() -> Command {
         .subcommand_required(true)
         .arg_required_else_help(true)
         .author("Karolis Stasaitis")
+        .subcommands(
+            step_subcommands()
+                .into_iter()
+                .map(|command| command.args(programmer_args())),
+        )
+        // Power control does not talk to the target's ICP, so any part will do
+        .mut_subcommand("power", |command| {
+            command.mut_arg("part", |arg| arg.required(false).default_value(AUTO_PART))
+        })
         .subcommand(
-            Command::new("read")
-                .short_flag('r')
-                .about("Read the chips flash contents")
-                .arg(arg!(output_file: <OUTPUT_FILE> "file to write flash contents to"))
-                .arg(
-                    arg!(-c --programmer <PROGRAMMER>)
-                        .value_parser(["sinodude-serial"])
-                        .required(true),
-                )
-                .arg(
-                    arg!(-p --part <PART>)
-                        .value_parser(PARTS.keys().copied().collect::<Vec<_>>())
-                        .required(true),
-                )
-                .arg(
-                    arg!(--port <PORT> "Serial port for sinodude-serial programmer (e.g., /dev/ttyUSB0)")
-                        .required(false),
-                ),
+            Command::new("identify")
+                .short_flag('i')
+                .about("Detect the connected part from its JTAG ID and part number")
+                .args(programmer_args())
+                .mut_arg("part", |arg| arg.required(false).default_value(AUTO_PART)),
         )
         .subcommand(
-            Command::new("write")
-                .short_flag('w')
-                .about("Write to flash")
-                .arg(arg!(input_file: <INPUT_FILE> "file to write to flash"))
-                .arg(
-                    arg!(-c --programmer <PROGRAMMER>)
-                        .value_parser(["sinodude-serial"])
-                        .required(true),
-                )
-                .arg(
-                    arg!(-p --part <PART>)
-                        .value_parser(PARTS.keys().copied().collect::<Vec<_>>())
-                        .required(true),
-                )
-                .arg(
-                    arg!(--port <PORT> "Serial port for sinodude-serial programmer (e.g., /dev/ttyUSB0)")
-                        .required(false),
-                )
-                .arg(
-                    arg!(--customer_id <CUSTOMER_ID> "Customer ID (4 bytes hex, e.g., 01020304)")
-                        .required(false),
-                )
-                .arg(
-                    arg!(--operation_number <OPERATION_NUMBER> "Operation number (2 bytes hex, e.g., 0102)")
-                        .required(false),
-                )
-                .arg(
-                    arg!(--customer_option <CUSTOMER_OPTION> "Customer option (hex string)")
-                        .required(false),
-                )
-                .arg(
-                    arg!(--security <SECURITY> "Security bits (hex string)")
-                        .required(false),
-                )
+            Command::new("gdbserver")
+                .about("Serve the GDB remote protocol on a local TCP port to debug the target")
                 .arg(
-                    arg!(--serial_number <SERIAL_NUMBER> "Serial number (4 bytes hex, e.g., 01020304)")
-                        .required(false),
-                )
-                .arg(
-                    arg!(--start_addr <START_ADDR> "Start address for partial write (hex, e.g., 0x1000)")
-                        .required(false),
+                    arg!(--gdb_port <GDB_PORT> "TCP port on 127.0.0.1 to listen on for GDB")
+                        .value_parser(value_parser!(u16))
+                        .default_value("3333"),
                 )
-                .arg(
-                    arg!(--end_addr <END_ADDR> "End address for partial write (hex, e.g., 0x2000)")
-                        .required(false),
-                ),
+                .args(programmer_args()),
         )
         .subcommand(
-            Command::new("erase")
-                .short_flag('e')
-                .about("Erase the chip's flash (mass erase or specific sectors)")
-                .arg(
-                    arg!(-c --programmer <PROGRAMMER>)
-                        .value_parser(["sinodude-serial"])
-                        .required(true),
-                )
-                .arg(
-                    arg!(-p --part <PART>)
-                        .value_parser(PARTS.keys().copied().collect::<Vec<_>>())
-                        .required(true),
-                )
+            Command::new("list-programmers")
+                .about("List serial ports with a sinodude-serial programmer attached")
+                .arg(reset_arg()),
+        )
+        .subcommand(
+            Command::new("run")
+                .about("Run several steps in one session, without reconnecting to the target in between")
                 .arg(
-                    arg!(--port <PORT> "Serial port for sinodude-serial programmer (e.g., /dev/ttyUSB0)")
-                        .required(false),
+                    arg!(script: [SCRIPT] "File with one step per line, e.g. \"erase --start_addr 0x0 --end_addr 0x4000\"")
+                        .value_parser(value_parser!(PathBuf))
+                        .required_unless_present("steps"),
                 )
                 .arg(
-                    arg!(--start_addr <START_ADDR> "Start address for sector erase (hex, e.g., 0x1000)")
+                    arg!(--steps <STEPS> "Steps separated by ';' instead of a script file")
+                        .conflicts_with("script")
                         .required(false),
                 )
-                .arg(
-                    arg!(--end_addr <END_ADDR> "End address for sector erase (hex, e.g., 0x2000)")
-                        .required(false),
-                ),
+                .args(programmer_args()),
         )
 }
 
-fn run(cancelled: Arc<AtomicBool>) -> Result<(), Box<dyn std::error::Error>> {
-    let matches = cli().get_matches();
+/// Subcommands that can also run as steps of a `run` session
+fn step_subcommands() -> [Command; 13] {
+    [
+        Command::new("read")
+            .short_flag('r')
+            .about("Read the chips flash contents")
+            .arg(arg!(output_file: <OUTPUT_FILE> "file to write flash contents to"))
+            .arg(format_arg())
+            .arg(
+                arg!(--include_custom <FILE> "Also save the custom fields to a TOML (or .json) file")
+                    .value_parser(value_parser!(PathBuf))
+                    .required(false),
+            ),
+        Command::new("read-custom")
+            .about("Save the chip's custom fields and code options to a TOML (or .json) file")
+            .arg(
+                arg!(output_file: <OUTPUT_FILE> "file to write the custom fields to")
+                    .value_parser(value_parser!(PathBuf)),
+            ),
+        Command::new("write")
+            .short_flag('w')
+            .about("Write to flash")
+            .arg(arg!(input_file: <INPUT_FILE> "file to write to flash"))
+            .arg(format_arg())
+            .arg(
+                arg!(--offset <OFFSET> "Load offset for binary images (hex, e.g., 0x1000)")
+                    .required(false),
+            )
+            .arg(
+                arg!(--custom_from <FILE> "Apply custom fields saved by read-custom or read --include_custom")
+                    .value_parser(value_parser!(PathBuf))
+                    .conflicts_with_all([
+                        "customer_id",
+                        "operation_number",
+                        "customer_option",
+                        "security",
+                        "serial_number",
+                    ])
+                    .required(false),
+            )
+            .arg(
+                arg!(--customer_id <CUSTOMER_ID> "Customer ID (4 bytes hex, e.g., 01020304)")
+                    .required(false),
+            )
+            .arg(
+                arg!(--operation_number <OPERATION_NUMBER> "Operation number (2 bytes hex, e.g., 0102)")
+                    .required(false),
+            )
+            .arg(
+                arg!(--customer_option <CUSTOMER_OPTION> "Customer option (hex string)")
+                    .required(false),
+            )
+            .arg(option_arg("option").conflicts_with_all(["customer_option", "custom_from"]))
+            .arg(
+                arg!(--security <SECURITY> "Security bits (hex string)")
+                    .required(false),
+            )
+            .arg(
+                arg!(--serial_number <SERIAL_NUMBER> "Serial number (4 bytes hex, e.g., 01020304)")
+                    .required(false),
+            )
+            .arg(
+                arg!(--start_addr <START_ADDR> "Start address for partial write (hex, e.g., 0x1000)")
+                    .required(false),
+            )
+            .arg(
+                arg!(--end_addr <END_ADDR> "End address for partial write (hex, e.g., 0x2000)")
+                    .required(false),
+            )
+            .arg(
+                arg!(--verify_erase "Blank-check each sector right after erasing it")
+                    .required(false),
+            )
+            .arg(
+                arg!(--verify <MODE> "Verify by reading back all data, or by comparing per-sector checksums computed by the programmer")
+                    .value_parser(VERIFY_MODES)
+                    .default_value("read"),
+            )
+            .arg(
+                arg!(--run_after "Power the target on without ICP afterwards, so that the written firmware runs")
+                    .required(false),
+            ),
+        Command::new("options")
+            .about("Show code options by name, or change them with --set")
+            .arg(arg!(names: [NAME] ... "Options to show (all if omitted)"))
+            .arg(option_arg("set")),
+        Command::new("verify")
+            .short_flag('v')
+            .about("Compare the chip's flash and custom fields against an image")
+            .arg(arg!(input_file: <INPUT_FILE> "file to compare flash contents against"))
+            .arg(format_arg())
+            .arg(
+                arg!(--offset <OFFSET> "Load offset for binary images (hex, e.g., 0x1000)")
+                    .required(false),
+            )
+            .arg(
+                arg!(--customer_id <CUSTOMER_ID> "Expected customer ID (4 bytes hex, e.g., 01020304)")
+                    .required(false),
+            )
+            .arg(
+                arg!(--customer_option <CUSTOMER_OPTION> "Expected customer option (hex string)")
+                    .required(false),
+            )
+            .arg(
+                arg!(--serial_number <SERIAL_NUMBER> "Expected serial number (4 bytes hex, e.g., 01020304)")
+                    .required(false),
+            )
+            .arg(
+                arg!(--start_addr <START_ADDR> "Start address for partial verify (hex, e.g., 0x1000)")
+                    .required(false),
+            )
+            .arg(
+                arg!(--end_addr <END_ADDR> "End address for partial verify (hex, e.g., 0x2000)")
+                    .required(false),
+            ),
+        Command::new("erase")
+            .short_flag('e')
+            .about("Erase the chip's flash (mass erase or specific sectors)")
+            .arg(
+                arg!(--start_addr <START_ADDR> "Start address for sector erase (hex, e.g., 0x1000)")
+                    .required(false),
+            )
+            .arg(
+                arg!(--end_addr <END_ADDR> "End address for sector erase (hex, e.g., 0x2000)")
+                    .required(false),
+            )
+            .arg(
+                arg!(--verify_erase "Blank-check each sector right after erasing it")
+                    .required(false),
+            ),
+        Command::new("blank-check")
+            .short_flag('b')
+            .about("Check that the chip's flash (or a sector range) is erased")
+            .arg(
+                arg!(--start_addr <START_ADDR> "Start address for the check (hex, e.g., 0x1000)")
+                    .required(false),
+            )
+            .arg(
+                arg!(--end_addr <END_ADDR> "End address for the check (hex, e.g., 0x2000)")
+                    .required(false),
+            ),
+        Command::new("halt")
+            .about("Halt the target's core through JTAG and print its program counter"),
+        Command::new("resume").about("Let the halted core run again"),
+        Command::new("read-memory")
+            .about("Dump IRAM, SFRs, XDATA or code memory of the core, halting it if it runs")
+            .arg(arg!(space: <SPACE> "Memory space").value_parser(MEMORY_SPACES))
+            .arg(arg!(address: <ADDRESS> "Start address (hex, e.g., 0x30)"))
+            .arg(arg!(length: [LENGTH] "Number of bytes").default_value("16")),
+        Command::new("write-memory")
+            .about("Write IRAM, SFRs or XDATA of the core, halting it if it runs")
+            .arg(arg!(space: <SPACE> "Memory space").value_parser(MEMORY_SPACES))
+            .arg(arg!(address: <ADDRESS> "Start address (hex, e.g., 0x30)"))
+            .arg(arg!(data: <DATA> "Bytes to write (hex string, e.g., 0102)")),
+        Command::new("power")
+            .about("Switch the target's power, with on and cycle leaving it to run its own firmware")
+            .arg(arg!(action: <ACTION> "Power action").value_parser(POWER_ACTIONS))
+            .arg(
+                arg!(--off_time <MS> "How long cycle keeps the target unpowered, in milliseconds")
+                    .value_parser(value_parser!(u64))
+                    .default_value("500"),
+            ),
+        Command::new("debug")
+            .about(
+                "Set hardware breakpoints, run to one and single-step the core, then print its registers",
+            )
+            .arg(
+                Arg::new("clear")
+                    .long("clear")
+                    .value_name("INDEX")
+                    .help("Clear hardware breakpoint INDEX (repeatable)")
+                    .value_parser(value_parser!(usize))
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                Arg::new("break")
+                    .long("break")
+                    .value_name("ADDRESS")
+                    .help("Break at a code address (hex, repeatable), using breakpoints 0, 1, ... in order")
+                    .action(ArgAction::Append),
+            )
+            .arg(
+                Arg::new("continue")
+                    .long("continue")
+                    .help("Resume the core and wait until it stops at a breakpoint")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                arg!(--step <COUNT> "Single-step COUNT instructions, printing the PC after each")
+                    .value_parser(value_parser!(usize))
+                    .required(false),
+            ),
+    ]
+}
 
-    match matches.subcommand() {
-        Some(("read", sub_matches)) => {
-            let output_file = sub_matches
-                .get_one::<String>("output_file")
-                .map(|s| s.as_str())
-                .unwrap();
-
-            let part_name = sub_matches
-                .get_one::<String>("part")
-                .map(|s| s.as_str())
-                .unwrap();
-
-            let part = PARTS.get(part_name).unwrap();
-
-            let port = sub_matches
-                .get_one::<String>("port")
-                .expect("--port is required for sinodude-serial programmer");
-            let mut programmer = SinodudeSerialProgrammer::new(port, part, cancelled.clone())?;
-            programmer.read_init()?;
-            let result = programmer.read_flash()?;
-            programmer.finish()?;
+/// The part selected with `--part`, or None for `--part auto`
+fn get_part(sub_matches: &ArgMatches) -> Option<&'static Part> {
+    let part_name = sub_matches
+        .get_one::<String>("part")
+        .map(|s| s.as_str())
+        .unwrap();
+
+    if part_name == AUTO_PART {
+        return None;
+    }
+    Some(PARTS.get(part_name).unwrap())
+}
+
+/// Open the selected programmer for `part` (None leaves the part unselected)
+fn open_for_part(
+    sub_matches: &ArgMatches,
+    part: Option<&'static Part>,
+    cancelled: Arc<AtomicBool>,
+) -> Result<Box<dyn Programmer>, ProgrammerError> {
+    let programmer_name = sub_matches
+        .get_one::<String>("programmer")
+        .map(|s| s.as_str())
+        .unwrap();
+
+    let config = ProgrammerConfig {
+        port: sub_matches.get_one::<String>("port").cloned(),
+        sim_state: sub_matches.get_one::<PathBuf>("sim_state").cloned(),
+        sim_part: sub_matches.get_one::<String>("sim_part").cloned(),
+        reset: get_reset(sub_matches)?,
+    };
 
-            let digest = md5::compute(&result);
-            info!("MD5: {:x}", digest);
+    open_programmer(programmer_name, &config, part, cancelled)
+}
 
-            let ihex = to_ihex(result)?;
-            fs::write(output_file, ihex)?;
+/// Open the selected programmer. With `--part auto` this connects to the target and
+/// selects the detected part, failing if the match is not unique.
+fn open_from_matches(
+    sub_matches: &ArgMatches,
+    cancelled: Arc<AtomicBool>,
+) -> Result<Box<dyn Programmer>, ProgrammerError> {
+    let part = get_part(sub_matches);
+    let mut programmer = open_for_part(sub_matches, part, cancelled)?;
+    if part.is_none() {
+        programmer.select_detected_part()?;
+    }
+    Ok(programmer)
+}
+
+fn parse_addr_arg(
+    sub_matches: &ArgMatches,
+    name: &str,
+) -> Result<Option<usize>, Box<dyn std::error::Error>> {
+    sub_matches
+        .get_one::<String>(name)
+        .map(|s| parse_addr(s))
+        .transpose()
+}
+
+/// Parse the optional `--start_addr`/`--end_addr` pair and check that it is sector
+/// aligned and, with the missing end defaulting to the flash bounds, a non-empty range
+/// within the flash
+fn parse_range(
+    sub_matches: &ArgMatches,
+    part: &Part,
+) -> Result<(Option<usize>, Option<usize>), Box<dyn std::error::Error>> {
+    let sector_size = part.sector_size;
+    let start_addr = parse_addr_arg(sub_matches, "start_addr")?;
+    let end_addr = parse_addr_arg(sub_matches, "end_addr")?;
+
+    if let Some(addr) = start_addr {
+        if addr % sector_size != 0 {
+            return Err(format!(
+                "Start address {:#x} is not aligned to sector size {:#x}",
+                addr, sector_size
+            )
+            .into());
         }
-        Some(("write", sub_matches)) => {
-            let input_file = sub_matches
-                .get_one::<String>("input_file")
-                .map(|s| s.as_str())
-                .unwrap();
-
-            let part_name = sub_matches
-                .get_one::<String>("part")
-                .map(|s| s.as_str())
-                .unwrap();
-
-            let part = PARTS.get(part_name).unwrap();
-
-            let mut file = fs::File::open(input_file)?;
-            let mut file_buf = Vec::new();
-            file.read_to_end(&mut file_buf)?;
-            let file_str = String::from_utf8_lossy(&file_buf[..]);
-            let mut firmware = from_ihex(&file_str, part.flash_size)?;
-
-            if firmware.len() < part.flash_size {
-                firmware.resize(part.flash_size, 0);
-            }
+    }
+    if let Some(addr) = end_addr {
+        if addr % sector_size != 0 {
+            return Err(format!(
+                "End address {:#x} is not aligned to sector size {:#x}",
+                addr, sector_size
+            )
+            .into());
+        }
+    }
+    if start_addr.is_some() || end_addr.is_some() {
+        check_flash_range(
+            part,
+            start_addr.unwrap_or(0),
+            end_addr.unwrap_or(part.flash_size),
+        )?;
+    }
 
-            let port = sub_matches
-                .get_one::<String>("port")
-                .expect("--port is required for sinodude-serial programmer");
-
-            // Parse and validate address range before connecting
-            let sector_size = part.sector_size;
-            let start_addr = sub_matches
-                .get_one::<String>("start_addr")
-                .map(|s| parse_addr(s))
-                .transpose()?;
-            let end_addr = sub_matches
-                .get_one::<String>("end_addr")
-                .map(|s| parse_addr(s))
-                .transpose()?;
-
-            if let Some(addr) = start_addr {
-                if addr % sector_size != 0 {
-                    return Err(format!(
-                        "Start address {:#x} is not aligned to sector size {:#x}",
-                        addr, sector_size
-                    )
-                    .into());
-                }
-            }
-            if let Some(addr) = end_addr {
-                if addr % sector_size != 0 {
-                    return Err(format!(
-                        "End address {:#x} is not aligned to sector size {:#x}",
-                        addr, sector_size
-                    )
-                    .into());
-                }
-            }
+    Ok((start_addr, end_addr))
+}
 
-            let mut programmer = SinodudeSerialProgrammer::new(port, part, cancelled.clone())?;
-            programmer.write_init()?;
-
-            // Use sector-based erase for partial writes, mass erase for full writes
-            match (start_addr, end_addr) {
-                (Some(start), Some(end)) => {
-                    programmer.erase_sectors(start as u32, end as u32)?;
-                }
-                (Some(start), None) => {
-                    programmer.erase_sectors(start as u32, part.flash_size as u32)?;
-                }
-                (None, Some(end)) => {
-                    programmer.erase_sectors(0, end as u32)?;
-                }
-                (None, None) => {
-                    programmer.mass_erase()?;
-                }
-            }
+/// Use sector-based erase for partial ranges, mass erase otherwise
+fn erase_range(
+    programmer: &mut dyn Programmer,
+    start_addr: Option<usize>,
+    end_addr: Option<usize>,
+    alternate: bool,
+    verify_erase: bool,
+) -> Result<(), ProgrammerError> {
+    let flash_size = programmer.part()?.flash_size;
+    match (start_addr, end_addr) {
+        (Some(start), Some(end)) => {
+            programmer.erase_sectors(start as u32, end as u32, verify_erase)
+        }
+        (Some(start), None) => {
+            programmer.erase_sectors(start as u32, flash_size as u32, verify_erase)
+        }
+        (None, Some(end)) => programmer.erase_sectors(0, end as u32, verify_erase),
+        (None, None) => programmer.erase_chip(alternate, verify_erase),
+    }
+}
 
-            // Parse custom fields
-            let customer_id: Option<[u8; 4]> = sub_matches
-                .get_one::<String>("customer_id")
-                .map(|s| parse_hex(s))
-                .transpose()?
-                .map(|v| {
-                    if v.len() != 4 {
-                        return Err("Customer ID must be exactly 4 bytes");
-                    }
-                    Ok(v.as_slice().try_into().unwrap())
-                })
-                .transpose()?;
-
-            let operation_number: Option<[u8; 2]> = sub_matches
-                .get_one::<String>("operation_number")
-                .map(|s| parse_hex(s))
-                .transpose()?
-                .map(|v| {
-                    if v.len() != 2 {
-                        return Err("Operation number must be exactly 2 bytes");
-                    }
-                    Ok(v.as_slice().try_into().unwrap())
-                })
-                .transpose()?;
-
-            let customer_option: Option<Vec<u8>> = sub_matches
-                .get_one::<String>("customer_option")
-                .map(|s| parse_hex(s))
-                .transpose()?;
-
-            let security: Option<Vec<u8>> = sub_matches
-                .get_one::<String>("security")
-                .map(|s| parse_hex(s))
-                .transpose()?;
-
-            let serial_number: Option<[u8; 4]> = sub_matches
-                .get_one::<String>("serial_number")
-                .map(|s| parse_hex(s))
-                .transpose()?
-                .map(|v| {
-                    if v.len() != 4 {
-                        return Err("Serial number must be exactly 4 bytes");
-                    }
-                    Ok(v.as_slice().try_into().unwrap())
-                })
-                .transpose()?;
-
-            // Write all custom fields in one transaction (use stored values as defaults)
-            programmer.write_custom_fields(
-                customer_id.as_ref(),
-                operation_number.as_ref(),
-                customer_option.as_deref(),
-                security.as_deref(),
-                serial_number.as_ref(),
-                true, // use_stored_defaults
-            )?;
-
-            // Use range write for partial writes, full write otherwise
-            match (start_addr, end_addr) {
-                (Some(start), Some(end)) => {
-                    programmer.write_flash_range(&firmware, start, end)?;
-                }
-                (Some(start), None) => {
-                    programmer.write_flash_range(&firmware, start, firmware.len())?;
-                }
-                (None, Some(end)) => {
-                    programmer.write_flash_range(&firmware, 0, end)?;
-                }
-                (None, None) => {
-                    programmer.write_flash(&firmware)?;
-                }
-            }
+/// Parse an optional hex argument; arguments not defined on the subcommand are `None`
+fn parse_hex_arg(
+    sub_matches: &ArgMatches,
+    name: &str,
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    sub_matches
+        .try_get_one::<String>(name)
+        .ok()
+        .flatten()
+        .map(|s| parse_hex_string(s))
+        .transpose()
+}
 
-            programmer.finish()?;
+/// Parse an optional fixed-length hex argument
+fn parse_hex_field<const N: usize>(
+    sub_matches: &ArgMatches,
+    name: &str,
+    error: &'static str,
+) -> Result<Option<[u8; N]>, Box<dyn std::error::Error>> {
+    parse_hex_arg(sub_matches, name)?
+        .map(|v| v.as_slice().try_into().map_err(|_| error.into()))
+        .transpose()
+}
+
+/// Parse the custom field arguments defined on the subcommand
+fn parse_custom_fields(
+    sub_matches: &ArgMatches,
+) -> Result<CustomFields, Box<dyn std::error::Error>> {
+    Ok(CustomFields {
+        customer_id: parse_hex_field(
+            sub_matches,
+            "customer_id",
+            "Customer ID must be exactly 4 bytes",
+        )?,
+        operation_number: parse_hex_field(
+            sub_matches,
+            "operation_number",
+            "Operation number must be exactly 2 bytes",
+        )?,
+        customer_option: parse_hex_arg(sub_matches, "customer_option")?,
+        security: parse_hex_arg(sub_matches, "security")?,
+        serial_number: parse_hex_field(
+            sub_matches,
+            "serial_number",
+            "Serial number must be exactly 4 bytes",
+        )?,
+        region: None,
+    })
+}
+
+fn option_arg(name: &'static str) -> Arg {
+    Arg::new(name)
+        .long(name)
+        .value_name("NAME=VALUE")
+        .help("Set a code option by name, to a state value or description (repeatable)")
+        .action(ArgAction::Append)
+        .required(false)
+}
+
+/// Apply the `NAME=VALUE` assignments given for argument `name` to `code_options`
+fn apply_option_args(
+    sub_matches: &ArgMatches,
+    name: &str,
+    part: &Part,
+    code_options: &mut [u8],
+) -> Result<(), OptionError> {
+    let options = (part.options)();
+    for assignment in sub_matches.get_many::<String>(name).into_iter().flatten() {
+        let (name, value) = parse_option_assignment(assignment)?;
+        set_code_option(code_options, &options, name, value)?;
+    }
+    Ok(())
+}
+
+fn format_arg() -> Arg {
+    arg!(--format <FORMAT> "Image format, auto picks bin for .bin files and ihex otherwise")
+        .value_parser(IMAGE_FORMATS)
+        .default_value("auto")
+}
+
+fn get_format(sub_matches: &ArgMatches, file: &str) -> Result<ImageFormat, ImageError> {
+    ImageFormat::from_arg(sub_matches.get_one::<String>("format").unwrap(), file)
+}
+
+/// Load the input image in the selected format, padded to the part's flash size
+fn load_input_image(
+    sub_matches: &ArgMatches,
+    input_file: &str,
+    part: &Part,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let format = get_format(sub_matches, input_file)?;
+    let offset = parse_addr_arg(sub_matches, "offset")?;
+    Ok(load_image(input_file, format, part.flash_size, offset)?)
+}
+
+fn read(
+    sub_matches: &ArgMatches,
+    programmer: &mut dyn Programmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let output_file = sub_matches
+        .get_one::<String>("output_file")
+        .map(|s| s.as_str())
+        .unwrap();
+    let format = get_format(sub_matches, output_file)?;
+
+    programmer.identify()?;
+    let fields = programmer.read_custom_fields()?;
+    let result = programmer.read_flash()?;
+
+    if let Some(custom_file) = sub_matches.get_one::<PathBuf>("include_custom") {
+        fields.save(programmer.part()?, custom_file)?;
+    }
+
+    let digest = md5::compute(&result);
+    info!("MD5: {:x}", digest);
+
+    save_image(output_file, format, result)?;
+
+    Ok(())
+}
+
+fn read_custom(
+    sub_matches: &ArgMatches,
+    programmer: &mut dyn Programmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let output_file = sub_matches.get_one::<PathBuf>("output_file").unwrap();
+
+    programmer.identify()?;
+    let fields = programmer.read_custom_fields()?;
+
+    fields.save(programmer.part()?, output_file)?;
+    eprintln!("Custom fields saved to {}", output_file.display());
+
+    Ok(())
+}
+
+fn write(
+    sub_matches: &ArgMatches,
+    programmer: &mut dyn Programmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let input_file = sub_matches
+        .get_one::<String>("input_file")
+        .map(|s| s.as_str())
+        .unwrap();
+
+    let part = programmer.part()?;
+
+    let firmware = load_input_image(sub_matches, input_file, part)?;
+
+    // Parse and validate address range before touching the flash
+    let (start_addr, end_addr) = parse_range(sub_matches, part)?;
+    let verify = VerifyMode::from_arg(sub_matches.get_one::<String>("verify").unwrap())?;
+
+    let requested = match sub_matches.get_one::<PathBuf>("custom_from") {
+        Some(custom_file) => CustomFields::load(part, custom_file)?,
+        None => parse_custom_fields(sub_matches)?,
+    };
+
+    // Check named options against the defaults before touching the chip
+    apply_option_args(
+        sub_matches,
+        "option",
+        part,
+        &mut part.default_code_options.to_vec(),
+    )?;
+
+    programmer.identify()?;
+    let stored = programmer.read_custom_fields()?;
+
+    let mut requested = requested;
+    if sub_matches.contains_id("option") {
+        let mut code_options = stored
+            .customer_option
+            .clone()
+            .unwrap_or_else(|| part.default_code_options.to_vec());
+        apply_option_args(sub_matches, "option", part, &mut code_options)?;
+        requested.customer_option = Some(code_options);
+    }
+
+    erase_range(
+        programmer,
+        start_addr,
+        end_addr,
+        stored.needs_alternate_erase(part),
+        sub_matches.get_flag("verify_erase"),
+    )?;
+
+    // Write all custom fields in one transaction (use stored values as defaults)
+    programmer.write_custom_fields(&requested.or(&stored))?;
+
+    // Use range write for partial writes, full write otherwise
+    match (start_addr, end_addr) {
+        (None, None) => programmer.write_flash(&firmware, verify)?,
+        (start, end) => programmer.write_flash_range(
+            &firmware,
+            start.unwrap_or(0),
+            end.unwrap_or(firmware.len()),
+            verify,
+        )?,
+    }
+
+    run_after(sub_matches, programmer)
+}
+
+/// Let the target run the data just written if `--run_after` is given
+fn run_after(
+    sub_matches: &ArgMatches,
+    programmer: &mut dyn Programmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if sub_matches.get_flag("run_after") {
+        programmer.power_on()?;
+    }
+    Ok(())
+}
+
+fn options(
+    sub_matches: &ArgMatches,
+    programmer: &mut dyn Programmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let part = programmer.part()?;
+    let options_metadata = (part.options)();
+
+    let names: Vec<&String> = sub_matches
+        .get_many::<String>("names")
+        .into_iter()
+        .flatten()
+        .collect();
+    for name in &names {
+        if !options_metadata
+            .keys()
+            .any(|n| n.eq_ignore_ascii_case(name))
+        {
+            return Err(OptionError::UnknownOption(name.to_string()).into());
         }
-        Some(("erase", sub_matches)) => {
-            let part_name = sub_matches
-                .get_one::<String>("part")
-                .map(|s| s.as_str())
-                .unwrap();
-
-            let part = PARTS.get(part_name).unwrap();
-
-            let port = sub_matches
-                .get_one::<String>("port")
-                .expect("--port is required for sinodude-serial programmer");
-
-            // Parse and validate address range before connecting
-            let sector_size = part.sector_size;
-            let start_addr = sub_matches
-                .get_one::<String>("start_addr")
-                .map(|s| parse_addr(s))
-                .transpose()?;
-            let end_addr = sub_matches
-                .get_one::<String>("end_addr")
-                .map(|s| parse_addr(s))
-                .transpose()?;
-
-            if let Some(addr) = start_addr {
-                if addr % sector_size != 0 {
-                    return Err(format!(
-                        "Start address {:#x} is not aligned to sector size {:#x}",
-                        addr, sector_size
-                    )
-                    .into());
-                }
-            }
-            if let Some(addr) = end_addr {
-                if addr % sector_size != 0 {
-                    return Err(format!(
-                        "End address {:#x} is not aligned to sector size {:#x}",
-                        addr, sector_size
-                    )
-                    .into());
-                }
-            }
+    }
+    apply_option_args(
+        sub_matches,
+        "set",
+        part,
+        &mut part.default_code_options.to_vec(),
+    )?;
+
+    programmer.identify()?;
+    let stored = programmer.read_custom_fields()?;
+    let mut code_options = stored
+        .customer_option
+        .unwrap_or_else(|| part.default_code_options.to_vec());
+
+    if sub_matches.contains_id("set") {
+        apply_option_args(sub_matches, "set", part, &mut code_options)?;
+        programmer.write_customer_option(&code_options)?;
+    }
 
-            let mut programmer = SinodudeSerialProgrammer::new(port, part, cancelled.clone())?;
-            programmer.erase_init()?;
-
-            // Use sector-based erase for partial erases, mass erase otherwise
-            match (start_addr, end_addr) {
-                (Some(start), Some(end)) => {
-                    programmer.erase_sectors(start as u32, end as u32)?;
-                }
-                (Some(start), None) => {
-                    programmer.erase_sectors(start as u32, part.flash_size as u32)?;
-                }
-                (None, Some(end)) => {
-                    programmer.erase_sectors(0, end as u32)?;
-                }
-                (None, None) => {
-                    programmer.mass_erase()?;
-                }
+    let parsed: Vec<ParsedOption> = parse_code_options(&code_options, &options_metadata)
+        .into_iter()
+        .filter(|o| names.is_empty() || names.iter().any(|n| n.eq_ignore_ascii_case(o.name)))
+        .collect();
+    print!("{}", format_parsed_options(&parsed));
+
+    Ok(())
+}
+
+fn verify(
+    sub_matches: &ArgMatches,
+    programmer: &mut dyn Programmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let input_file = sub_matches
+        .get_one::<String>("input_file")
+        .map(|s| s.as_str())
+        .unwrap();
+
+    let part = programmer.part()?;
+    let firmware = load_input_image(sub_matches, input_file, part)?;
+
+    let start_addr = parse_addr_arg(sub_matches, "start_addr")?
+        .unwrap_or(0)
+        .min(part.flash_size);
+    let end_addr = parse_addr_arg(sub_matches, "end_addr")?
+        .unwrap_or(part.flash_size)
+        .min(part.flash_size);
+    if start_addr >= end_addr {
+        return Err(format!(
+            "Start address {:#x} must be below end address {:#x}",
+            start_addr, end_addr
+        )
+        .into());
+    }
+
+    let expected_fields = parse_custom_fields(sub_matches)?;
+
+    programmer.identify()?;
+    let actual_fields = programmer.read_custom_fields()?;
+    let contents = programmer.read_flash_range(start_addr, end_addr)?;
+
+    let mut failed = false;
+
+    let ranges = mismatched_ranges(&firmware[start_addr..end_addr], &contents);
+    if !ranges.is_empty() {
+        failed = true;
+        let total: usize = ranges.iter().map(|r| r.len()).sum();
+        eprintln!(
+            "Flash mismatch: {} byte(s) in {} range(s)",
+            total,
+            ranges.len()
+        );
+        for range in &ranges {
+            eprintln!(
+                "  {:#06x}-{:#06x} ({} bytes)",
+                start_addr + range.start,
+                start_addr + range.end,
+                range.len()
+            );
+        }
+    }
+
+    for (name, expected, actual) in expected_fields.mismatches(&actual_fields) {
+        failed = true;
+        eprintln!(
+            "{} mismatch: expected {}, got {}",
+            name,
+            to_hex(&expected),
+            to_hex(&actual)
+        );
+    }
+
+    if failed {
+        return Err("Verification failed".into());
+    }
+
+    eprintln!(
+        "Verify OK ({} bytes, range {:#x}-{:#x})",
+        end_addr - start_addr,
+        start_addr,
+        end_addr
+    );
+
+    Ok(())
+}
+
+fn erase(
+    sub_matches: &ArgMatches,
+    programmer: &mut dyn Programmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Parse and validate address range before touching the flash
+    let (start_addr, end_addr) = parse_range(sub_matches, programmer.part()?)?;
+
+    programmer.identify()?;
+
+    erase_range(
+        programmer,
+        start_addr,
+        end_addr,
+        false,
+        sub_matches.get_flag("verify_erase"),
+    )?;
+
+    Ok(())
+}
+
+fn blank_check(
+    sub_matches: &ArgMatches,
+    programmer: &mut dyn Programmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let part = programmer.part()?;
+
+    let (start_addr, end_addr) = parse_range(sub_matches, part)?;
+    let start_addr = start_addr.unwrap_or(0);
+    let end_addr = end_addr.unwrap_or(part.flash_size);
+
+    programmer.identify()?;
+    let ranges = programmer.blank_check(start_addr, end_addr)?;
+
+    if !ranges.is_empty() {
+        print_non_blank(&ranges);
+        return Err("Blank check failed".into());
+    }
+
+    eprintln!(
+        "Blank check OK ({} bytes, range {:#x}-{:#x})",
+        end_addr - start_addr,
+        start_addr,
+        end_addr
+    );
+
+    Ok(())
+}
+
+fn halt(
+    _sub_matches: &ArgMatches,
+    programmer: &mut dyn Programmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    programmer.identify()?;
+    let pc = programmer.halt()?;
+    println!("PC: {:#06x}", pc);
+    Ok(())
+}
+
+fn resume(
+    _sub_matches: &ArgMatches,
+    programmer: &mut dyn Programmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    programmer.identify()?;
+    programmer.resume()?;
+    eprintln!("Core resumed");
+    Ok(())
+}
+
+/// An address of the core's 16-bit address spaces
+fn parse_core_addr(s: &str) -> Result<u16, Box<dyn std::error::Error>> {
+    let address = parse_addr(s)?;
+    u16::try_from(address)
+        .map_err(|_| format!("Address {:#x} exceeds the 16-bit address space", address).into())
+}
+
+/// Memory space and start address arguments of the debug memory steps
+fn parse_memory_args(
+    sub_matches: &ArgMatches,
+) -> Result<(MemorySpace, u16), Box<dyn std::error::Error>> {
+    let space = memory_space_from_arg(sub_matches.get_one::<String>("space").unwrap())?;
+    let address = parse_core_addr(sub_matches.get_one::<String>("address").unwrap())?;
+    Ok((space, address))
+}
+
+/// Hex dump of `data` read from `addr`, 16 bytes per line
+fn format_memory(addr: u16, data: &[u8]) -> String {
+    data.chunks(16)
+        .enumerate()
+        .map(|(i, line)| {
+            let bytes: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{:04x}: {}\n", addr as usize + i * 16, bytes.join(" "))
+        })
+        .collect()
+}
+
+fn read_memory(
+    sub_matches: &ArgMatches,
+    programmer: &mut dyn Programmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (space, address) = parse_memory_args(sub_matches)?;
+    let length = parse_addr(sub_matches.get_one::<String>("length").unwrap())?;
+    check_memory_access(space, address, length, false)?;
+
+    programmer.identify()?;
+    programmer.halt()?;
+    let data = programmer.read_memory(space, address, length)?;
+    print!("{}", format_memory(address, &data));
+
+    Ok(())
+}
+
+fn write_memory(
+    sub_matches: &ArgMatches,
+    programmer: &mut dyn Programmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (space, address) = parse_memory_args(sub_matches)?;
+    let data = parse_hex_string(sub_matches.get_one::<String>("data").unwrap())?;
+    check_memory_access(space, address, data.len(), true)?;
+
+    programmer.identify()?;
+    programmer.halt()?;
+    programmer.write_memory(space, address, &data)?;
+    eprintln!(
+        "Wrote {} byte(s) to {} at {:#06x}",
+        data.len(),
+        sub_matches.get_one::<String>("space").unwrap(),
+        address
+    );
+
+    Ok(())
+}
+
+fn power(
+    sub_matches: &ArgMatches,
+    programmer: &mut dyn Programmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match sub_matches.get_one::<String>("action").unwrap().as_str() {
+        "on" => programmer.power_on()?,
+        "off" => programmer.power_off()?,
+        "cycle" => {
+            let off_time = *sub_matches.get_one::<u64>("off_time").unwrap();
+            programmer.cycle_power(std::time::Duration::from_millis(off_time))?
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Poll the running core until it halts
+fn wait_for_halt(programmer: &mut dyn Programmer) -> Result<CoreStatus, ProgrammerError> {
+    loop {
+        let status = programmer.core_status()?;
+        if status.halted {
+            return Ok(status);
+        }
+        programmer.check_cancelled()?;
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+}
+
+fn debug(
+    sub_matches: &ArgMatches,
+    programmer: &mut dyn Programmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let clear: Vec<usize> = sub_matches
+        .get_many::<usize>("clear")
+        .into_iter()
+        .flatten()
+        .copied()
+        .collect();
+    for &index in &clear {
+        check_breakpoint_index(index)?;
+    }
+    let breakpoints = sub_matches
+        .get_many::<String>("break")
+        .into_iter()
+        .flatten()
+        .map(|addr| parse_core_addr(addr))
+        .collect::<Result<Vec<_>, _>>()?;
+    if breakpoints.len() > BREAKPOINT_COUNT {
+        return Err(format!("The target has only {} breakpoints", BREAKPOINT_COUNT).into());
+    }
+
+    programmer.identify()?;
+    programmer.halt()?;
+    for index in clear {
+        programmer.clear_breakpoint(index)?;
+        eprintln!("Cleared breakpoint {}", index);
+    }
+    for (index, &addr) in breakpoints.iter().enumerate() {
+        programmer.set_breakpoint(index, addr)?;
+        eprintln!("Breakpoint {} at {:#06x}", index, addr);
+    }
+
+    if sub_matches.get_flag("continue") {
+        programmer.resume()?;
+        let status = wait_for_halt(programmer)?;
+        match status.breakpoint {
+            Some(index) => println!("Hit breakpoint {} at {:#06x}", index, status.pc),
+            None => println!("Halted at {:#06x}", status.pc),
+        }
+    }
+    if let Some(&count) = sub_matches.get_one::<usize>("step") {
+        for _ in 0..count {
+            println!("Step to {:#06x}", programmer.step()?);
+        }
+    }
+    println!("{}", programmer.read_registers()?);
+
+    Ok(())
+}
+
+fn gdbserver(
+    sub_matches: &ArgMatches,
+    cancelled: Arc<AtomicBool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let port = *sub_matches.get_one::<u16>("gdb_port").unwrap();
+    let mut programmer = open_from_matches(sub_matches, cancelled)?;
+    programmer.identify()?;
+    gdb::serve(programmer.as_mut(), port)?;
+    programmer.finish()?;
+    Ok(())
+}
+
+fn identify(
+    sub_matches: &ArgMatches,
+    cancelled: Arc<AtomicBool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let part = get_part(sub_matches);
+    let mut programmer = open_for_part(sub_matches, part, cancelled)?;
+
+    // An explicit part is only checked against the target
+    if part.is_some() {
+        programmer.identify()?;
+        programmer.finish()?;
+        eprintln!(
+            "Target matches {}",
+            sub_matches.get_one::<String>("part").unwrap()
+        );
+        return Ok(());
+    }
+
+    let candidates = programmer.detect_parts()?;
+    programmer.finish()?;
+
+    match candidates.as_slice() {
+        [] => return Err(ProgrammerError::NoMatchingPart.into()),
+        [name] => {
+            let part = PARTS[name];
+            println!("{}", name);
+            eprintln!(
+                "Flash: {} bytes ({} byte sectors), EEPROM: {} bytes, code options: {} bytes",
+                part.flash_size, part.sector_size, part.eeprom_size, part.option_byte_count
+            );
+        }
+        _ => {
+            eprintln!("Target matches {} parts:", candidates.len());
+            for name in &candidates {
+                println!("{}", name);
             }
+            eprintln!("Select one of them with --part");
+        }
+    }
 
-            programmer.finish()?;
+    Ok(())
+}
+
+fn list_programmers(
+    sub_matches: &ArgMatches,
+    cancelled: Arc<AtomicBool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let discovered = SinodudeSerialProgrammer::discover(get_reset(sub_matches)?, cancelled)?;
+    if discovered.is_empty() {
+        eprintln!("No USB serial ports matching a known programmer bridge found");
+        return Ok(());
+    }
+
+    for programmer in &discovered {
+        match programmer.version {
+            Some((major, minor)) => println!(
+                "{}\t{}\tfirmware {}.{}",
+                programmer.port_name, programmer.bridge, major, minor
+            ),
+            None => println!(
+                "{}\t{}\tnot responding",
+                programmer.port_name, programmer.bridge
+            ),
         }
+    }
+
+    Ok(())
+}
+
+/// Run one of the [`step_subcommands`] on an open programmer
+fn run_step(
+    name: &str,
+    sub_matches: &ArgMatches,
+    programmer: &mut dyn Programmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match name {
+        "read" => read(sub_matches, programmer),
+        "read-custom" => read_custom(sub_matches, programmer),
+        "write" => write(sub_matches, programmer),
+        "verify" => verify(sub_matches, programmer),
+        "options" => options(sub_matches, programmer),
+        "erase" => erase(sub_matches, programmer),
+        "blank-check" => blank_check(sub_matches, programmer),
+        "halt" => halt(sub_matches, programmer),
+        "resume" => resume(sub_matches, programmer),
+        "read-memory" => read_memory(sub_matches, programmer),
+        "write-memory" => write_memory(sub_matches, programmer),
+        "debug" => debug(sub_matches, programmer),
+        "power" => power(sub_matches, programmer),
         _ => unreachable!(),
     }
+}
+
+/// A parsed step of a `run` session
+struct Step {
+    /// The step as written, for progress and error messages
+    text: String,
+    name: String,
+    matches: ArgMatches,
+}
+
+/// Parse `run` steps, one per `separator`-delimited entry of `text`. Arguments are
+/// split like a shell would, so paths containing spaces can be quoted; blank entries
+/// and entries starting with `#` are skipped.
+fn parse_steps(text: &str, separator: char) -> Result<Vec<Step>, Box<dyn std::error::Error>> {
+    let parser = Command::new("step")
+        .no_binary_name(true)
+        .subcommand_required(true)
+        .subcommands(step_subcommands());
+
+    let mut steps = Vec::new();
+    for entry in text.split(separator).map(str::trim) {
+        if entry.is_empty() || entry.starts_with('#') {
+            continue;
+        }
+        let args = shlex::split(entry)
+            .ok_or_else(|| format!("Invalid step \"{}\": unterminated quote", entry))?;
+        let matches = parser.clone().try_get_matches_from(args).map_err(|e| {
+            let message = e.render().to_string();
+            // The first paragraph, without the usage text that follows it
+            let reason = message.split("\n\n").next().unwrap_or_default();
+            let reason = reason.split_whitespace().collect::<Vec<_>>().join(" ");
+            format!(
+                "Invalid step \"{}\": {}",
+                entry,
+                reason.trim_start_matches("error: ")
+            )
+        })?;
+        let (name, sub_matches) = matches.subcommand().unwrap();
+        steps.push(Step {
+            text: entry.to_string(),
+            name: name.to_string(),
+            matches: sub_matches.clone(),
+        });
+    }
+
+    if steps.is_empty() {
+        return Err("No steps to run".into());
+    }
+    Ok(steps)
+}
+
+/// Run all steps over a single connection to the target, stopping at the first failure
+fn run_session(
+    sub_matches: &ArgMatches,
+    cancelled: Arc<AtomicBool>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Parse every step before touching the target
+    let steps = match sub_matches.get_one::<PathBuf>("script") {
+        Some(script) => parse_steps(&std::fs::read_to_string(script)?, '\n')?,
+        None => parse_steps(sub_matches.get_one::<String>("steps").unwrap(), ';')?,
+    };
+
+    let mut programmer = open_from_matches(sub_matches, cancelled)?;
+    // Power steps leave ICP, so the next target step has to reconnect
+    let mut reconnect = false;
+    for (i, step) in steps.iter().enumerate() {
+        eprintln!("Step {}/{}: {}", i + 1, steps.len(), step.text);
+        let result = if step.name == "power" {
+            reconnect = true;
+            run_step(&step.name, &step.matches, programmer.as_mut())
+        } else if std::mem::take(&mut reconnect) {
+            programmer
+                .identify()
+                .map_err(Into::into)
+                .and_then(|_| run_step(&step.name, &step.matches, programmer.as_mut()))
+        } else {
+            run_step(&step.name, &step.matches, programmer.as_mut())
+        };
+        if let Err(e) = result {
+            eprintln!("Step {} failed: {}", i + 1, step.text);
+            return Err(e);
+        }
+    }
+    programmer.finish()?;
+
+    eprintln!("All {} steps completed", steps.len());
 
     Ok(())
 }
 
+fn run(cancelled: Arc<AtomicBool>) -> Result<(), Box<dyn std::error::Error>> {
+    let matches = cli().get_matches();
+
+    match matches.subcommand() {
+        Some(("identify", sub_matches)) => identify(sub_matches, cancelled),
+        Some(("list-programmers", sub_matches)) => list_programmers(sub_matches, cancelled),
+        Some(("run", sub_matches)) => run_session(sub_matches, cancelled),
+        Some(("gdbserver", sub_matches)) => gdbserver(sub_matches, cancelled),
+        // Switching power needs no part, so --part auto skips detection here
+        Some(("power", sub_matches)) => {
+            let part = get_part(sub_matches);
+            let mut programmer = open_for_part(sub_matches, part, cancelled)?;
+            power(sub_matches, programmer.as_mut())?;
+            programmer.finish()?;
+            Ok(())
+        }
+        Some((name, sub_matches)) => {
+            let mut programmer = open_from_matches(sub_matches, cancelled)?;
+            run_step(name, sub_matches, programmer.as_mut())?;
+            programmer.finish()?;
+            Ok(())
+        }
+        _ => unreachable!(),
+    }
+}
+
 fn main() {
     SimpleLogger::new()
         .with_utc_timestamps()
@@ -410,3 +1234,83 @@ fn main() {
         std::process::exit(1);
     }
 }
+
+#[test]
+fn test_parse_steps() {
+    let steps = parse_steps(
+        "# production sequence\nread backup.hex\n\nerase --start_addr 0x0 --end_addr 0x4000\n",
+        '\n',
+    )
+    .unwrap();
+    assert_eq!(steps.len(), 2);
+    assert_eq!(steps[0].name, "read");
+    assert_eq!(steps[1].text, "erase --start_addr 0x0 --end_addr 0x4000");
+    assert_eq!(
+        steps[1].matches.get_one::<String>("end_addr").unwrap(),
+        "0x4000"
+    );
+
+    let steps = parse_steps("options --set a=b --set c=d; blank-check", ';').unwrap();
+    assert_eq!(
+        steps[0].matches.get_many::<String>("set").unwrap().count(),
+        2
+    );
+    assert_eq!(steps[1].name, "blank-check");
+
+    assert!(parse_steps("identify", ';').is_err());
+
+    let steps = parse_steps("write \"my firmware/fw v2.hex\"; read 'back up.bin'", ';').unwrap();
+    assert_eq!(
+        steps[0].matches.get_one::<String>("input_file").unwrap(),
+        "my firmware/fw v2.hex"
+    );
+    assert_eq!(
+        steps[1].matches.get_one::<String>("output_file").unwrap(),
+        "back up.bin"
+    );
+    assert!(parse_steps("write \"fw.hex", ';').is_err());
+
+    assert!(parse_steps("write", ';').is_err());
+    assert!(parse_steps("# nothing", '\n').is_err());
+}
+
+#[test]
+fn test_parse_range() {
+    let part = &parts::sh68f90::PART;
+    let range = |args: &str| {
+        let steps = parse_steps(&format!("erase {}", args), ';').unwrap();
+        parse_range(&steps[0].matches, part).map_err(|e| e.to_string())
+    };
+    assert_eq!(range(""), Ok((None, None)));
+    assert_eq!(
+        range("--start_addr 0x1000 --end_addr 0x2000"),
+        Ok((Some(0x1000), Some(0x2000)))
+    );
+    assert_eq!(range("--start_addr 0x1000"), Ok((Some(0x1000), None)));
+    assert!(range("--start_addr 0x1000 --end_addr 0x1000").is_err());
+    assert!(range("--start_addr 0x1000 --end_addr 0x800").is_err());
+    assert!(range(&format!(
+        "--end_addr {:#x}",
+        part.flash_size + part.sector_size
+    ))
+    .is_err());
+    assert!(range(&format!("--start_addr {:#x}", part.flash_size)).is_err());
+    assert!(range("--start_addr 0x1001").is_err());
+
+    let steps = parse_steps("blank-check --start_addr 0x1000 --end_addr 0x800", ';').unwrap();
+    assert!(parse_range(&steps[0].matches, part).is_err());
+}
+
+#[test]
+fn test_format_memory() {
+    let data: Vec<u8> = (0..18).collect();
+    assert_eq!(
+        format_memory(0x30, &data),
+        "0030: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n0040: 10 11\n"
+    );
+}
+
+#[test]
+fn test_cli() {
+    cli().debug_assert();
+}
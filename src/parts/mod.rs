@@ -1,5 +1,6 @@
 use indexmap::IndexMap;
 use phf::phf_map;
+use thiserror::Error;
 
 pub mod adc2015;
 pub mod ch6935a;
@@ -366,7 +367,7 @@ pub fn parse_code_options(code_options: &[u8], options: &Options) -> Vec<ParsedO
 
         let byte = code_options[info.byte_index];
         let bit_count = info.bits_end - info.bits_start + 1;
-        let mask = (1u8 << bit_count) - 1;
+        let mask = u8::MAX >> (8 - bit_count);
         let raw_value = (byte >> info.bits_start) & mask;
 
         let description = info.states.get(&raw_value).copied();
@@ -385,6 +386,97 @@ pub fn parse_code_options(code_options: &[u8], options: &Options) -> Vec<ParsedO
     parsed
 }
 
+#[derive(Debug, Error, PartialEq)]
+pub enum OptionError {
+    #[error("Invalid option assignment {0:?}, expected NAME=VALUE")]
+    InvalidAssignment(String),
+    #[error("Unknown code option {0}")]
+    UnknownOption(String),
+    #[error("Code option {0} is not editable")]
+    NotEditable(&'static str),
+    #[error("Code option {name} has no state {value:?} (valid: {valid})")]
+    UndefinedState {
+        name: &'static str,
+        value: String,
+        valid: String,
+    },
+    #[error(
+        "Code option {name} is in byte {byte_index}, but only {len} code option bytes are present"
+    )]
+    ByteOutOfRange {
+        name: &'static str,
+        byte_index: usize,
+        len: usize,
+    },
+}
+
+/// Split a `NAME=VALUE` option assignment
+pub fn parse_option_assignment(s: &str) -> Result<(&str, &str), OptionError> {
+    s.split_once('=')
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .filter(|(name, value)| !name.is_empty() && !value.is_empty())
+        .ok_or_else(|| OptionError::InvalidAssignment(s.to_string()))
+}
+
+/// Set the named option (case insensitive) in `code_options`. `value` is either the raw state value
+/// (decimal or 0x-prefixed hex) or a state description (case insensitive).
+pub fn set_code_option(
+    code_options: &mut [u8],
+    options: &Options,
+    name: &str,
+    value: &str,
+) -> Result<(), OptionError> {
+    let (name, info) = options
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(n, info)| (*n, info))
+        .ok_or_else(|| OptionError::UnknownOption(name.to_string()))?;
+
+    if !info.editable {
+        return Err(OptionError::NotEditable(name));
+    }
+    if info.byte_index >= code_options.len() {
+        return Err(OptionError::ByteOutOfRange {
+            name,
+            byte_index: info.byte_index,
+            len: code_options.len(),
+        });
+    }
+
+    let number = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => value.parse::<u8>().ok(),
+    };
+    let raw_value = number
+        .filter(|v| info.states.contains_key(v))
+        .or_else(|| {
+            info.states
+                .iter()
+                .find(|(_, desc)| desc.eq_ignore_ascii_case(value))
+                .map(|(v, _)| *v)
+        })
+        .ok_or_else(|| OptionError::UndefinedState {
+            name,
+            value: value.to_string(),
+            valid: info
+                .states
+                .iter()
+                .map(|(v, desc)| format!("{} = {}", v, desc))
+                .collect::<Vec<_>>()
+                .join(", "),
+        })?;
+
+    let bit_count = info.bits_end - info.bits_start + 1;
+    let mask = (u8::MAX >> (8 - bit_count)) << info.bits_start;
+    let byte = &mut code_options[info.byte_index];
+    *byte = (*byte & !mask) | ((raw_value << info.bits_start) & mask);
+
+    Ok(())
+}
+
 /// Format parsed options for user-friendly display as a table
 pub fn format_parsed_options(parsed: &[ParsedOption]) -> String {
     if parsed.is_empty() {
@@ -830,6 +922,16 @@ pub static PARTS: phf::Map<&'static str, &'static Part> = phf_map! {
     "xa2000" => &xa2000::PART,
 };
 
+/// Name the part is registered under in [`PARTS`]. `part` must be a reference taken
+/// from [`PARTS`].
+pub fn part_name(part: &Part) -> &'static str {
+    PARTS
+        .entries()
+        .find(|(_, p)| std::ptr::eq(**p, part))
+        .map(|(name, _)| *name)
+        .unwrap()
+}
+
 /// Find all part names that match a given JTAG ID
 pub fn find_parts_by_jtag_id(jtag_id: u16) -> Vec<&'static str> {
     PARTS
@@ -847,3 +949,49 @@ pub fn find_parts_by_part_number(part_number: &[u8; 5]) -> Vec<&'static str> {
         .map(|(name, _)| *name)
         .collect()
 }
+
+#[test]
+fn test_set_code_option() {
+    let part = PARTS["sh68f90"];
+    let options = (part.options)();
+    let mut code_options = part.default_code_options.to_vec();
+
+    set_code_option(&mut code_options, &options, "OP_RST", "1").unwrap();
+    assert_eq!(code_options[0], 0x84 | 0x20);
+    set_code_option(
+        &mut code_options,
+        &options,
+        "op_rst",
+        "p0.2 used as rst pin",
+    )
+    .unwrap();
+    assert_eq!(code_options[0], 0x84);
+
+    assert_eq!(
+        set_code_option(&mut code_options, &options, "OP_OSCDRIVE", "1"),
+        Err(OptionError::NotEditable("OP_OSCDRIVE"))
+    );
+    assert!(matches!(
+        set_code_option(&mut code_options, &options, "OP_RST", "2"),
+        Err(OptionError::UndefinedState { .. })
+    ));
+    assert!(matches!(
+        set_code_option(&mut code_options, &options, "OP_NOPE", "1"),
+        Err(OptionError::UnknownOption(_))
+    ));
+    assert_eq!(parse_option_assignment("OP_RST = 1"), Ok(("OP_RST", "1")));
+
+    // Options spanning a whole byte
+    let part = PARTS["sh79f9206"];
+    let options = (part.options)();
+    let mut code_options = part.default_code_options.to_vec();
+    set_code_option(&mut code_options, &options, "OP_WDT", "165").unwrap();
+    assert_eq!(code_options[6], 165);
+    let parsed = parse_code_options(&code_options, &options);
+    let wdt = parsed.iter().find(|o| o.name == "OP_WDT").unwrap();
+    assert_eq!(wdt.raw_value, 165);
+    assert_eq!(wdt.description, Some("Disable WDT function"));
+    set_code_option(&mut code_options, &options, "OP_WDT", "enable wdt function").unwrap();
+    assert_eq!(code_options[6], 0);
+    assert!(parse_option_assignment("OP_RST").is_err());
+}
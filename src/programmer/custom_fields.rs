@@ -0,0 +1,422 @@
+use super::super::parts::{part_name, Part, PARTS};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use thiserror::Error;
+
+/// Size of the custom fields region starting at the customer_id address
+pub const CUSTOM_FIELDS_REGION_SIZE: usize = 64;
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parse a hex string with an optional 0x prefix, None if it is not valid hex
+pub fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[derive(Debug, Error)]
+pub enum CustomFieldsFileError {
+    #[error("Custom fields file I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to write TOML: {0}")]
+    TomlSerialize(#[from] toml::ser::Error),
+    #[error("Failed to parse TOML: {0}")]
+    TomlParse(#[from] toml::de::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Field {field} is not a valid hex string")]
+    InvalidHex { field: &'static str },
+    #[error("Field {field} must be exactly {expected} bytes, got {actual}")]
+    InvalidLength {
+        field: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("Custom fields file is for part {file}, not {part}")]
+    PartMismatch { file: String, part: &'static str },
+}
+
+/// On-disk form of [`CustomFields`], written as JSON for `.json` files and TOML otherwise.
+/// Byte fields are hex strings.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct CustomFieldsDocument {
+    pub part: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub customer_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operation_number: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code_options: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub security: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<String>,
+    /// Address of the raw region, informational only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region_address: Option<u32>,
+    /// Raw custom fields region starting at the customer ID address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+}
+
+fn from_hex(field: &'static str, s: &str) -> Result<Vec<u8>, CustomFieldsFileError> {
+    parse_hex(s).ok_or(CustomFieldsFileError::InvalidHex { field })
+}
+
+fn from_hex_array<const N: usize>(
+    field: &'static str,
+    s: &str,
+) -> Result<[u8; N], CustomFieldsFileError> {
+    let data = from_hex(field, s)?;
+    data.as_slice()
+        .try_into()
+        .map_err(|_| CustomFieldsFileError::InvalidLength {
+            field,
+            expected: N,
+            actual: data.len(),
+        })
+}
+
+/// Custom fields and code options stored in the target's custom region
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CustomFields {
+    pub customer_id: Option<[u8; 4]>,
+    pub operation_number: Option<[u8; 2]>,
+    /// Full code options (both the lower 4 bytes and the upper bytes at 0x1100)
+    pub customer_option: Option<Vec<u8>>,
+    pub security: Option<Vec<u8>>,
+    pub serial_number: Option<[u8; 4]>,
+    /// Raw region as read from the target. When set it is the base the fields are
+    /// written over, so bytes outside the known fields are reproduced too.
+    pub region: Option<Vec<u8>>,
+}
+
+impl CustomFields {
+    /// Extract the fields from the region read at `customer_id.address` and the
+    /// upper code option bytes read from 0x1100 (empty for parts with <= 4 option bytes)
+    pub fn from_region(part: &Part, buffer: &[u8], upper_options: &[u8]) -> Self {
+        let base = part.customer_id.address;
+        let region_size = buffer.len();
+        let mut fields = CustomFields {
+            customer_id: Some(buffer[0..4].try_into().unwrap()),
+            region: Some(buffer.to_vec()),
+            ..Default::default()
+        };
+
+        let offset = (part.operation_number.address - base) as usize;
+        if offset + 2 <= region_size {
+            fields.operation_number = Some(buffer[offset..offset + 2].try_into().unwrap());
+        }
+
+        let offset = (part.security.address - base) as usize;
+        let security_len = part.security_length();
+        if offset + security_len <= region_size {
+            fields.security = Some(buffer[offset..offset + security_len].to_vec());
+        }
+
+        let offset = (part.serial_number.address - base) as usize;
+        if offset + 4 <= region_size {
+            fields.serial_number = Some(buffer[offset..offset + 4].try_into().unwrap());
+        }
+
+        let offset = (part.customer_option.address - base) as usize;
+        let first_part_size = 4.min(part.option_byte_count);
+        let mut code_options = buffer[offset..offset + first_part_size].to_vec();
+        code_options.extend_from_slice(upper_options);
+        fields.customer_option = Some(code_options);
+
+        fields
+    }
+
+    /// Build the region written at `customer_id.address`, plus the upper code option
+    /// bytes to write to 0x1100 (if any)
+    pub fn to_region(&self, part: &Part) -> (Vec<u8>, Option<Vec<u8>>) {
+        let base = part.customer_id.address;
+        let mut buffer = self.region.clone().unwrap_or_default();
+        buffer.resize(CUSTOM_FIELDS_REGION_SIZE, 0);
+
+        if let Some(data) = &self.customer_id {
+            // customer_id is at offset 0
+            buffer[0..4].copy_from_slice(data);
+        }
+
+        if let Some(data) = &self.operation_number {
+            let offset = (part.operation_number.address - base) as usize;
+            if offset + 2 <= CUSTOM_FIELDS_REGION_SIZE {
+                buffer[offset..offset + 2].copy_from_slice(data);
+            }
+        }
+
+        let mut customer_option_upper = None;
+        if let Some(data) = &self.customer_option {
+            let offset = (part.customer_option.address - base) as usize;
+            // First 4 bytes go to the buffer at customer_option offset
+            if offset < CUSTOM_FIELDS_REGION_SIZE {
+                let first_part_len = data.len().min(4).min(CUSTOM_FIELDS_REGION_SIZE - offset);
+                buffer[offset..offset + first_part_len].copy_from_slice(&data[..first_part_len]);
+            }
+            // Remaining bytes (if any) go to 0x1100
+            if data.len() > 4 {
+                customer_option_upper = Some(data[4..].to_vec());
+            }
+        }
+
+        if let Some(data) = &self.security {
+            let offset = (part.security.address - base) as usize;
+            if offset < CUSTOM_FIELDS_REGION_SIZE {
+                let len = data.len().min(CUSTOM_FIELDS_REGION_SIZE - offset);
+                buffer[offset..offset + len].copy_from_slice(&data[..len]);
+            }
+        }
+
+        if let Some(data) = &self.serial_number {
+            let offset = (part.serial_number.address - base) as usize;
+            if offset + 4 <= CUSTOM_FIELDS_REGION_SIZE {
+                buffer[offset..offset + 4].copy_from_slice(data);
+            }
+        }
+
+        (buffer, customer_option_upper)
+    }
+
+    /// Use the fields of `self` where present, falling back to `defaults`. The raw region
+    /// is never taken from `defaults`, so unknown bytes are only carried over on request.
+    pub fn or(&self, defaults: &CustomFields) -> CustomFields {
+        CustomFields {
+            customer_id: self.customer_id.or(defaults.customer_id),
+            operation_number: self.operation_number.or(defaults.operation_number),
+            customer_option: self
+                .customer_option
+                .clone()
+                .or_else(|| defaults.customer_option.clone()),
+            security: self.security.clone().or_else(|| defaults.security.clone()),
+            serial_number: self.serial_number.or(defaults.serial_number),
+            region: self.region.clone(),
+        }
+    }
+
+    pub fn to_document(&self, part: &Part) -> CustomFieldsDocument {
+        CustomFieldsDocument {
+            part: part_name(part).to_string(),
+            customer_id: self.customer_id.map(|v| to_hex(&v)),
+            operation_number: self.operation_number.map(|v| to_hex(&v)),
+            code_options: self.customer_option.as_deref().map(to_hex),
+            security: self.security.as_deref().map(to_hex),
+            serial_number: self.serial_number.map(|v| to_hex(&v)),
+            region_address: self.region.as_ref().map(|_| part.customer_id.address),
+            region: self.region.as_deref().map(to_hex),
+        }
+    }
+
+    /// Convert a document back to fields, checking that it was captured from `part`
+    pub fn from_document(
+        document: &CustomFieldsDocument,
+        part: &Part,
+    ) -> Result<Self, CustomFieldsFileError> {
+        let same_part = PARTS
+            .get(document.part.as_str())
+            .is_some_and(|p| std::ptr::eq(*p, part));
+        if !same_part {
+            return Err(CustomFieldsFileError::PartMismatch {
+                file: document.part.clone(),
+                part: part_name(part),
+            });
+        }
+
+        let region = document
+            .region
+            .as_deref()
+            .map(|s| from_hex("region", s))
+            .transpose()?;
+        if let Some(region) = &region {
+            if region.len() != CUSTOM_FIELDS_REGION_SIZE {
+                return Err(CustomFieldsFileError::InvalidLength {
+                    field: "region",
+                    expected: CUSTOM_FIELDS_REGION_SIZE,
+                    actual: region.len(),
+                });
+            }
+        }
+
+        Ok(CustomFields {
+            customer_id: document
+                .customer_id
+                .as_deref()
+                .map(|s| from_hex_array("customer_id", s))
+                .transpose()?,
+            operation_number: document
+                .operation_number
+                .as_deref()
+                .map(|s| from_hex_array("operation_number", s))
+                .transpose()?,
+            customer_option: document
+                .code_options
+                .as_deref()
+                .map(|s| from_hex("code_options", s))
+                .transpose()?,
+            security: document
+                .security
+                .as_deref()
+                .map(|s| from_hex("security", s))
+                .transpose()?,
+            serial_number: document
+                .serial_number
+                .as_deref()
+                .map(|s| from_hex_array("serial_number", s))
+                .transpose()?,
+            region,
+        })
+    }
+
+    /// Save the fields as TOML, or JSON for `.json` files
+    pub fn save(&self, part: &Part, path: &Path) -> Result<(), CustomFieldsFileError> {
+        let document = self.to_document(part);
+        let contents = if is_json(path) {
+            serde_json::to_string_pretty(&document)? + "\n"
+        } else {
+            toml::to_string(&document)?
+        };
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// Load fields saved with [`CustomFields::save`] for `part`
+    pub fn load(part: &Part, path: &Path) -> Result<Self, CustomFieldsFileError> {
+        let contents = fs::read_to_string(path)?;
+        let document: CustomFieldsDocument = if is_json(path) {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+        Self::from_document(&document, part)
+    }
+
+    /// True if the upper code option bytes have non-editable bits that differ from
+    /// the part defaults, in which case the alternate mass erase must be used
+    pub fn needs_alternate_erase(&self, part: &Part) -> bool {
+        let (Some(code_options), Some(expected_upper)) =
+            (&self.customer_option, part.upper_code_option_defaults())
+        else {
+            return false;
+        };
+
+        let mask = part.code_option_mask;
+        let mut differs = false;
+        for (i, &expected) in expected_upper.iter().enumerate() {
+            let idx = 4 + i;
+            if idx < code_options.len() {
+                let current = code_options[idx] & !mask[idx];
+                if current != expected {
+                    eprintln!(
+                        "Warning: Code option byte {} has non-editable bits that differ from defaults (current: {:#04x}, expected: {:#04x})",
+                        idx, current, expected
+                    );
+                    differs = true;
+                }
+            }
+        }
+        differs
+    }
+
+    /// Compare the fields set in `self` against `actual`, returning the name, expected
+    /// and actual value of each mismatching field. Code options are compared over the
+    /// length of the expected value.
+    pub fn mismatches(&self, actual: &CustomFields) -> Vec<(&'static str, Vec<u8>, Vec<u8>)> {
+        let mut mismatches = Vec::new();
+        let mut check = |name, expected: Option<&[u8]>, actual: Option<&[u8]>| {
+            if let Some(expected) = expected {
+                let actual = actual.unwrap_or_default();
+                if actual.get(..expected.len()) != Some(expected) {
+                    mismatches.push((name, expected.to_vec(), actual.to_vec()));
+                }
+            }
+        };
+
+        check(
+            "Customer ID",
+            self.customer_id.as_ref().map(|v| &v[..]),
+            actual.customer_id.as_ref().map(|v| &v[..]),
+        );
+        check(
+            "Code Options",
+            self.customer_option.as_deref(),
+            actual.customer_option.as_deref(),
+        );
+        check(
+            "Serial Number",
+            self.serial_number.as_ref().map(|v| &v[..]),
+            actual.serial_number.as_ref().map(|v| &v[..]),
+        );
+
+        mismatches
+    }
+
+    pub fn print(&self) {
+        if let Some(customer_id) = &self.customer_id {
+            eprintln!("Customer ID: {}", to_hex(customer_id));
+        }
+        if let Some(operation_number) = &self.operation_number {
+            eprintln!("Operation Number: {}", to_hex(operation_number));
+        }
+        if let Some(security) = &self.security {
+            eprintln!("Security Bits: {}", to_hex(security));
+        }
+        if let Some(serial_number) = &self.serial_number {
+            eprintln!("Serial Number: {}", to_hex(serial_number));
+        }
+        if let Some(code_options) = &self.customer_option {
+            eprintln!("Code Options: {}", to_hex(code_options));
+        }
+    }
+}
+
+#[test]
+fn test_custom_fields_document_round_trip() {
+    let part = PARTS["sh68f90"];
+    let mut region = vec![0u8; CUSTOM_FIELDS_REGION_SIZE];
+    region[0..4].copy_from_slice(&[1, 2, 3, 4]);
+    region[CUSTOM_FIELDS_REGION_SIZE - 1] = 0x5a;
+    let fields = CustomFields::from_region(part, &region, &[0x11, 0x22, 0x33, 0x44]);
+
+    let document = fields.to_document(part);
+    let toml = toml::to_string(&document).unwrap();
+    let parsed: CustomFieldsDocument = toml::from_str(&toml).unwrap();
+    assert_eq!(CustomFields::from_document(&parsed, part).unwrap(), fields);
+
+    let json = serde_json::to_string(&document).unwrap();
+    let parsed: CustomFieldsDocument = serde_json::from_str(&json).unwrap();
+    assert_eq!(CustomFields::from_document(&parsed, part).unwrap(), fields);
+
+    // Bytes outside the known fields are reproduced from the raw region
+    assert_eq!(fields.to_region(part).0, region);
+}
+
+#[test]
+fn test_custom_fields_document_part_mismatch() {
+    let document = CustomFieldsDocument {
+        part: "sh68f91".to_string(),
+        ..Default::default()
+    };
+    assert!(matches!(
+        CustomFields::from_document(&document, PARTS["sh68f90"]),
+        Err(CustomFieldsFileError::PartMismatch { .. })
+    ));
+}
@@ -1,2 +1,1231 @@
+use super::parts::{
+    find_parts_by_jtag_id, find_parts_by_part_number, format_parsed_options, parse_code_options,
+    Part, Region, PARTS,
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use phf::phf_map;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+pub mod custom_fields;
+pub mod sim;
 pub mod sinodude_serial;
+pub use custom_fields::*;
+pub use sim::*;
 pub use sinodude_serial::*;
+
+pub use sinodude_protocol::{CoreStatus, MemorySpace, BREAKPOINT_COUNT};
+
+/// Default size of the flash chunks used by the generic read/write/verify loops
+pub const CHUNK_SIZE: usize = 1024;
+
+/// Value of an erased flash byte
+pub const ERASED_BYTE: u8 = 0x00;
+
+/// Values accepted by `--verify`
+pub const VERIFY_MODES: [&str; 2] = ["read", "checksum"];
+
+/// How written flash is verified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// Read back every byte and compare
+    Read,
+    /// Compare per-sector CRC32s computed by the programmer, reading back only the
+    /// sectors that differ
+    Checksum,
+}
+
+impl VerifyMode {
+    pub fn from_arg(mode: &str) -> Result<Self, ProgrammerError> {
+        match mode {
+            "read" => Ok(VerifyMode::Read),
+            "checksum" => Ok(VerifyMode::Checksum),
+            _ => Err(ProgrammerError::UnknownVerifyMode(mode.to_string())),
+        }
+    }
+}
+
+/// Values accepted for the memory space of the debug memory commands
+pub const MEMORY_SPACES: [&str; 4] = ["iram", "sfr", "xdata", "code"];
+
+pub fn memory_space_from_arg(space: &str) -> Result<MemorySpace, ProgrammerError> {
+    match space {
+        "iram" => Ok(MemorySpace::Iram),
+        "sfr" => Ok(MemorySpace::Sfr),
+        "xdata" => Ok(MemorySpace::Xdata),
+        "code" => Ok(MemorySpace::Code),
+        _ => Err(ProgrammerError::UnknownMemorySpace(space.to_string())),
+    }
+}
+
+/// Register state of the 8051 core
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    /// R0-R7 of the current bank
+    pub r: [u8; 8],
+    pub a: u8,
+    pub b: u8,
+    pub psw: u8,
+    pub sp: u8,
+    pub dptr: u16,
+    pub pc: u16,
+}
+
+impl Registers {
+    /// SFR addresses of A, B, PSW, SP, DPL and DPH
+    pub const SFRS: [u8; 6] = [0xE0, 0xF0, 0xD0, 0x81, 0x82, 0x83];
+
+    /// IRAM address of R0 in the register bank `psw` selects
+    pub const fn bank_base(psw: u8) -> u16 {
+        (psw & 0x18) as u16
+    }
+}
+
+impl std::fmt::Display for Registers {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "PC={:#06x} A={:#04x} B={:#04x} PSW={:#04x} SP={:#04x} DPTR={:#06x}",
+            self.pc, self.a, self.b, self.psw, self.sp, self.dptr
+        )?;
+        for (i, r) in self.r.iter().enumerate() {
+            write!(f, " R{}={:#04x}", i, r)?;
+        }
+        Ok(())
+    }
+}
+
+/// Address of the upper code option bytes (bytes 4+) for parts with more than 4 option bytes
+pub const UPPER_CODE_OPTIONS_ADDRESS: u32 = 0x1100;
+
+#[derive(Debug, Error)]
+pub enum ProgrammerError {
+    #[error(transparent)]
+    SinodudeSerial(#[from] SinodudeSerialProgrammerError),
+    #[error(transparent)]
+    Sim(#[from] SimProgrammerError),
+    #[error("The programmer does not support {0}")]
+    Unsupported(&'static str),
+    #[error("Unknown programmer: {0}")]
+    UnknownProgrammer(String),
+    #[error("Unknown verify mode: {0}")]
+    UnknownVerifyMode(String),
+    #[error("Unknown memory space: {0}")]
+    UnknownMemorySpace(String),
+    #[error("Access of {len} bytes at {addr:#x} is outside {space:?} memory")]
+    MemoryOutOfRange {
+        space: MemorySpace,
+        addr: u16,
+        len: usize,
+    },
+    #[error("{0:?} memory is read only")]
+    ReadOnlyMemory(MemorySpace),
+    #[error("Breakpoint {0} does not exist, the target has {BREAKPOINT_COUNT}")]
+    InvalidBreakpoint(usize),
+    #[error("No part selected")]
+    NoPartSelected,
+    #[error("JTAG ID mismatch: expected {expected:#06x}, got {actual:#06x}")]
+    JtagIdMismatch { expected: u16, actual: u16 },
+    #[error("Part number mismatch: expected {expected}, got {actual}")]
+    PartNumberMismatch { expected: String, actual: String },
+    #[error("Custom block {0:#04x} has no part number")]
+    NoPartNumber(u8),
+    #[error("No known part matches the target")]
+    NoMatchingPart,
+    #[error("Target matches several parts ({}), select one with --part", .0.join(", "))]
+    AmbiguousPart(Vec<&'static str>),
+    #[error("Operation cancelled")]
+    Cancelled,
+    #[error("Address range {start:#x}-{end:#x} is empty or extends beyond the {flash_size:#x} bytes of flash")]
+    InvalidRange {
+        start: usize,
+        end: usize,
+        flash_size: usize,
+    },
+    #[error("Verification failed at address {0:#x}")]
+    VerificationFailed(u32),
+    #[error(
+        "Flash not blank after erase: {len} byte(s) in {count} range(s) starting at {addr:#x}"
+    )]
+    EraseVerificationFailed { addr: u32, len: usize, count: usize },
+    #[error("Customer option length {provided} exceeds maximum {max}")]
+    CustomerOptionLengthExceeded { provided: usize, max: usize },
+    #[error("Non-editable bits modified at byte {byte}: provided {provided:#04x}, expected {expected:#04x} (mask {mask:#04x})")]
+    NonEditableBitsModified {
+        byte: usize,
+        provided: u8,
+        expected: u8,
+        mask: u8,
+    },
+    #[error("Writing security bits is only supported for security_level 4 and chip_type 0x07 (got security_level {security_level}, chip_type {chip_type:#04x})")]
+    UnsupportedSecurityWrite { security_level: u8, chip_type: u8 },
+}
+
+/// Backend-specific settings collected from the command line
+#[derive(Debug, Clone, Default)]
+pub struct ProgrammerConfig {
+    /// Serial port for serial-attached programmers
+    pub port: Option<String>,
+    /// File the simulated programmer loads its target state from and saves it to
+    pub sim_state: Option<PathBuf>,
+    /// Part simulated by the sim programmer, defaults to the selected part
+    pub sim_part: Option<String>,
+    /// How serial-attached programmer boards are reset after opening the port
+    pub reset: ResetMode,
+}
+
+/// Opens a programmer for the given part, or with no part selected yet when the
+/// part is to be detected from the target
+pub type ProgrammerConstructor = fn(
+    &ProgrammerConfig,
+    Option<&'static Part>,
+    Arc<AtomicBool>,
+) -> Result<Box<dyn Programmer>, ProgrammerError>;
+
+pub static PROGRAMMERS: phf::Map<&'static str, ProgrammerConstructor> = phf_map! {
+    "sinodude-serial" => SinodudeSerialProgrammer::open,
+    "sim" => SimProgrammer::open,
+};
+
+/// Open the programmer registered under `name`
+pub fn open_programmer(
+    name: &str,
+    config: &ProgrammerConfig,
+    part: Option<&'static Part>,
+    cancelled: Arc<AtomicBool>,
+) -> Result<Box<dyn Programmer>, ProgrammerError> {
+    let open = PROGRAMMERS
+        .get(name)
+        .ok_or_else(|| ProgrammerError::UnknownProgrammer(name.to_string()))?;
+    open(config, part, cancelled)
+}
+
+/// Address of the 16-byte block holding the part number for the given custom_block type.
+/// The part number itself is at bytes 9..14 of the block.
+pub fn part_number_block_address(custom_block: u8) -> Option<u32> {
+    match custom_block {
+        0x02 => Some(0x0A00),
+        0x03 => Some(0x1200),
+        0x04 => Some(0x2200),
+        _ => None,
+    }
+}
+
+fn bytes_progress_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template("{msg} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        .unwrap()
+        .progress_chars("=>-")
+}
+
+/// Leave a progress bar showing why the operation stopped
+fn abandon_progress(progress: &ProgressBar, error: &ProgrammerError, message: &'static str) {
+    if matches!(error, ProgrammerError::Cancelled) {
+        progress.abandon_with_message("Cancelled");
+    } else {
+        progress.abandon_with_message(message);
+    }
+}
+
+/// A programmer capable of reading, writing and erasing a single target part.
+///
+/// Backends implement the low level primitives; the provided methods build the
+/// chunked read/write/verify loops and custom field handling on top of them.
+pub trait Programmer {
+    /// The part this programmer was opened for or that was selected after detection
+    fn selected_part(&self) -> Option<&'static Part>;
+
+    /// Select the part to program, e.g. after detecting it with [`Programmer::detect_parts`]
+    fn set_part(&mut self, part: &'static Part) -> Result<(), ProgrammerError>;
+
+    /// True once the user requested cancellation (Ctrl+C)
+    fn is_cancelled(&self) -> bool;
+
+    /// Bring up the programmer and connect to the target. Does nothing if already connected.
+    fn connect_target(&mut self) -> Result<(), ProgrammerError>;
+
+    /// Read the target's JTAG ID
+    fn read_jtag_id(&mut self) -> Result<u16, ProgrammerError>;
+
+    /// Configure the programmer for `chip_type` and read the target's part number from
+    /// the block used by `custom_block` parts. Returns None if that layout has no part number.
+    fn read_part_number(
+        &mut self,
+        chip_type: u8,
+        custom_block: u8,
+    ) -> Result<Option<[u8; 5]>, ProgrammerError>;
+
+    /// Read `length` bytes of flash starting at `addr`
+    fn read_chunk(&mut self, addr: u32, length: u16) -> Result<Vec<u8>, ProgrammerError>;
+
+    /// Most bytes a single [`Programmer::read_chunk`] or [`Programmer::write_chunk`] call takes
+    fn chunk_size(&self) -> usize {
+        CHUNK_SIZE
+    }
+
+    /// Read `len` bytes of flash starting at `addr`, calling `progress` with the number of
+    /// bytes read so far. Backends that can stream a whole range override this; the
+    /// default reads it chunk by chunk.
+    fn read_flash_stream(
+        &mut self,
+        addr: u32,
+        len: usize,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<Vec<u8>, ProgrammerError> {
+        self.read_flash_chunked(addr, len, progress)
+    }
+
+    /// [`Programmer::read_flash_stream`] with one [`Programmer::read_chunk`] call per chunk
+    fn read_flash_chunked(
+        &mut self,
+        addr: u32,
+        len: usize,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<Vec<u8>, ProgrammerError> {
+        let chunk_size = self.chunk_size();
+        let start_addr = addr as usize;
+        let end_addr = start_addr + len;
+        let mut contents = Vec::with_capacity(len);
+        for chunk_addr in (start_addr..end_addr).step_by(chunk_size) {
+            self.check_cancelled()?;
+            let end = (chunk_addr + chunk_size).min(end_addr);
+            contents
+                .extend_from_slice(&self.read_chunk(chunk_addr as u32, (end - chunk_addr) as u16)?);
+            progress(contents.len());
+        }
+        Ok(contents)
+    }
+
+    /// CRC32 (see [`crc32`]) of `len` bytes of flash at `addr`, computed by the programmer
+    /// without sending the data to the host. None if the backend cannot do this.
+    fn flash_checksum(&mut self, _addr: u32, _len: usize) -> Result<Option<u32>, ProgrammerError> {
+        Ok(None)
+    }
+
+    /// Write `data` to (already erased) flash starting at `addr`
+    fn write_chunk(&mut self, addr: u32, data: &[u8]) -> Result<(), ProgrammerError>;
+
+    /// Erase the flash sector containing `addr`
+    fn erase_sector(&mut self, addr: u32) -> Result<(), ProgrammerError>;
+
+    /// Erase the whole flash. `alternate` selects the erase used when code options
+    /// have non-editable bits that differ from defaults.
+    fn mass_erase(&mut self, alternate: bool) -> Result<(), ProgrammerError>;
+
+    /// Read `size` bytes from the given region
+    fn read_region(
+        &mut self,
+        region: Region,
+        address: u32,
+        size: usize,
+    ) -> Result<Vec<u8>, ProgrammerError>;
+
+    /// Write `data` to the custom region at `addr`
+    fn write_custom_region(&mut self, addr: u32, data: &[u8]) -> Result<(), ProgrammerError>;
+
+    // The debug and power primitives are optional: backends without them keep these
+    // defaults, which fail with ProgrammerError::Unsupported.
+
+    /// Stop the target's core and return its program counter. Only reads the program
+    /// counter if the core is already halted.
+    fn halt(&mut self) -> Result<u16, ProgrammerError> {
+        Err(ProgrammerError::Unsupported("on-chip debugging"))
+    }
+
+    /// Let the halted core run again
+    fn resume(&mut self) -> Result<(), ProgrammerError> {
+        Err(ProgrammerError::Unsupported("on-chip debugging"))
+    }
+
+    /// Read `len` bytes of `space` starting at `addr` from the halted core
+    fn read_memory(
+        &mut self,
+        _space: MemorySpace,
+        _addr: u16,
+        _len: usize,
+    ) -> Result<Vec<u8>, ProgrammerError> {
+        Err(ProgrammerError::Unsupported("on-chip debugging"))
+    }
+
+    /// Write `data` to `space` starting at `addr` on the halted core
+    fn write_memory(
+        &mut self,
+        _space: MemorySpace,
+        _addr: u16,
+        _data: &[u8],
+    ) -> Result<(), ProgrammerError> {
+        Err(ProgrammerError::Unsupported("on-chip debugging"))
+    }
+
+    /// Run one instruction of the halted core and return its new program counter
+    fn step(&mut self) -> Result<u16, ProgrammerError> {
+        Err(ProgrammerError::Unsupported("on-chip debugging"))
+    }
+
+    /// Whether the core is halted, where, and which breakpoint stopped it
+    fn core_status(&mut self) -> Result<CoreStatus, ProgrammerError> {
+        Err(ProgrammerError::Unsupported("on-chip debugging"))
+    }
+
+    /// Move the halted core to `pc`
+    fn set_pc(&mut self, _pc: u16) -> Result<(), ProgrammerError> {
+        Err(ProgrammerError::Unsupported("on-chip debugging"))
+    }
+
+    /// Stop the core when it reaches `addr`, using hardware breakpoint `index`
+    fn set_breakpoint(&mut self, _index: usize, _addr: u16) -> Result<(), ProgrammerError> {
+        Err(ProgrammerError::Unsupported("on-chip debugging"))
+    }
+
+    /// Disable hardware breakpoint `index`
+    fn clear_breakpoint(&mut self, _index: usize) -> Result<(), ProgrammerError> {
+        Err(ProgrammerError::Unsupported("on-chip debugging"))
+    }
+
+    /// Power the target without entering ICP so that it runs its own firmware,
+    /// restarting it if it was connected
+    fn power_on(&mut self) -> Result<(), ProgrammerError> {
+        Err(ProgrammerError::Unsupported("power control"))
+    }
+
+    fn power_off(&mut self) -> Result<(), ProgrammerError> {
+        Err(ProgrammerError::Unsupported("power control"))
+    }
+
+    /// Release the target
+    fn finish(&mut self) -> Result<(), ProgrammerError>;
+
+    /// The selected part, failing if none has been selected or detected yet
+    fn part(&self) -> Result<&'static Part, ProgrammerError> {
+        self.selected_part().ok_or(ProgrammerError::NoPartSelected)
+    }
+
+    /// Connect to the target and check that it matches the selected part
+    fn identify(&mut self) -> Result<(), ProgrammerError> {
+        let part = self.part()?;
+        self.connect_target()?;
+
+        let id = self.read_jtag_id()?;
+        eprintln!("Target MCU ID: {:04x}", id);
+        if id != part.jtag_id {
+            let matching_parts = find_parts_by_jtag_id(id);
+            if !matching_parts.is_empty() {
+                eprintln!(
+                    "Parts matching JTAG ID {:#06x}: {}",
+                    id,
+                    matching_parts.join(", ")
+                );
+            }
+            return Err(ProgrammerError::JtagIdMismatch {
+                expected: part.jtag_id,
+                actual: id,
+            });
+        }
+
+        let part_number = self
+            .read_part_number(part.chip_type, part.custom_block)?
+            .ok_or(ProgrammerError::NoPartNumber(part.custom_block))?;
+        eprintln!("Target Part Number: {}", to_hex(&part_number));
+        if part_number != part.part_number {
+            let matching_parts = find_parts_by_part_number(&part_number);
+            if !matching_parts.is_empty() {
+                eprintln!(
+                    "Parts matching part number {}: {}",
+                    to_hex(&part_number),
+                    matching_parts.join(", ")
+                );
+            }
+            return Err(ProgrammerError::PartNumberMismatch {
+                expected: to_hex(&part.part_number),
+                actual: to_hex(&part_number),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Connect to the target and return the names of all parts matching both its JTAG ID
+    /// and its part number. Parts sharing a JTAG ID may use different chip types and part
+    /// number locations, so the part number is read once per distinct layout.
+    fn detect_parts(&mut self) -> Result<Vec<&'static str>, ProgrammerError> {
+        self.connect_target()?;
+
+        let id = self.read_jtag_id()?;
+        eprintln!("Target MCU ID: {:04x}", id);
+        let by_jtag_id = find_parts_by_jtag_id(id);
+
+        let mut layouts: Vec<(u8, u8)> = by_jtag_id
+            .iter()
+            .map(|name| (PARTS[name].chip_type, PARTS[name].custom_block))
+            .collect();
+        layouts.sort_unstable();
+        layouts.dedup();
+
+        let mut candidates = Vec::new();
+        for (chip_type, custom_block) in layouts {
+            let layout_parts = by_jtag_id.iter().copied().filter(|name| {
+                PARTS[name].chip_type == chip_type && PARTS[name].custom_block == custom_block
+            });
+            match self.read_part_number(chip_type, custom_block)? {
+                Some(part_number) => {
+                    eprintln!(
+                        "Target Part Number (chip type {:#04x}): {}",
+                        chip_type,
+                        to_hex(&part_number)
+                    );
+                    let by_part_number = find_parts_by_part_number(&part_number);
+                    candidates.extend(layout_parts.filter(|name| by_part_number.contains(name)));
+                }
+                // No part number to narrow with, every part of this layout remains a candidate
+                None => candidates.extend(layout_parts),
+            }
+        }
+
+        candidates.sort_unstable();
+        Ok(candidates)
+    }
+
+    /// Detect the target's part and select it if the match is unique
+    fn select_detected_part(&mut self) -> Result<&'static Part, ProgrammerError> {
+        let candidates = self.detect_parts()?;
+        match candidates.as_slice() {
+            [] => Err(ProgrammerError::NoMatchingPart),
+            [name] => {
+                eprintln!("Detected part: {}", name);
+                let part = PARTS[name];
+                self.set_part(part)?;
+                Ok(part)
+            }
+            _ => Err(ProgrammerError::AmbiguousPart(candidates)),
+        }
+    }
+
+    /// Registers of the halted core, with R0-R7 taken from the bank PSW selects
+    fn read_registers(&mut self) -> Result<Registers, ProgrammerError> {
+        let mut sfrs = [0u8; 6];
+        for (value, sfr) in sfrs.iter_mut().zip(Registers::SFRS) {
+            *value = self.read_memory(MemorySpace::Sfr, sfr as u16, 1)?[0];
+        }
+        let [a, b, psw, sp, dpl, dph] = sfrs;
+        let bank = self.read_memory(MemorySpace::Iram, Registers::bank_base(psw), 8)?;
+        Ok(Registers {
+            r: bank.try_into().unwrap(),
+            a,
+            b,
+            psw,
+            sp,
+            dptr: u16::from_le_bytes([dpl, dph]),
+            pc: self.core_status()?.pc,
+        })
+    }
+
+    /// Power the target off, wait `off_time` and power it on to run its firmware
+    fn cycle_power(&mut self, off_time: Duration) -> Result<(), ProgrammerError> {
+        self.power_off()?;
+        std::thread::sleep(off_time);
+        self.power_on()
+    }
+
+    fn check_cancelled(&self) -> Result<(), ProgrammerError> {
+        if self.is_cancelled() {
+            Err(ProgrammerError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read_flash(&mut self) -> Result<Vec<u8>, ProgrammerError> {
+        let flash_size = self.part()?.flash_size;
+        self.read_flash_range(0, flash_size)
+    }
+
+    /// Read a specific range of flash (addresses are inclusive of start, exclusive of end)
+    fn read_flash_range(
+        &mut self,
+        start_addr: usize,
+        end_addr: usize,
+    ) -> Result<Vec<u8>, ProgrammerError> {
+        let flash_size = self.part()?.flash_size;
+        let start_addr = start_addr.min(flash_size);
+        let end_addr = end_addr.min(flash_size);
+        let range_size = end_addr.saturating_sub(start_addr);
+
+        if start_addr == 0 && end_addr == flash_size {
+            eprintln!("Reading {} bytes from flash...", range_size);
+        } else {
+            eprintln!(
+                "Reading {} bytes from flash (range {:#x}-{:#x})...",
+                range_size, start_addr, end_addr
+            );
+        }
+
+        let progress = ProgressBar::new(range_size as u64);
+        progress.set_style(bytes_progress_style());
+        progress.set_message("Reading");
+
+        let start = Instant::now();
+        let contents = self
+            .read_flash_stream(start_addr as u32, range_size, &mut |read| {
+                progress.set_position(read as u64)
+            })
+            .inspect_err(|e| abandon_progress(&progress, e, "Read failed"))?;
+        let elapsed = start.elapsed();
+
+        progress.finish_with_message(format!("Read complete in {:.2?}", elapsed));
+        Ok(contents)
+    }
+
+    /// Read a range of flash without progress output
+    fn read_flash_chunks(
+        &mut self,
+        start_addr: usize,
+        end_addr: usize,
+    ) -> Result<Vec<u8>, ProgrammerError> {
+        self.read_flash_stream(
+            start_addr as u32,
+            end_addr.saturating_sub(start_addr),
+            &mut |_| {},
+        )
+    }
+
+    /// Read a range of flash and return the absolute address ranges that are not erased.
+    /// An empty or reversed range is an error rather than a pass.
+    fn blank_check(
+        &mut self,
+        start_addr: usize,
+        end_addr: usize,
+    ) -> Result<Vec<Range<usize>>, ProgrammerError> {
+        check_flash_range(self.part()?, start_addr, end_addr)?;
+        let contents = self.read_flash_range(start_addr, end_addr)?;
+        Ok(non_blank_ranges(&contents)
+            .into_iter()
+            .map(|r| start_addr + r.start..start_addr + r.end)
+            .collect())
+    }
+
+    /// Erase sectors covering the given address range. With `verify_erase`, each
+    /// sector is blank-checked right after it is erased.
+    fn erase_sectors(
+        &mut self,
+        start_addr: u32,
+        end_addr: u32,
+        verify_erase: bool,
+    ) -> Result<(), ProgrammerError> {
+        check_flash_range(self.part()?, start_addr as usize, end_addr as usize)?;
+        let sector_size = self.part()?.sector_size as u32;
+        let first_sector = start_addr / sector_size;
+        let last_sector = (end_addr - 1) / sector_size;
+        let num_sectors = last_sector - first_sector + 1;
+
+        eprintln!(
+            "Erasing {} sector(s) from {:#x} to {:#x}...",
+            num_sectors,
+            first_sector * sector_size,
+            (last_sector + 1) * sector_size
+        );
+
+        let progress = ProgressBar::new(num_sectors as u64);
+        progress.set_style(
+            ProgressStyle::default_bar()
+                .template("{msg} [{bar:40.cyan/blue}] {pos}/{len}")
+                .unwrap()
+                .progress_chars("=>-"),
+        );
+        progress.set_message("Erasing");
+
+        let start = Instant::now();
+        for sector in first_sector..=last_sector {
+            self.check_cancelled().inspect_err(|_| {
+                progress.abandon_with_message("Cancelled");
+            })?;
+            let sector_addr = sector * sector_size;
+            self.erase_sector(sector_addr).inspect_err(|_| {
+                progress.abandon_with_message("Erase failed");
+            })?;
+            if verify_erase {
+                let contents = self.read_flash_chunks(
+                    sector_addr as usize,
+                    (sector_addr + sector_size) as usize,
+                )?;
+                check_blank(sector_addr as usize, &contents).inspect_err(|_| {
+                    progress.abandon_with_message("Erase verify failed");
+                })?;
+            }
+            progress.inc(1);
+        }
+        let elapsed = start.elapsed();
+        progress.finish_with_message(format!("Erase complete in {:.2?}", elapsed));
+
+        Ok(())
+    }
+
+    /// Mass erase, then blank the security region and restore the high code option defaults.
+    /// With `verify_erase`, the whole flash is blank-checked after the erase.
+    fn erase_chip(&mut self, alternate: bool, verify_erase: bool) -> Result<(), ProgrammerError> {
+        if alternate {
+            eprintln!("Mass erasing flash (alternate mode due to non-default code options)...");
+        } else {
+            eprintln!("Mass erasing flash...");
+        }
+        let start = Instant::now();
+
+        self.mass_erase(alternate)?;
+
+        let elapsed = start.elapsed();
+        eprintln!("Mass erase complete in {:.2?}", elapsed);
+
+        if verify_erase {
+            let ranges = self.blank_check(0, self.part()?.flash_size)?;
+            report_non_blank(&ranges)?;
+        }
+
+        self.blank_security_and_set_code_option_defaults()
+    }
+
+    /// Blank security region and set high code option defaults after mass erase.
+    /// For parts with >4-byte code options, writes the non-editable bits from defaults to 0x1100.
+    fn blank_security_and_set_code_option_defaults(&mut self) -> Result<(), ProgrammerError> {
+        let part = self.part()?;
+
+        // Blank security region
+        {
+            let security = &part.security;
+            let security_length = part.security_length();
+            eprintln!(
+                "Blanking security region at {:#x} ({} bytes)...",
+                security.address, security_length
+            );
+            let zeros = vec![0u8; security_length];
+            self.write_custom_region(security.address, &zeros)?;
+        }
+
+        // Set high code option defaults for parts with >4 byte options
+        if let Some(upper) = part.upper_code_option_defaults() {
+            eprintln!(
+                "Setting high code option defaults at {:#x} ({} bytes)...",
+                UPPER_CODE_OPTIONS_ADDRESS,
+                upper.len()
+            );
+            self.write_custom_region(UPPER_CODE_OPTIONS_ADDRESS, &upper)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_flash(&mut self, firmware: &[u8], verify: VerifyMode) -> Result<(), ProgrammerError> {
+        let flash_size = self.part()?.flash_size.min(firmware.len());
+        self.write_flash_range(firmware, 0, flash_size, verify)
+    }
+
+    /// Write a specific range of flash (addresses are inclusive of start, exclusive of end)
+    fn write_flash_range(
+        &mut self,
+        firmware: &[u8],
+        start_addr: usize,
+        end_addr: usize,
+        verify: VerifyMode,
+    ) -> Result<(), ProgrammerError> {
+        let flash_size = self.part()?.flash_size.min(firmware.len());
+        let start_addr = start_addr.min(flash_size);
+        let end_addr = end_addr.min(flash_size);
+        let range_size = end_addr.saturating_sub(start_addr);
+
+        if range_size == 0 {
+            eprintln!("Nothing to write (empty range)");
+            return Ok(());
+        }
+
+        if start_addr == 0 && end_addr == flash_size {
+            eprintln!("Writing {} bytes to flash...", range_size);
+        } else {
+            eprintln!(
+                "Writing {} bytes to flash (range {:#x}-{:#x})...",
+                range_size, start_addr, end_addr
+            );
+        }
+
+        // Write data in chunks
+        let write_progress = ProgressBar::new(range_size as u64);
+        write_progress.set_style(bytes_progress_style());
+        write_progress.set_message("Writing");
+
+        let chunk_size = self.chunk_size();
+        let start = Instant::now();
+        for addr in (start_addr..end_addr).step_by(chunk_size) {
+            self.check_cancelled().inspect_err(|_| {
+                write_progress.abandon_with_message("Cancelled");
+            })?;
+            let end = (addr + chunk_size).min(end_addr);
+            let chunk = &firmware[addr..end];
+            self.write_chunk(addr as u32, chunk).inspect_err(|_| {
+                write_progress.abandon_with_message("Write failed");
+            })?;
+            write_progress.set_position((end - start_addr) as u64);
+        }
+        let elapsed = start.elapsed();
+        write_progress.finish_with_message(format!("Write complete in {:.2?}", elapsed));
+
+        match verify {
+            VerifyMode::Read => self.verify_flash_read(firmware, start_addr, end_addr),
+            VerifyMode::Checksum => self.verify_flash_checksums(firmware, start_addr, end_addr),
+        }
+    }
+
+    /// Read back a range of flash and compare it with `firmware`
+    fn verify_flash_read(
+        &mut self,
+        firmware: &[u8],
+        start_addr: usize,
+        end_addr: usize,
+    ) -> Result<(), ProgrammerError> {
+        let range_size = end_addr - start_addr;
+        let verify_progress = ProgressBar::new(range_size as u64);
+        verify_progress.set_style(bytes_progress_style());
+        verify_progress.set_message("Verifying");
+
+        let start = Instant::now();
+        let contents = self
+            .read_flash_stream(start_addr as u32, range_size, &mut |read| {
+                verify_progress.set_position(read as u64)
+            })
+            .inspect_err(|e| abandon_progress(&verify_progress, e, "Verify failed"))?;
+
+        for addr in (start_addr..end_addr).step_by(CHUNK_SIZE) {
+            let end = (addr + CHUNK_SIZE).min(end_addr);
+            let expected = &firmware[addr..end];
+            let actual = &contents[addr - start_addr..end - start_addr];
+
+            if expected != actual {
+                verify_progress.abandon_with_message("Verify failed");
+                eprintln!("Verification failed at address {:#x}", addr);
+                eprintln!("Expected: {:02x?}", expected);
+                eprintln!("Actual:   {:02x?}", actual);
+                return Err(ProgrammerError::VerificationFailed(addr as u32));
+            }
+        }
+        let elapsed = start.elapsed();
+        verify_progress.finish_with_message(format!("Verify complete in {:.2?}", elapsed));
+
+        Ok(())
+    }
+
+    /// Compare per-sector checksums of a range of flash with `firmware`, reading back
+    /// only the sectors whose checksum differs. Falls back to reading back the whole
+    /// range if the programmer cannot compute checksums.
+    fn verify_flash_checksums(
+        &mut self,
+        firmware: &[u8],
+        start_addr: usize,
+        end_addr: usize,
+    ) -> Result<(), ProgrammerError> {
+        let sector_size = self.part()?.sector_size;
+        let range_size = end_addr - start_addr;
+        let verify_progress = ProgressBar::new(range_size as u64);
+        verify_progress.set_style(bytes_progress_style());
+        verify_progress.set_message("Verifying checksums");
+
+        let start = Instant::now();
+        let mut mismatched = Vec::new();
+        let mut addr = start_addr;
+        while addr < end_addr {
+            self.check_cancelled().inspect_err(|_| {
+                verify_progress.abandon_with_message("Cancelled");
+            })?;
+            // Stop at the sector boundary so a mismatch reads back at most one sector
+            let end = ((addr / sector_size + 1) * sector_size).min(end_addr);
+            let expected = crc32(&firmware[addr..end]);
+            let actual = self
+                .flash_checksum(addr as u32, end - addr)
+                .inspect_err(|_| {
+                    verify_progress.abandon_with_message("Verify failed");
+                })?;
+            match actual {
+                Some(actual) if actual != expected => mismatched.push(addr..end),
+                Some(_) => {}
+                None => {
+                    verify_progress.abandon_with_message("Checksums not supported");
+                    eprintln!("Programmer cannot compute checksums, reading back instead");
+                    return self.verify_flash_read(firmware, start_addr, end_addr);
+                }
+            }
+            verify_progress.set_position((end - start_addr) as u64);
+            addr = end;
+        }
+
+        for sector in mismatched {
+            let actual = self.read_flash_chunks(sector.start, sector.end)?;
+            let expected = &firmware[sector.clone()];
+            if let Some(range) = mismatched_ranges(expected, &actual).first() {
+                verify_progress.abandon_with_message("Verify failed");
+                let addr = sector.start + range.start;
+                eprintln!("Verification failed at address {:#x}", addr);
+                eprintln!("Expected: {:02x?}", &expected[range.clone()]);
+                eprintln!("Actual:   {:02x?}", &actual[range.clone()]);
+                return Err(ProgrammerError::VerificationFailed(addr as u32));
+            }
+        }
+        let elapsed = start.elapsed();
+        verify_progress.finish_with_message(format!("Verify complete in {:.2?}", elapsed));
+
+        Ok(())
+    }
+
+    /// Read the custom fields and code options stored on the target and print them
+    fn read_custom_fields(&mut self) -> Result<CustomFields, ProgrammerError> {
+        let part = self.part()?;
+        let region = part.options_region();
+        let customer_id_addr = part.customer_id.address;
+
+        // Read the whole custom fields region in one transaction
+        let buffer = self.read_region(region, customer_id_addr, CUSTOM_FIELDS_REGION_SIZE)?;
+
+        // Read upper code option bytes from 0x1100 in separate transaction if needed
+        let upper_options = if part.option_byte_count > 4 {
+            let second_part_size = part.option_byte_count - 4;
+            self.read_region(region, UPPER_CODE_OPTIONS_ADDRESS, second_part_size)?
+        } else {
+            Vec::new()
+        };
+
+        let fields = CustomFields::from_region(part, &buffer, &upper_options);
+        fields.print();
+
+        // Parse and display options in user-friendly format
+        if let Some(code_options) = &fields.customer_option {
+            let options_metadata = (part.options)();
+            let parsed = parse_code_options(code_options, &options_metadata);
+            eprintln!("Code Options (parsed):\n{}", format_parsed_options(&parsed));
+        }
+
+        Ok(fields)
+    }
+
+    /// Write all custom fields in one transaction (from customer_id to +0x40).
+    /// Fields that are `None` are left as zeros.
+    fn write_custom_fields(&mut self, fields: &CustomFields) -> Result<(), ProgrammerError> {
+        let part = self.part()?;
+        let customer_id_addr = part.customer_id.address;
+        let (buffer, customer_option_upper) = fields.to_region(part);
+
+        eprintln!(
+            "Writing custom fields region ({} bytes) at {:#x}...",
+            buffer.len(),
+            customer_id_addr
+        );
+        self.write_custom_region(customer_id_addr, &buffer)?;
+
+        // Write upper part of customer_option to 0x1100 if present
+        if let Some(upper) = customer_option_upper {
+            eprintln!(
+                "Writing customer option upper ({} bytes) at {:#x}...",
+                upper.len(),
+                UPPER_CODE_OPTIONS_ADDRESS
+            );
+            self.write_custom_region(UPPER_CODE_OPTIONS_ADDRESS, &upper)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_customer_id(&mut self, data: &[u8; 4]) -> Result<(), ProgrammerError> {
+        let field = &self.part()?.customer_id;
+        eprintln!("Writing customer ID at {:#x}...", field.address);
+        self.write_custom_region(field.address, data)
+    }
+
+    fn write_operation_number(&mut self, data: &[u8; 2]) -> Result<(), ProgrammerError> {
+        let field = &self.part()?.operation_number;
+        eprintln!("Writing operation number at {:#x}...", field.address);
+        self.write_custom_region(field.address, data)
+    }
+
+    fn write_customer_option(&mut self, data: &[u8]) -> Result<(), ProgrammerError> {
+        let part = self.part()?;
+        validate_customer_option(part, data)?;
+
+        let field = &part.customer_option;
+        // Split write: first 4 bytes to customer_option.address, rest to 0x1100
+        // Write second region first, then the first region
+        let first_part_size = 4.min(data.len());
+        let second_part_size = data.len().saturating_sub(4);
+
+        if second_part_size > 0 {
+            eprintln!(
+                "Writing customer option ({} bytes) at {:#x}...",
+                second_part_size, UPPER_CODE_OPTIONS_ADDRESS
+            );
+            self.write_custom_region(UPPER_CODE_OPTIONS_ADDRESS, &data[first_part_size..])?;
+        }
+
+        eprintln!(
+            "Writing customer option ({} bytes) at {:#x}...",
+            first_part_size, field.address
+        );
+        self.write_custom_region(field.address, &data[..first_part_size])
+    }
+
+    fn write_security(&mut self, data: &[u8]) -> Result<(), ProgrammerError> {
+        let part = self.part()?;
+        // Only security_level 4 and chip_type 0x07 are supported for writing security
+        if part.security_level != 4 || part.chip_type != 0x07 {
+            return Err(ProgrammerError::UnsupportedSecurityWrite {
+                security_level: part.security_level,
+                chip_type: part.chip_type,
+            });
+        }
+
+        let field = &part.security;
+        eprintln!("Writing security at {:#x}...", field.address);
+        self.write_custom_region(field.address, data)
+    }
+
+    fn write_serial_number(&mut self, data: &[u8; 4]) -> Result<(), ProgrammerError> {
+        let field = &self.part()?.serial_number;
+        eprintln!("Writing serial number at {:#x}...", field.address);
+        self.write_custom_region(field.address, data)
+    }
+}
+
+/// CRC-32 (IEEE 802.3, reflected poly 0xEDB88320) as computed by the programmer
+/// firmware for on-device verification
+pub fn crc32(data: &[u8]) -> u32 {
+    !sinodude_protocol::crc32_update(0xFFFF_FFFF, data)
+}
+
+/// Find the address ranges (relative to the start of the slices) where `actual`
+/// differs from `expected`. Bytes beyond the shorter slice count as mismatches.
+pub fn mismatched_ranges(expected: &[u8], actual: &[u8]) -> Vec<Range<usize>> {
+    let len = expected.len().max(actual.len());
+    ranges_where(len, |i| expected.get(i) != actual.get(i))
+}
+
+/// Find the address ranges (relative to the start of `data`) that are not erased
+pub fn non_blank_ranges(data: &[u8]) -> Vec<Range<usize>> {
+    ranges_where(data.len(), |i| data[i] != ERASED_BYTE)
+}
+
+/// Merge the indices in `0..len` matching `pred` into contiguous ranges
+fn ranges_where(len: usize, pred: impl Fn(usize) -> bool) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for i in 0..len {
+        if !pred(i) {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.end == i => last.end = i + 1,
+            _ => ranges.push(i..i + 1),
+        }
+    }
+    ranges
+}
+
+/// Print the non-blank ranges found by a blank check
+pub fn print_non_blank(ranges: &[Range<usize>]) {
+    let total: usize = ranges.iter().map(|r| r.len()).sum();
+    eprintln!(
+        "Flash not blank: {} byte(s) in {} range(s)",
+        total,
+        ranges.len()
+    );
+    for range in ranges {
+        eprintln!(
+            "  {:#06x}-{:#06x} ({} bytes)",
+            range.start,
+            range.end,
+            range.len()
+        );
+    }
+}
+
+/// Fail with [`ProgrammerError::EraseVerificationFailed`] if any range is not blank
+pub fn report_non_blank(ranges: &[Range<usize>]) -> Result<(), ProgrammerError> {
+    let Some(first) = ranges.first() else {
+        return Ok(());
+    };
+    print_non_blank(ranges);
+    Err(ProgrammerError::EraseVerificationFailed {
+        addr: first.start as u32,
+        len: ranges.iter().map(|r| r.len()).sum(),
+        count: ranges.len(),
+    })
+}
+
+fn check_blank(addr: usize, contents: &[u8]) -> Result<(), ProgrammerError> {
+    let ranges: Vec<_> = non_blank_ranges(contents)
+        .into_iter()
+        .map(|r| addr + r.start..addr + r.end)
+        .collect();
+    report_non_blank(&ranges)
+}
+
+/// Check that `start..end` is a non-empty range within the flash of `part`
+pub fn check_flash_range(part: &Part, start: usize, end: usize) -> Result<(), ProgrammerError> {
+    if start < end && end <= part.flash_size {
+        Ok(())
+    } else {
+        Err(ProgrammerError::InvalidRange {
+            start,
+            end,
+            flash_size: part.flash_size,
+        })
+    }
+}
+
+/// Check that a debug memory access stays within `space`, and that writes target
+/// writable memory
+pub fn check_memory_access(
+    space: MemorySpace,
+    addr: u16,
+    len: usize,
+    write: bool,
+) -> Result<(), ProgrammerError> {
+    if write && !space.is_writable() {
+        return Err(ProgrammerError::ReadOnlyMemory(space));
+    }
+    if !space.contains(addr, len) {
+        return Err(ProgrammerError::MemoryOutOfRange { space, addr, len });
+    }
+    Ok(())
+}
+
+pub fn check_breakpoint_index(index: usize) -> Result<(), ProgrammerError> {
+    if index < BREAKPOINT_COUNT {
+        Ok(())
+    } else {
+        Err(ProgrammerError::InvalidBreakpoint(index))
+    }
+}
+
+/// Validate customer option length and that non-editable bits match the part defaults
+pub fn validate_customer_option(part: &Part, data: &[u8]) -> Result<(), ProgrammerError> {
+    if data.len() > part.option_byte_count {
+        return Err(ProgrammerError::CustomerOptionLengthExceeded {
+            provided: data.len(),
+            max: part.option_byte_count,
+        });
+    }
+
+    let mask = part.code_option_mask;
+    let defaults = part.default_code_options;
+    for (i, &byte) in data.iter().enumerate() {
+        if i < mask.len() && i < defaults.len() {
+            // Non-editable bits are where mask is 0
+            // Check that (provided & ~mask) == (default & ~mask)
+            let non_editable_mask = !mask[i];
+            let provided_non_editable = byte & non_editable_mask;
+            let default_non_editable = defaults[i] & non_editable_mask;
+            if provided_non_editable != default_non_editable {
+                return Err(ProgrammerError::NonEditableBitsModified {
+                    byte: i,
+                    provided: byte,
+                    expected: (byte & mask[i]) | default_non_editable,
+                    mask: mask[i],
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(crc32(&[]), 0);
+}
+
+#[test]
+fn test_mismatched_ranges() {
+    let expected = [0, 1, 2, 3, 4, 5, 6, 7];
+    let actual = [0, 9, 9, 3, 4, 9, 6];
+    assert_eq!(
+        mismatched_ranges(&expected, &actual),
+        vec![1..3, 5..6, 7..8]
+    );
+    assert!(mismatched_ranges(&expected, &expected).is_empty());
+}
+
+#[test]
+fn test_non_blank_ranges() {
+    let data = [ERASED_BYTE, 0xff, 0x01, ERASED_BYTE, 0x80, ERASED_BYTE];
+    assert_eq!(non_blank_ranges(&data), vec![1..3, 4..5]);
+    assert!(non_blank_ranges(&[ERASED_BYTE; 4]).is_empty());
+
+    assert!(check_blank(0x100, &[ERASED_BYTE; 4]).is_ok());
+    assert!(matches!(
+        check_blank(0x100, &data),
+        Err(ProgrammerError::EraseVerificationFailed {
+            addr: 0x101,
+            len: 3,
+            count: 2
+        })
+    ));
+}
+
+/// Backend with only the required primitives, none of which are called
+#[cfg(test)]
+struct FlashOnlyProgrammer;
+
+#[cfg(test)]
+impl Programmer for FlashOnlyProgrammer {
+    fn selected_part(&self) -> Option<&'static Part> {
+        None
+    }
+    fn set_part(&mut self, _part: &'static Part) -> Result<(), ProgrammerError> {
+        unimplemented!()
+    }
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+    fn connect_target(&mut self) -> Result<(), ProgrammerError> {
+        unimplemented!()
+    }
+    fn read_jtag_id(&mut self) -> Result<u16, ProgrammerError> {
+        unimplemented!()
+    }
+    fn read_part_number(&mut self, _: u8, _: u8) -> Result<Option<[u8; 5]>, ProgrammerError> {
+        unimplemented!()
+    }
+    fn read_chunk(&mut self, _addr: u32, _length: u16) -> Result<Vec<u8>, ProgrammerError> {
+        unimplemented!()
+    }
+    fn write_chunk(&mut self, _addr: u32, _data: &[u8]) -> Result<(), ProgrammerError> {
+        unimplemented!()
+    }
+    fn erase_sector(&mut self, _addr: u32) -> Result<(), ProgrammerError> {
+        unimplemented!()
+    }
+    fn mass_erase(&mut self, _alternate: bool) -> Result<(), ProgrammerError> {
+        unimplemented!()
+    }
+    fn read_region(&mut self, _: Region, _: u32, _: usize) -> Result<Vec<u8>, ProgrammerError> {
+        unimplemented!()
+    }
+    fn write_custom_region(&mut self, _addr: u32, _data: &[u8]) -> Result<(), ProgrammerError> {
+        unimplemented!()
+    }
+    fn finish(&mut self) -> Result<(), ProgrammerError> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_optional_primitives_unsupported() {
+    let mut programmer = FlashOnlyProgrammer;
+    assert!(matches!(
+        programmer.halt(),
+        Err(ProgrammerError::Unsupported("on-chip debugging"))
+    ));
+    assert!(matches!(
+        programmer.cycle_power(Duration::ZERO),
+        Err(ProgrammerError::Unsupported("power control"))
+    ));
+    assert!(matches!(
+        programmer.read_flash(),
+        Err(ProgrammerError::NoPartSelected)
+    ));
+}
@@ -0,0 +1,846 @@
+use super::super::parts::{Part, Region, PARTS};
+use super::{
+    check_breakpoint_index, check_memory_access, crc32, part_number_block_address, CoreStatus,
+    MemorySpace, Programmer, ProgrammerConfig, ProgrammerError, BREAKPOINT_COUNT, ERASED_BYTE,
+    UPPER_CODE_OPTIONS_ADDRESS,
+};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Size of the simulated custom region address space
+pub const CUSTOM_REGION_SIZE: usize = 0x4000;
+
+#[derive(Debug, Error)]
+pub enum SimProgrammerError {
+    #[error("Simulator state I/O error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Simulator state file has {actual} bytes, expected {expected} for this part")]
+    InvalidStateSize { expected: usize, actual: usize },
+    #[error("Simulated target is not connected")]
+    NotConnected,
+    #[error("Access of {len} bytes at {addr:#x} is outside the simulated {region:?} region")]
+    OutOfRange {
+        region: Region,
+        addr: u32,
+        len: usize,
+    },
+    #[error("--sim_part is required for the sim programmer when the part is auto-detected")]
+    SimPartRequired,
+    #[error("Unknown simulated part: {0}")]
+    UnknownSimPart(String),
+    #[error("Mass erase failed: code option byte {byte} has non-default non-editable bits, alternate erase required")]
+    AlternateEraseRequired { byte: usize },
+    #[error("Simulated core is not halted")]
+    NotHalted,
+}
+
+/// Debug view of the simulated 8051 core. It never executes code, so it only changes
+/// through the debug commands; like real RAM it is not saved with the target state.
+/// A step advances the PC by one byte, and resuming with breakpoints set stops at the
+/// next one in address order, as if the code had run up to it.
+pub struct SimCore {
+    pub halted: bool,
+    pub pc: u16,
+    pub breakpoints: [Option<u16>; BREAKPOINT_COUNT],
+    /// Breakpoint that stopped the core
+    pub hit: Option<u8>,
+    pub iram: [u8; 0x100],
+    /// SFRs 0x80-0xFF
+    pub sfr: [u8; 0x80],
+    pub xdata: Vec<u8>,
+}
+
+impl SimCore {
+    /// Like the firmware, everything but halt, resume and status needs a halted core
+    pub fn check_halted(&self) -> Result<(), SimProgrammerError> {
+        if self.halted {
+            Ok(())
+        } else {
+            Err(SimProgrammerError::NotHalted)
+        }
+    }
+
+    pub fn resume(&mut self) {
+        self.hit = None;
+        let next = self
+            .breakpoints
+            .iter()
+            .enumerate()
+            .filter_map(|(i, addr)| addr.map(|addr| (addr, i)))
+            .min_by_key(|&(addr, _)| (addr <= self.pc, addr));
+        match next {
+            Some((addr, index)) => {
+                self.pc = addr;
+                self.hit = Some(index as u8);
+            }
+            None => self.halted = false,
+        }
+    }
+
+    pub fn step(&mut self) -> Result<u16, SimProgrammerError> {
+        self.check_halted()?;
+        self.hit = None;
+        self.pc = self.pc.wrapping_add(1);
+        Ok(self.pc)
+    }
+
+    pub fn status(&self) -> CoreStatus {
+        CoreStatus {
+            halted: self.halted,
+            pc: if self.halted { self.pc } else { 0 },
+            breakpoint: self.hit,
+        }
+    }
+}
+
+impl Default for SimCore {
+    fn default() -> Self {
+        Self {
+            halted: false,
+            pc: 0,
+            breakpoints: [None; BREAKPOINT_COUNT],
+            hit: None,
+            iram: [0; 0x100],
+            sfr: [0; 0x80],
+            xdata: vec![0; 0x1_0000],
+        }
+    }
+}
+
+/// In-memory model of a single target part
+pub struct SimTarget {
+    pub part: &'static Part,
+    pub flash: Vec<u8>,
+    pub custom: Vec<u8>,
+    pub core: SimCore,
+    /// Flash addresses of worn cells that keep their programmed bits through erases
+    pub stuck: Vec<u32>,
+}
+
+impl SimTarget {
+    /// A freshly erased part with the factory part number and default code options
+    pub fn new(part: &'static Part) -> Self {
+        let mut target = Self {
+            part,
+            flash: vec![ERASED_BYTE; part.flash_size],
+            custom: vec![ERASED_BYTE; CUSTOM_REGION_SIZE],
+            core: SimCore::default(),
+            stuck: Vec::new(),
+        };
+
+        if let Some(addr) = part_number_block_address(part.custom_block) {
+            let addr = addr as usize;
+            target.custom[addr + 9..addr + 14].copy_from_slice(&part.part_number);
+        }
+
+        let defaults = part.default_code_options;
+        let first_part_size = 4.min(defaults.len());
+        let region = part.options_region();
+        let _ = target.write(
+            region,
+            part.customer_option.address,
+            &defaults[..first_part_size],
+        );
+        if defaults.len() > 4 {
+            let _ = target.write(region, UPPER_CODE_OPTIONS_ADDRESS, &defaults[4..]);
+        }
+
+        target
+    }
+
+    /// Load a target previously saved with [`SimTarget::save`]
+    pub fn load(part: &'static Part, path: &PathBuf) -> Result<Self, SimProgrammerError> {
+        let data = fs::read(path)?;
+        let expected = part.flash_size + CUSTOM_REGION_SIZE;
+        if data.len() != expected {
+            return Err(SimProgrammerError::InvalidStateSize {
+                expected,
+                actual: data.len(),
+            });
+        }
+        let (flash, custom) = data.split_at(part.flash_size);
+        Ok(Self {
+            part,
+            flash: flash.to_vec(),
+            custom: custom.to_vec(),
+            core: SimCore::default(),
+            stuck: Vec::new(),
+        })
+    }
+
+    /// Save the flash followed by the custom region as a raw image
+    pub fn save(&self, path: &PathBuf) -> Result<(), SimProgrammerError> {
+        let mut data = self.flash.clone();
+        data.extend_from_slice(&self.custom);
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    fn memory(&mut self, region: Region) -> &mut Vec<u8> {
+        match region {
+            Region::Flash => &mut self.flash,
+            Region::Custom => &mut self.custom,
+        }
+    }
+
+    fn range(
+        &mut self,
+        region: Region,
+        addr: u32,
+        len: usize,
+    ) -> Result<&mut [u8], SimProgrammerError> {
+        let memory = self.memory(region);
+        let start = addr as usize;
+        let end = start + len;
+        if end > memory.len() {
+            return Err(SimProgrammerError::OutOfRange { region, addr, len });
+        }
+        Ok(&mut memory[start..end])
+    }
+
+    pub fn read(
+        &mut self,
+        region: Region,
+        addr: u32,
+        len: usize,
+    ) -> Result<Vec<u8>, SimProgrammerError> {
+        Ok(self.range(region, addr, len)?.to_vec())
+    }
+
+    /// Overwrite bytes (custom region writes do not need a prior erase)
+    pub fn write(
+        &mut self,
+        region: Region,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<(), SimProgrammerError> {
+        self.range(region, addr, data.len())?.copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Program flash; like the real cell array this can only set bits, so
+    /// writing over data that was not erased first shows up as a verification failure
+    pub fn program(
+        &mut self,
+        region: Region,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<(), SimProgrammerError> {
+        let range = self.range(region, addr, data.len())?;
+        for (cell, byte) in range.iter_mut().zip(data) {
+            *cell |= byte;
+        }
+        Ok(())
+    }
+
+    pub fn erase_sector(&mut self, addr: u32) -> Result<(), SimProgrammerError> {
+        let sector_size = self.part.sector_size;
+        let start = (addr as usize / sector_size * sector_size) as u32;
+        self.erase(Region::Flash, start, sector_size)
+    }
+
+    /// Erase a range, leaving the stuck flash cells in it programmed
+    fn erase(&mut self, region: Region, start: u32, len: usize) -> Result<(), SimProgrammerError> {
+        let stuck: Vec<(u32, u8)> = match region {
+            Region::Flash => self
+                .stuck
+                .iter()
+                .filter(|&&addr| (start..start + len as u32).contains(&addr))
+                .map(|&addr| (addr, self.flash[addr as usize]))
+                .collect(),
+            _ => Vec::new(),
+        };
+        self.range(region, start, len)?.fill(ERASED_BYTE);
+        for (addr, byte) in stuck {
+            self.flash[addr as usize] = byte;
+        }
+        Ok(())
+    }
+
+    /// Normal mass erase only clears the flash and refuses to run when the upper code
+    /// options have non-default non-editable bits. The alternate erase additionally
+    /// clears the option bytes at 0x1100.
+    pub fn mass_erase(&mut self, alternate: bool) -> Result<(), SimProgrammerError> {
+        let part = self.part;
+        if let Some(expected_upper) = part.upper_code_option_defaults() {
+            let region = part.options_region();
+            let current = self.read(region, UPPER_CODE_OPTIONS_ADDRESS, expected_upper.len())?;
+            if alternate {
+                self.range(region, UPPER_CODE_OPTIONS_ADDRESS, expected_upper.len())?
+                    .fill(ERASED_BYTE);
+            } else {
+                let mask = part.code_option_mask;
+                for (i, (&byte, &expected)) in current.iter().zip(&expected_upper).enumerate() {
+                    let idx = 4 + i;
+                    if byte & !mask[idx] != expected {
+                        return Err(SimProgrammerError::AlternateEraseRequired { byte: idx });
+                    }
+                }
+            }
+        }
+
+        self.erase(Region::Flash, 0, part.flash_size)
+    }
+
+    /// Read memory of the halted core. Code beyond the flash reads as erased.
+    pub fn read_memory(
+        &self,
+        space: MemorySpace,
+        addr: u16,
+        len: usize,
+    ) -> Result<Vec<u8>, SimProgrammerError> {
+        self.core.check_halted()?;
+        let addr = addr as usize;
+        Ok(match space {
+            MemorySpace::Iram => self.core.iram[addr..addr + len].to_vec(),
+            MemorySpace::Sfr => self.core.sfr[addr - 0x80..addr - 0x80 + len].to_vec(),
+            MemorySpace::Xdata => self.core.xdata[addr..addr + len].to_vec(),
+            MemorySpace::Code => (addr..addr + len)
+                .map(|i| self.flash.get(i).copied().unwrap_or(ERASED_BYTE))
+                .collect(),
+        })
+    }
+
+    /// Write memory of the halted core. Code is not writable through the debugger.
+    pub fn write_memory(
+        &mut self,
+        space: MemorySpace,
+        addr: u16,
+        data: &[u8],
+    ) -> Result<(), SimProgrammerError> {
+        self.core.check_halted()?;
+        let addr = addr as usize;
+        let memory = match space {
+            MemorySpace::Iram => &mut self.core.iram[addr..],
+            MemorySpace::Sfr => &mut self.core.sfr[addr - 0x80..],
+            MemorySpace::Xdata => &mut self.core.xdata[addr..],
+            MemorySpace::Code => unreachable!("code memory is read only"),
+        };
+        memory[..data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Programmer backed by an in-memory [`SimTarget`], optionally persisted to a file
+pub struct SimProgrammer {
+    chip_type: Option<&'static Part>,
+    target: SimTarget,
+    state_file: Option<PathBuf>,
+    connected: bool,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl SimProgrammer {
+    /// Registry constructor for the simulated programmer
+    pub fn open(
+        config: &ProgrammerConfig,
+        part: Option<&'static Part>,
+        cancelled: Arc<AtomicBool>,
+    ) -> Result<Box<dyn Programmer>, ProgrammerError> {
+        let simulated = match &config.sim_part {
+            Some(name) => PARTS
+                .get(name.as_str())
+                .copied()
+                .ok_or_else(|| SimProgrammerError::UnknownSimPart(name.clone()))?,
+            None => part.ok_or(SimProgrammerError::SimPartRequired)?,
+        };
+        Ok(Box::new(Self::new(
+            part,
+            simulated,
+            config.sim_state.clone(),
+            cancelled,
+        )?))
+    }
+
+    /// Simulate a `simulated` part, talking to it as `chip_type` (None until detected)
+    pub fn new(
+        chip_type: Option<&'static Part>,
+        simulated: &'static Part,
+        state_file: Option<PathBuf>,
+        cancelled: Arc<AtomicBool>,
+    ) -> Result<Self, SimProgrammerError> {
+        let target = match &state_file {
+            Some(path) if path.exists() => {
+                eprintln!("Loading simulator state from {}", path.display());
+                SimTarget::load(simulated, path)?
+            }
+            _ => SimTarget::new(simulated),
+        };
+
+        Ok(Self {
+            chip_type,
+            target,
+            state_file,
+            connected: false,
+            cancelled,
+        })
+    }
+
+    fn check_connected(&self) -> Result<(), SimProgrammerError> {
+        if self.connected {
+            Ok(())
+        } else {
+            Err(SimProgrammerError::NotConnected)
+        }
+    }
+}
+
+impl Programmer for SimProgrammer {
+    fn selected_part(&self) -> Option<&'static Part> {
+        self.chip_type
+    }
+
+    fn set_part(&mut self, part: &'static Part) -> Result<(), ProgrammerError> {
+        self.chip_type = Some(part);
+        Ok(())
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn connect_target(&mut self) -> Result<(), ProgrammerError> {
+        if !self.connected {
+            eprintln!("Connecting to simulated target...");
+            self.connected = true;
+        }
+        Ok(())
+    }
+
+    fn read_jtag_id(&mut self) -> Result<u16, ProgrammerError> {
+        self.check_connected()?;
+        Ok(self.target.part.jtag_id)
+    }
+
+    /// The chip type only matters to the real ICP routines; the simulated target answers
+    /// with whatever is stored at the block for `custom_block`
+    fn read_part_number(
+        &mut self,
+        _chip_type: u8,
+        custom_block: u8,
+    ) -> Result<Option<[u8; 5]>, ProgrammerError> {
+        self.check_connected()?;
+        let Some(addr) = part_number_block_address(custom_block) else {
+            return Ok(None);
+        };
+        let data = self.target.read(Region::Custom, addr, 16)?;
+        Ok(Some(data[9..14].try_into().unwrap()))
+    }
+
+    fn read_chunk(&mut self, addr: u32, length: u16) -> Result<Vec<u8>, ProgrammerError> {
+        self.check_connected()?;
+        Ok(self.target.read(Region::Flash, addr, length as usize)?)
+    }
+
+    fn flash_checksum(&mut self, addr: u32, len: usize) -> Result<Option<u32>, ProgrammerError> {
+        self.check_connected()?;
+        Ok(Some(crc32(&self.target.read(Region::Flash, addr, len)?)))
+    }
+
+    fn write_chunk(&mut self, addr: u32, data: &[u8]) -> Result<(), ProgrammerError> {
+        self.check_connected()?;
+        Ok(self.target.program(Region::Flash, addr, data)?)
+    }
+
+    fn erase_sector(&mut self, addr: u32) -> Result<(), ProgrammerError> {
+        self.check_connected()?;
+        Ok(self.target.erase_sector(addr)?)
+    }
+
+    fn mass_erase(&mut self, alternate: bool) -> Result<(), ProgrammerError> {
+        self.check_connected()?;
+        Ok(self.target.mass_erase(alternate)?)
+    }
+
+    fn read_region(
+        &mut self,
+        region: Region,
+        address: u32,
+        size: usize,
+    ) -> Result<Vec<u8>, ProgrammerError> {
+        self.check_connected()?;
+        Ok(self.target.read(region, address, size)?)
+    }
+
+    fn write_custom_region(&mut self, addr: u32, data: &[u8]) -> Result<(), ProgrammerError> {
+        self.check_connected()?;
+        let region = self.part()?.options_region();
+        Ok(self.target.write(region, addr, data)?)
+    }
+
+    fn halt(&mut self) -> Result<u16, ProgrammerError> {
+        self.check_connected()?;
+        self.target.core.halted = true;
+        Ok(self.target.core.pc)
+    }
+
+    fn resume(&mut self) -> Result<(), ProgrammerError> {
+        self.check_connected()?;
+        self.target.core.resume();
+        Ok(())
+    }
+
+    fn read_memory(
+        &mut self,
+        space: MemorySpace,
+        addr: u16,
+        len: usize,
+    ) -> Result<Vec<u8>, ProgrammerError> {
+        self.check_connected()?;
+        check_memory_access(space, addr, len, false)?;
+        Ok(self.target.read_memory(space, addr, len)?)
+    }
+
+    fn write_memory(
+        &mut self,
+        space: MemorySpace,
+        addr: u16,
+        data: &[u8],
+    ) -> Result<(), ProgrammerError> {
+        self.check_connected()?;
+        check_memory_access(space, addr, data.len(), true)?;
+        Ok(self.target.write_memory(space, addr, data)?)
+    }
+
+    fn step(&mut self) -> Result<u16, ProgrammerError> {
+        self.check_connected()?;
+        Ok(self.target.core.step()?)
+    }
+
+    fn core_status(&mut self) -> Result<CoreStatus, ProgrammerError> {
+        self.check_connected()?;
+        Ok(self.target.core.status())
+    }
+
+    fn set_pc(&mut self, pc: u16) -> Result<(), ProgrammerError> {
+        self.check_connected()?;
+        self.target.core.check_halted()?;
+        self.target.core.pc = pc;
+        Ok(())
+    }
+
+    fn set_breakpoint(&mut self, index: usize, addr: u16) -> Result<(), ProgrammerError> {
+        self.check_connected()?;
+        check_breakpoint_index(index)?;
+        self.target.core.check_halted()?;
+        self.target.core.breakpoints[index] = Some(addr);
+        Ok(())
+    }
+
+    fn clear_breakpoint(&mut self, index: usize) -> Result<(), ProgrammerError> {
+        self.check_connected()?;
+        check_breakpoint_index(index)?;
+        self.target.core.check_halted()?;
+        self.target.core.breakpoints[index] = None;
+        Ok(())
+    }
+
+    /// Like the firmware, both leave ICP and restart the core
+    fn power_on(&mut self) -> Result<(), ProgrammerError> {
+        eprintln!("Powering simulated target on");
+        self.connected = false;
+        self.target.core = SimCore::default();
+        Ok(())
+    }
+
+    fn power_off(&mut self) -> Result<(), ProgrammerError> {
+        eprintln!("Powering simulated target off");
+        self.connected = false;
+        self.target.core = SimCore::default();
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), ProgrammerError> {
+        self.connected = false;
+        if let Some(path) = &self.state_file {
+            eprintln!("Saving simulator state to {}", path.display());
+            self.target.save(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// A simulated `part`, connected and identified
+#[cfg(test)]
+pub fn sim_programmer(part: &'static Part) -> SimProgrammer {
+    let mut programmer =
+        SimProgrammer::new(Some(part), part, None, Arc::new(AtomicBool::new(false))).unwrap();
+    programmer.identify().unwrap();
+    programmer
+}
+
+#[test]
+fn test_sim_write_custom_fields_and_flash() {
+    let part = &super::super::parts::sh68f90::PART;
+    let mut programmer = sim_programmer(part);
+    let stored = programmer.read_custom_fields().unwrap();
+
+    let requested = super::CustomFields {
+        customer_id: Some([1, 2, 3, 4]),
+        serial_number: Some([5, 6, 7, 8]),
+        ..Default::default()
+    };
+    programmer.erase_chip(false, false).unwrap();
+    programmer
+        .write_custom_fields(&requested.or(&stored))
+        .unwrap();
+
+    let firmware: Vec<u8> = (0..part.flash_size).map(|i| i as u8).collect();
+    programmer
+        .write_flash(&firmware, super::VerifyMode::Read)
+        .unwrap();
+
+    assert_eq!(programmer.read_flash().unwrap(), firmware);
+    let fields = programmer.read_custom_fields().unwrap();
+    assert_eq!(fields.customer_id, Some([1, 2, 3, 4]));
+    assert_eq!(fields.serial_number, Some([5, 6, 7, 8]));
+    assert_eq!(fields.customer_option, stored.customer_option);
+}
+
+#[test]
+fn test_sim_write_without_erase_fails_verification() {
+    let part = &super::super::parts::sh68f90::PART;
+    let mut programmer = sim_programmer(part);
+    programmer
+        .write_flash_range(&[0x0f; 1024], 0, 1024, super::VerifyMode::Read)
+        .unwrap();
+
+    programmer.erase_sectors(0, 512, true).unwrap();
+    let result = programmer.write_flash_range(&[0xf0; 1024], 0, 1024, super::VerifyMode::Read);
+    assert!(matches!(
+        result,
+        Err(ProgrammerError::VerificationFailed(0))
+    ));
+}
+
+#[test]
+fn test_sim_checksum_verify_finds_failing_sector() {
+    let part = &super::super::parts::sh68f90::PART;
+    let mut programmer = sim_programmer(part);
+    programmer
+        .write_flash_range(&[0x0f; 1024], 0, 1024, super::VerifyMode::Checksum)
+        .unwrap();
+
+    // Only the first sector is erased, so the second one reads back 0xff
+    programmer.erase_sectors(0, 512, true).unwrap();
+    let result = programmer.write_flash_range(&[0xf0; 1024], 0, 1024, super::VerifyMode::Checksum);
+    assert!(matches!(
+        result,
+        Err(ProgrammerError::VerificationFailed(0x200))
+    ));
+}
+
+#[test]
+fn test_sim_alternate_erase_required() {
+    let part = &super::super::parts::sh68f90::PART;
+    let mut programmer = sim_programmer(part);
+
+    // Flip a non-editable bit in the upper code options
+    let mut upper = part.upper_code_option_defaults().unwrap();
+    let idx = (0..upper.len())
+        .find(|&i| part.code_option_mask[4 + i] != 0xff)
+        .unwrap();
+    upper[idx] ^= !part.code_option_mask[4 + idx];
+    programmer
+        .write_custom_region(UPPER_CODE_OPTIONS_ADDRESS, &upper)
+        .unwrap();
+
+    let stored = programmer.read_custom_fields().unwrap();
+    assert!(stored.needs_alternate_erase(part));
+    assert!(programmer.erase_chip(false, false).is_err());
+    programmer.erase_chip(true, false).unwrap();
+    assert!(!programmer
+        .read_custom_fields()
+        .unwrap()
+        .needs_alternate_erase(part));
+}
+
+#[test]
+fn test_sim_detect_part() {
+    let part = &super::super::parts::sh68f90::PART;
+    let mut programmer =
+        SimProgrammer::new(None, part, None, Arc::new(AtomicBool::new(false))).unwrap();
+    assert!(programmer.selected_part().is_none());
+
+    // Other parts share the JTAG ID, the part number narrows it down to one
+    assert_eq!(
+        programmer.select_detected_part().unwrap().part_number,
+        part.part_number
+    );
+    assert!(programmer.identify().is_ok());
+}
+
+#[test]
+fn test_sim_detect_ambiguous_part() {
+    let part = &super::super::parts::sh79f088::PART;
+    let mut programmer =
+        SimProgrammer::new(None, part, None, Arc::new(AtomicBool::new(false))).unwrap();
+
+    assert_eq!(
+        programmer.detect_parts().unwrap(),
+        vec!["sh79f088", "sh79f088b"]
+    );
+    assert!(matches!(
+        programmer.select_detected_part(),
+        Err(ProgrammerError::AmbiguousPart(_))
+    ));
+}
+
+#[test]
+fn test_sim_debug_memory() {
+    let part = &super::super::parts::sh68f90::PART;
+    let mut programmer = sim_programmer(part);
+    assert!(matches!(
+        programmer.read_memory(MemorySpace::Iram, 0, 1),
+        Err(ProgrammerError::Sim(SimProgrammerError::NotHalted))
+    ));
+
+    assert_eq!(programmer.halt().unwrap(), 0);
+    programmer
+        .write_memory(MemorySpace::Iram, 0x7E, &[1, 2, 3])
+        .unwrap();
+    programmer
+        .write_memory(MemorySpace::Sfr, 0xE0, &[0x5A])
+        .unwrap();
+    assert_eq!(
+        programmer.read_memory(MemorySpace::Iram, 0x7E, 3).unwrap(),
+        [1, 2, 3]
+    );
+    assert_eq!(
+        programmer.read_memory(MemorySpace::Sfr, 0xE0, 1).unwrap(),
+        [0x5A]
+    );
+    assert_eq!(
+        programmer.read_memory(MemorySpace::Code, 0, 4).unwrap(),
+        programmer.target.flash[..4]
+    );
+
+    assert!(matches!(
+        programmer.write_memory(MemorySpace::Code, 0, &[0]),
+        Err(ProgrammerError::ReadOnlyMemory(MemorySpace::Code))
+    ));
+    assert!(matches!(
+        programmer.read_memory(MemorySpace::Sfr, 0x70, 1),
+        Err(ProgrammerError::MemoryOutOfRange { .. })
+    ));
+
+    programmer.resume().unwrap();
+    assert!(programmer
+        .write_memory(MemorySpace::Xdata, 0, &[0])
+        .is_err());
+}
+
+#[test]
+fn test_sim_breakpoints_and_registers() {
+    let part = &super::super::parts::sh68f90a::PART;
+    let mut programmer = sim_programmer(part);
+    programmer.halt().unwrap();
+    programmer.set_breakpoint(2, 0x0300).unwrap();
+    programmer.set_breakpoint(5, 0x0100).unwrap();
+    assert!(matches!(
+        programmer.set_breakpoint(BREAKPOINT_COUNT, 0),
+        Err(ProgrammerError::InvalidBreakpoint(BREAKPOINT_COUNT))
+    ));
+
+    programmer.resume().unwrap();
+    let status = programmer.core_status().unwrap();
+    assert_eq!(
+        (status.halted, status.pc, status.breakpoint),
+        (true, 0x0100, Some(5))
+    );
+    assert_eq!(programmer.step().unwrap(), 0x0101);
+    programmer.clear_breakpoint(2).unwrap();
+    programmer.resume().unwrap();
+    assert_eq!(programmer.core_status().unwrap().breakpoint, Some(5));
+
+    // Bank 2 selected through PSW
+    programmer
+        .write_memory(MemorySpace::Sfr, 0xD0, &[0x10])
+        .unwrap();
+    programmer
+        .write_memory(MemorySpace::Iram, 0x10, &[7, 6])
+        .unwrap();
+    programmer
+        .write_memory(MemorySpace::Sfr, 0x82, &[0x34, 0x12])
+        .unwrap();
+    let registers = programmer.read_registers().unwrap();
+    assert_eq!(registers.r[..2], [7, 6]);
+    assert_eq!(
+        (registers.psw, registers.dptr, registers.pc),
+        (0x10, 0x1234, 0x0100)
+    );
+    assert!(registers.to_string().starts_with("PC=0x0100 A=0x00"));
+}
+
+#[test]
+fn test_sim_power_cycle() {
+    let part = &super::super::parts::sh68f90::PART;
+    let mut programmer = sim_programmer(part);
+    programmer.target.flash[0] = 0x12;
+    programmer.halt().unwrap();
+
+    // Restarting the target leaves ICP and lets the core run again
+    programmer.cycle_power(std::time::Duration::ZERO).unwrap();
+    assert!(!programmer.connected);
+    programmer.identify().unwrap();
+    assert_eq!(programmer.read_region(Region::Flash, 0, 1).unwrap(), [0x12]);
+    assert!(matches!(
+        programmer.read_memory(MemorySpace::Iram, 0, 1),
+        Err(ProgrammerError::Sim(SimProgrammerError::NotHalted))
+    ));
+}
+
+#[test]
+fn test_sim_blank_check() {
+    let part = &super::super::parts::sh68f90::PART;
+    let mut programmer = sim_programmer(part);
+    programmer.target.flash[0x0100..0x0104].fill(0xff);
+    programmer.target.flash[0x0300] = 0x80;
+    programmer.target.flash[0x0800] = 1;
+
+    // Ranges are absolute, and data outside the checked range is ignored
+    assert_eq!(
+        programmer.blank_check(0x0080, 0x0800).unwrap(),
+        vec![0x0100..0x0104, 0x0300..0x0301]
+    );
+    assert!(programmer.blank_check(0x0400, 0x0800).unwrap().is_empty());
+    assert!(matches!(
+        programmer.blank_check(0x1000, 0x0800),
+        Err(ProgrammerError::InvalidRange { .. })
+    ));
+}
+
+#[test]
+fn test_sim_verify_erase_finds_stuck_cell() {
+    let part = &super::super::parts::sh68f90::PART;
+    let mut programmer = sim_programmer(part);
+    let sector_size = part.sector_size as u32;
+    programmer
+        .write_flash_range(&[0x5a; 1024], 0, 1024, super::VerifyMode::Read)
+        .unwrap();
+    programmer.target.stuck.push(sector_size + 5);
+
+    assert!(matches!(
+        programmer.erase_sectors(sector_size, sector_size, false),
+        Err(ProgrammerError::InvalidRange { .. })
+    ));
+
+    // Without verification the partially erased sector goes unnoticed
+    programmer.erase_sectors(0, 2 * sector_size, false).unwrap();
+    assert!(matches!(
+        programmer.erase_sectors(0, 2 * sector_size, true),
+        Err(ProgrammerError::EraseVerificationFailed { addr, len: 1, count: 1 })
+            if addr == sector_size + 5
+    ));
+    assert!(matches!(
+        programmer.erase_chip(false, true),
+        Err(ProgrammerError::EraseVerificationFailed { addr, len: 1, count: 1 })
+            if addr == sector_size + 5
+    ));
+
+    programmer.target.stuck.clear();
+    programmer.erase_chip(false, true).unwrap();
+}
@@ -1,52 +1,97 @@
-use super::super::parts::{
-    find_parts_by_jtag_id, find_parts_by_part_number, format_parsed_options, parse_code_options,
-    Part, Region, Voltage,
+use super::super::parts::{Part, Region, Voltage};
+use super::{
+    check_breakpoint_index, check_memory_access, part_number_block_address, Programmer,
+    ProgrammerConfig, ProgrammerError,
 };
-use indicatif::{ProgressBar, ProgressStyle};
 use log::debug;
+use sinodude_protocol::{
+    frame, AddressLength, Breakpoint, Capabilities, Command, CoreStatus, Failure, FlashRange,
+    MemoryAccess, MemorySpace, Reason, Response, BAUD_CHECK_MS, BAUD_RATE, BAUD_RATES,
+    MAX_DATA_LEN,
+};
 use std::io::{Read, Write};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use thiserror::Error;
 
-// Expected firmware version (must match firmware)
-const EXPECTED_VERSION_MAJOR: u8 = 2;
-
-// Serial protocol commands (must match firmware)
-mod cmd {
-    // System commands
-    pub const CMD_PING: u8 = 0x01;
-    pub const CMD_GET_VERSION: u8 = 0x02;
-
-    // Connection
-    pub const CMD_CONNECT: u8 = 0x03;
-    pub const CMD_DISCONNECT: u8 = 0x04;
-
-    // Identification
-    pub const CMD_GET_ID: u8 = 0x05;
-
-    // Configuration
-    pub const CMD_SET_CONFIG: u8 = 0x06;
-    pub const CMD_GET_CONFIG: u8 = 0x07;
-
-    // Memory operations
-    pub const CMD_READ_FLASH: u8 = 0x08;
-    pub const CMD_WRITE_FLASH: u8 = 0x09;
-    pub const CMD_ERASE_FLASH_SECTOR: u8 = 0x0A;
-    pub const CMD_MASS_ERASE: u8 = 0x0B;
-    pub const CMD_READ_CUSTOM_REGION: u8 = 0x0C;
-    pub const CMD_WRITE_CUSTOM_REGION: u8 = 0x0D;
-
-    // Response codes
-    pub const RSP_OK: u8 = 0x00;
-    pub const RSP_ERR: u8 = 0xFF;
-    pub const RSP_DATA: u8 = 0x01;
+// Oldest firmware major version speaking the framed protocol
+const MIN_VERSION_MAJOR: u8 = 3;
+// Firmware major version of the unframed protocol, still usable with its original commands
+const LEGACY_VERSION_MAJOR: u8 = 2;
+
+// How long the firmware waits for a valid frame at a new rate before reverting to
+// BAUD_RATE
+const BAUD_CHECK_WINDOW: Duration = Duration::from_millis(BAUD_CHECK_MS as u64);
+// Response timeout while checking a new rate, short enough that every retransmission
+// fits in BAUD_CHECK_WINDOW
+const BAUD_CHECK_TIMEOUT: Duration = Duration::from_millis(100);
+const TIMEOUT: Duration = Duration::from_secs(5);
+// Response timeout for a mass erase, longer than the firmware's own erase timeout (~10s)
+const MASS_ERASE_TIMEOUT: Duration = Duration::from_secs(15);
+// How long the board may take to answer the ping after opening the port, including a
+// reset into the bootloader
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a discovered port may take to answer the ping before it is opened again
+// with a reset, enough for a few pings to a programmer that is already running
+const PROBE_TIMEOUT: Duration = Duration::from_millis(300);
+// Response timeout of each ping while waiting for the board
+const READY_POLL_TIMEOUT: Duration = Duration::from_millis(100);
+// Length of each half of the reset pulse on DTR or RTS
+const RESET_PULSE: Duration = Duration::from_millis(50);
+// Time the target stays unpowered when power-cycling it
+const POWER_CYCLE_OFF_TIME: Duration = Duration::from_millis(100);
+// Quiet time that marks the end of an aborted stream
+const DRAIN_QUIET: Duration = Duration::from_millis(500);
+// Any byte other than frame::SYNC sent during a stream stops it
+const STREAM_ABORT: u8 = 0x00;
+// Retransmissions of a request before giving up
+const MAX_RETRIES: usize = 3;
+
+/// Values accepted by `--reset`
+pub const RESET_MODES: [&str; 3] = ["dtr", "rts", "none"];
+
+/// How the programmer board is reset after opening its port
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResetMode {
+    /// Pulse DTR, which the Nano's auto-reset circuit turns into a reset
+    #[default]
+    Dtr,
+    /// Pulse RTS, for adapters that wire RTS to the reset circuit
+    Rts,
+    /// Leave DTR and RTS deasserted, for boards with auto-reset disabled
+    NoReset,
 }
 
-const CHUNK_SIZE: usize = 1024;
-const BAUD_RATE: u32 = 115200;
-const TIMEOUT: Duration = Duration::from_secs(5);
+impl ResetMode {
+    pub fn from_arg(mode: &str) -> Result<Self, SinodudeSerialProgrammerError> {
+        match mode {
+            "dtr" => Ok(ResetMode::Dtr),
+            "rts" => Ok(ResetMode::Rts),
+            "none" => Ok(ResetMode::NoReset),
+            _ => Err(SinodudeSerialProgrammerError::UnknownResetMode(
+                mode.to_string(),
+            )),
+        }
+    }
+}
+
+/// USB serial bridges (VID, PID, name) found on Arduino Nano boards and clones
+const USB_BRIDGES: &[(u16, u16, &str)] = &[
+    (0x1a86, 0x7523, "CH340"),
+    (0x1a86, 0x5523, "CH341"),
+    (0x0403, 0x6001, "FT232R"),
+    (0x2341, 0x0043, "ATmega16U2"),
+    (0x2341, 0x0001, "ATmega16U2"),
+    (0x2a03, 0x0043, "ATmega16U2"),
+];
+
+/// Candidate port, its USB bridge and the result of opening it
+type OpenedCandidate = (
+    String,
+    &'static str,
+    Result<SinodudeSerialProgrammer, SinodudeSerialProgrammerError>,
+);
 
 #[derive(Debug, Error)]
 pub enum SinodudeSerialProgrammerError {
@@ -58,73 +103,325 @@ pub enum SinodudeSerialProgrammerError {
     NoResponse,
     #[error("Invalid response from device")]
     InvalidResponse,
-    #[error("Connection failed")]
+    #[error("Response frame CRC mismatch: expected {expected:#06x}, got {actual:#06x}")]
+    CrcMismatch { expected: u16, actual: u16 },
+    #[error("Response frame payload of {0} bytes exceeds the maximum")]
+    FrameTooLong(usize),
+    #[error("No valid response after {0} retransmissions")]
+    TooManyRetries(usize),
+    #[error("Programmer rejected a corrupted request frame")]
+    FrameRejected,
+    #[error("Flash stream skipped data: expected address {expected:#x}, got {actual:#x}")]
+    StreamGap { expected: u32, actual: u32 },
+    #[error("Flash stream truncated: received {received} of {expected} bytes")]
+    StreamTruncated { expected: usize, received: usize },
+    #[error("Connection failed (target did not answer in ICP mode, check wiring and power)")]
     ConnectionFailed,
     #[error("Operation failed")]
     OperationFailed,
-    #[error("Erase failed at address {0:#x}")]
-    EraseFailed(u32),
-    #[error("Mass erase failed")]
-    MassEraseFailed,
-    #[error("Custom region write failed at address {0:#x}")]
-    CustomRegionWriteFailed(u32),
-    #[error("Write failed at address {0:#x}")]
-    WriteFailed(u32),
-    #[error("Verification failed at address {0:#x}")]
-    VerificationFailed(u32),
-    #[error("Firmware version mismatch: expected major version {expected}, got {actual}")]
-    VersionMismatch { expected: u8, actual: u8 },
-    #[error("JTAG ID mismatch: expected {expected:#06x}, got {actual:#06x}")]
-    JtagIdMismatch { expected: u16, actual: u16 },
-    #[error("Part number mismatch: expected {expected}, got {actual}")]
-    PartNumberMismatch { expected: String, actual: String },
-    #[error("Operation cancelled")]
-    Cancelled,
-    #[error("Customer option length {provided} exceeds maximum {max}")]
-    CustomerOptionLengthExceeded { provided: usize, max: usize },
-    #[error("Non-editable bits modified at byte {byte}: provided {provided:#04x}, expected {expected:#04x} (mask {mask:#04x})")]
-    NonEditableBitsModified {
-        byte: usize,
-        provided: u8,
-        expected: u8,
-        mask: u8,
+    #[error("Programmer has no chip type configured")]
+    NoChipType,
+    #[error("Target did not acknowledge data byte {0} (TDO stayed low), check wiring or whether the chip is locked")]
+    NoAck(u16),
+    #[error("Erase status stayed low, the chip may be locked")]
+    EraseStatusLow,
+    #[error("Requested length exceeds the programmer buffer of {0} bytes")]
+    LengthExceedsBuffer(u16),
+    #[error("Programmer does not support command {0:#04x}, update the firmware")]
+    UnknownCommand(u8),
+    #[error("Programmer rejected the parameters ({0} byte payload)")]
+    BadParameters(u16),
+    #[error("Stream aborted")]
+    StreamAborted,
+    #[error("Target core is not halted")]
+    NotHalted,
+    #[error("Programmer firmware does not support on-chip debugging")]
+    DebugNotSupported,
+    #[error("Programmer firmware does not support power control, update it")]
+    PowerControlNotSupported,
+    #[error("Unknown firmware error reason {reason:#04x} (detail {detail:#06x})")]
+    UnknownReason { reason: u8, detail: u16 },
+    #[error("Erase failed at address {addr:#x}: {reason}")]
+    EraseFailed {
+        addr: u32,
+        reason: Box<SinodudeSerialProgrammerError>,
+    },
+    #[error("Mass erase failed: {0}")]
+    MassEraseFailed(Box<SinodudeSerialProgrammerError>),
+    #[error("Mass erase timed out, the target was power-cycled (is it connected?)")]
+    MassEraseTimeout,
+    #[error("Target did not finish the operation in time")]
+    OperationTimedOut,
+    #[error("Custom region write failed at address {addr:#x}: {reason}")]
+    CustomRegionWriteFailed {
+        addr: u32,
+        reason: Box<SinodudeSerialProgrammerError>,
+    },
+    #[error("Write failed at address {addr:#x}: {reason}")]
+    WriteFailed {
+        addr: u32,
+        reason: Box<SinodudeSerialProgrammerError>,
     },
-    #[error("Writing security bits is only supported for security_level 4 and chip_type 0x07 (got security_level {security_level}, chip_type {chip_type:#04x})")]
-    UnsupportedSecurityWrite { security_level: u8, chip_type: u8 },
+    #[error("Firmware major version {actual} is not supported, update it to major version {expected} or newer")]
+    VersionMismatch { expected: u8, actual: u8 },
+    #[error("Firmware major version {actual} speaks the unframed protocol, which is only supported for major version {expected}, update it to major version {MIN_VERSION_MAJOR} or newer")]
+    LegacyVersionMismatch { expected: u8, actual: u8 },
+    #[error("Programmer firmware does not support chip type {0:#04x}")]
+    UnsupportedChipType(u8),
     #[error("Custom region verification failed at address {addr:#x}: expected {expected:02x?}, got {actual:02x?}")]
     CustomRegionVerificationFailed {
         addr: u32,
         expected: Vec<u8>,
         actual: Vec<u8>,
     },
+    #[error("Unknown reset mode: {0}")]
+    UnknownResetMode(String),
+    #[error("Programmer did not answer the ping within {0:?}")]
+    NotReady(Duration),
+    #[error("Failed to enumerate serial ports: {0}")]
+    PortEnumerationError(String),
+    #[error("No sinodude-serial programmer found, specify one with --port")]
+    NoProgrammerFound,
+    #[error("Multiple sinodude-serial programmers found ({}), select one with --port", .0.join(", "))]
+    MultipleProgrammersFound(Vec<String>),
     #[error("Part does not support 5.0V required by sinodude-serial programmer. Supported voltages: {supported}")]
     UnsupportedVoltage { supported: String },
 }
 
+impl SinodudeSerialProgrammerError {
+    /// Decode the reason code and detail the firmware sends after Response::Error. Firmware
+    /// without reason codes sends no payload.
+    fn from_firmware(payload: &[u8]) -> Self {
+        let Some(Failure { reason, detail }) = Failure::decode(payload) else {
+            return SinodudeSerialProgrammerError::OperationFailed;
+        };
+        match Reason::from_u8(reason) {
+            Some(Reason::NoChipType) => SinodudeSerialProgrammerError::NoChipType,
+            Some(Reason::NoAck) => SinodudeSerialProgrammerError::NoAck(detail),
+            Some(Reason::EraseStatus) => SinodudeSerialProgrammerError::EraseStatusLow,
+            Some(Reason::Length) => SinodudeSerialProgrammerError::LengthExceedsBuffer(detail),
+            Some(Reason::UnknownCommand) => {
+                SinodudeSerialProgrammerError::UnknownCommand(detail as u8)
+            }
+            Some(Reason::ConnectFailed) => SinodudeSerialProgrammerError::ConnectionFailed,
+            Some(Reason::BadParameters) => SinodudeSerialProgrammerError::BadParameters(detail),
+            Some(Reason::Timeout) => SinodudeSerialProgrammerError::OperationTimedOut,
+            Some(Reason::Aborted) => SinodudeSerialProgrammerError::StreamAborted,
+            Some(Reason::NotHalted) => SinodudeSerialProgrammerError::NotHalted,
+            None => SinodudeSerialProgrammerError::UnknownReason { reason, detail },
+        }
+    }
+
+    /// The response did not arrive in time
+    fn is_timeout(&self) -> bool {
+        matches!(self, SinodudeSerialProgrammerError::IoError(e) if e.kind() == std::io::ErrorKind::TimedOut)
+    }
+
+    /// Errors caused by a lost or corrupted frame, which are worth retrying
+    fn is_transient(&self) -> bool {
+        match self {
+            SinodudeSerialProgrammerError::IoError(_) => self.is_timeout(),
+            SinodudeSerialProgrammerError::CrcMismatch { .. }
+            | SinodudeSerialProgrammerError::FrameTooLong(_)
+            | SinodudeSerialProgrammerError::FrameRejected
+            | SinodudeSerialProgrammerError::StreamGap { .. }
+            | SinodudeSerialProgrammerError::StreamTruncated { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// What 2.x firmware supports: the commands up to CMD_WRITE_CUSTOM_REGION at the
+/// default rate, with its 1024 byte buffer
+fn legacy_capabilities() -> Capabilities {
+    Capabilities {
+        commands: Command::ALL
+            .into_iter()
+            .filter(|&command| command as u8 <= Command::WriteCustomRegion as u8)
+            .fold(0, |bits, command| bits | command.bit()),
+        max_data_len: MAX_DATA_LEN as u16,
+        chip_types: 0x00FF,
+        max_baud: BAUD_RATE,
+    }
+}
+
+/// A sinodude-serial programmer found by [`SinodudeSerialProgrammer::discover`]
+pub struct DiscoveredProgrammer {
+    pub port_name: String,
+    /// Name of the USB serial bridge the port was matched on
+    pub bridge: &'static str,
+    /// Firmware version (major, minor), None if the port did not answer the ping
+    pub version: Option<(u8, u8)>,
+}
+
+/// Encode a request frame
+fn encode_frame(seq: u8, code: u8, payload: &[u8]) -> Vec<u8> {
+    let mut request = vec![0u8; frame::frame_len(payload.len())];
+    frame::encode(seq, code, payload, &mut request);
+    request
+}
+
+/// Parameters of the memory commands
+fn address_and_length(addr: u32, len: usize) -> [u8; AddressLength::LEN] {
+    AddressLength {
+        addr,
+        len: len as u16,
+    }
+    .encode()
+}
+
 pub struct SinodudeSerialProgrammer {
     port: Box<dyn serialport::SerialPort>,
-    chip_type: &'static Part,
+    port_name: String,
+    // Sequence number of the last request frame
+    seq: u8,
+    // Known once check_version has run, until then what 2.x firmware supports
+    capabilities: Capabilities,
+    // The firmware is 2.x and speaks the unframed protocol
+    legacy: bool,
+    chip_type: Option<&'static Part>,
+    // The firmware has been checked and the serial speed negotiated
+    link_open: bool,
     connected: bool,
     cancelled: Arc<AtomicBool>,
-    /// True if code options have non-editable bits that differ from defaults (use 0xc3 erase)
-    use_alternate_erase: bool,
-    /// Stored custom fields read from device during init
-    stored_customer_id: Option<[u8; 4]>,
-    stored_operation_number: Option<[u8; 2]>,
-    stored_customer_option: Option<Vec<u8>>,
-    stored_security: Option<Vec<u8>>,
-    stored_serial_number: Option<[u8; 4]>,
 }
 
 impl SinodudeSerialProgrammer {
-    pub fn new(
+    /// Registry constructor for the sinodude-serial programmer
+    pub fn open(
+        config: &ProgrammerConfig,
+        part: Option<&'static Part>,
+        cancelled: Arc<AtomicBool>,
+    ) -> Result<Box<dyn Programmer>, ProgrammerError> {
+        if let Some(part) = part {
+            Self::check_voltage(part)?;
+        }
+        let programmer = match config.port.as_deref() {
+            Some(port) => Self::new(port, config.reset, part, cancelled)?,
+            None => Self::find(config.reset, part, cancelled)?,
+        };
+        Ok(Box::new(programmer))
+    }
+
+    /// Serial ports whose USB VID/PID matches one of the known Nano bridges
+    pub fn candidate_ports() -> Result<Vec<(String, &'static str)>, SinodudeSerialProgrammerError> {
+        let ports = serialport::available_ports()
+            .map_err(|e| SinodudeSerialProgrammerError::PortEnumerationError(e.to_string()))?;
+
+        Ok(ports
+            .into_iter()
+            .filter_map(|port| match port.port_type {
+                serialport::SerialPortType::UsbPort(usb) => USB_BRIDGES
+                    .iter()
+                    .find(|(vid, pid, _)| *vid == usb.vid && *pid == usb.pid)
+                    .map(|(_, _, bridge)| (port.port_name, *bridge)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Open a candidate port, first without a reset so that a programmer that is already
+    /// running is left alone, and again with `reset` only if it does not answer the ping
+    fn open_candidate(
         port_name: &str,
-        chip_type: &'static Part,
+        reset: ResetMode,
+        part: Option<&'static Part>,
+        cancelled: Arc<AtomicBool>,
+    ) -> Result<Self, SinodudeSerialProgrammerError> {
+        let probed = Self::open_port(
+            port_name,
+            ResetMode::NoReset,
+            part,
+            cancelled.clone(),
+            PROBE_TIMEOUT,
+        );
+        match probed {
+            Err(e) if reset != ResetMode::NoReset => {
+                debug!("{}: {}, retrying with a reset", port_name, e);
+                Self::new(port_name, reset, part, cancelled)
+            }
+            probed => probed,
+        }
+    }
+
+    /// Open every candidate port in parallel, so that boards which need a reset or do not
+    /// answer at all wait out their ready timeouts together
+    fn open_candidates(
+        reset: ResetMode,
+        part: Option<&'static Part>,
+        cancelled: Arc<AtomicBool>,
+    ) -> Result<Vec<OpenedCandidate>, SinodudeSerialProgrammerError> {
+        let candidates = Self::candidate_ports()?;
+        Ok(std::thread::scope(|scope| {
+            let opening: Vec<_> = candidates
+                .into_iter()
+                .map(|(port_name, bridge)| {
+                    let cancelled = cancelled.clone();
+                    scope.spawn(move || {
+                        let opened = Self::open_candidate(&port_name, reset, part, cancelled);
+                        (port_name, bridge, opened)
+                    })
+                })
+                .collect();
+            opening
+                .into_iter()
+                .map(|thread| thread.join().expect("port probe panicked"))
+                .collect()
+        }))
+    }
+
+    /// Probe every candidate port and report the ones running the sinodude firmware
+    pub fn discover(
+        reset: ResetMode,
+        cancelled: Arc<AtomicBool>,
+    ) -> Result<Vec<DiscoveredProgrammer>, SinodudeSerialProgrammerError> {
+        Ok(Self::open_candidates(reset, None, cancelled)?
+            .into_iter()
+            .map(|(port_name, bridge, opened)| {
+                let version = opened
+                    .and_then(|mut programmer| programmer.get_version())
+                    .inspect_err(|e| debug!("{}: {}", port_name, e))
+                    .ok();
+                DiscoveredProgrammer {
+                    port_name,
+                    bridge,
+                    version,
+                }
+            })
+            .collect())
+    }
+
+    /// Open the only candidate port that answers the ping
+    fn find(
+        reset: ResetMode,
+        part: Option<&'static Part>,
         cancelled: Arc<AtomicBool>,
     ) -> Result<Self, SinodudeSerialProgrammerError> {
-        // Check that the part supports 5.0V (required by sinodude-serial programmer)
-        if !chip_type.compatible_voltages.contains(&Voltage::V5_0) {
-            let supported = chip_type
+        let mut found = Vec::new();
+        for (port_name, _, opened) in Self::open_candidates(reset, part, cancelled)? {
+            match opened {
+                Ok(programmer) => found.push(programmer),
+                Err(e) => debug!("{}: {}", port_name, e),
+            }
+        }
+
+        match found.len() {
+            0 => Err(SinodudeSerialProgrammerError::NoProgrammerFound),
+            1 => {
+                let programmer = found.remove(0);
+                eprintln!("Found programmer on {}", programmer.port_name);
+                Ok(programmer)
+            }
+            _ => Err(SinodudeSerialProgrammerError::MultipleProgrammersFound(
+                found.iter().map(|p| p.port_name.clone()).collect(),
+            )),
+        }
+    }
+
+    /// Check that the part supports 5.0V (required by sinodude-serial programmer)
+    fn check_voltage(part: &Part) -> Result<(), SinodudeSerialProgrammerError> {
+        if !part.compatible_voltages.contains(&Voltage::V5_0) {
+            let supported = part
                 .compatible_voltages
                 .iter()
                 .map(|v| v.to_string())
@@ -132,89 +429,357 @@ impl SinodudeSerialProgrammer {
                 .join(", ");
             return Err(SinodudeSerialProgrammerError::UnsupportedVoltage { supported });
         }
+        Ok(())
+    }
+
+    /// Open the port, reset the board as selected and wait until the firmware answers
+    /// the ping with its signature
+    pub fn new(
+        port_name: &str,
+        reset: ResetMode,
+        chip_type: Option<&'static Part>,
+        cancelled: Arc<AtomicBool>,
+    ) -> Result<Self, SinodudeSerialProgrammerError> {
+        Self::open_port(port_name, reset, chip_type, cancelled, READY_TIMEOUT)
+    }
 
+    /// [`SinodudeSerialProgrammer::new`] waiting up to `ready_timeout` for the ping
+    fn open_port(
+        port_name: &str,
+        reset: ResetMode,
+        chip_type: Option<&'static Part>,
+        cancelled: Arc<AtomicBool>,
+        ready_timeout: Duration,
+    ) -> Result<Self, SinodudeSerialProgrammerError> {
         eprintln!("Opening serial port: {}", port_name);
 
+        // DTR is driven explicitly below. Linux still asserts it briefly on open.
         let port = serialport::new(port_name, BAUD_RATE)
             .timeout(TIMEOUT)
+            .dtr_on_open(false)
             .open()
             .map_err(|e| SinodudeSerialProgrammerError::PortOpenError(e.to_string()))?;
 
-        // Give the Arduino time to reset after serial connection
-        std::thread::sleep(Duration::from_secs(2));
-
-        Ok(Self {
+        let mut programmer = Self {
             port,
+            port_name: port_name.to_string(),
+            seq: 0,
+            capabilities: legacy_capabilities(),
+            legacy: false,
             chip_type,
+            link_open: false,
             connected: false,
             cancelled,
-            use_alternate_erase: false,
-            stored_customer_id: None,
-            stored_operation_number: None,
-            stored_customer_option: None,
-            stored_security: None,
-            stored_serial_number: None,
-        })
+        };
+        programmer.reset_board(reset)?;
+        // A reset starts the firmware at BAUD_RATE, without one it may still run at the
+        // rate an earlier session left it at if that session ended abruptly
+        let rates: &[u32] = match reset {
+            ResetMode::NoReset => &BAUD_RATES,
+            _ => &[BAUD_RATE],
+        };
+        programmer.wait_ready(ready_timeout, rates)?;
+        Ok(programmer)
     }
 
-    fn check_cancelled(&self) -> Result<(), SinodudeSerialProgrammerError> {
-        if self.cancelled.load(Ordering::SeqCst) {
-            Err(SinodudeSerialProgrammerError::Cancelled)
-        } else {
-            Ok(())
+    fn set_reset_line(&mut self, reset: ResetMode, level: bool) -> serialport::Result<()> {
+        match reset {
+            ResetMode::Dtr => self.port.write_data_terminal_ready(level),
+            ResetMode::Rts => self.port.write_request_to_send(level),
+            ResetMode::NoReset => Ok(()),
         }
     }
 
-    fn send_command(&mut self, cmd: u8) -> Result<(), SinodudeSerialProgrammerError> {
-        debug!("Sending command: {:#04x}", cmd);
-        self.port.write_all(&[cmd])?;
-        self.port.flush()?;
+    /// Pulse the selected reset line; the board's auto-reset capacitor turns the
+    /// asserting edge into a reset pulse
+    fn reset_board(&mut self, reset: ResetMode) -> Result<(), SinodudeSerialProgrammerError> {
+        if reset == ResetMode::NoReset {
+            return Ok(());
+        }
+        debug!("Resetting board via {:?}", reset);
+        self.set_reset_line(reset, false)
+            .map_err(std::io::Error::from)?;
+        std::thread::sleep(RESET_PULSE);
+        self.set_reset_line(reset, true)
+            .map_err(std::io::Error::from)?;
+        std::thread::sleep(RESET_PULSE);
+        self.port.clear(serialport::ClearBuffer::Input).ok();
         Ok(())
     }
 
-    fn send_bytes(&mut self, data: &[u8]) -> Result<(), SinodudeSerialProgrammerError> {
-        debug!("Sending {} bytes", data.len());
-        self.port.write_all(data)?;
-        self.port.flush()?;
+    /// Ping until the firmware answers with its signature, e.g. once the bootloader has
+    /// handed over after a reset, or `timeout` passes. Each ping uses the next of `rates`
+    /// in turn, and firmware found at another rate than BAUD_RATE is switched back to it.
+    fn wait_ready(
+        &mut self,
+        timeout: Duration,
+        rates: &[u32],
+    ) -> Result<(), SinodudeSerialProgrammerError> {
+        let deadline = Instant::now() + timeout;
+        self.port
+            .set_timeout(READY_POLL_TIMEOUT)
+            .map_err(std::io::Error::from)?;
+        let mut rates = rates.iter().cycle();
+        let ready = loop {
+            let baud = *rates.next().unwrap();
+            self.port
+                .set_baud_rate(baud)
+                .map_err(std::io::Error::from)?;
+            match self.probe() {
+                Ok(true) if baud == BAUD_RATE => break Ok(()),
+                Ok(true) => {
+                    debug!("Programmer answered at {} baud, switching back", baud);
+                    break self.try_baud(BAUD_RATE);
+                }
+                Ok(false) => debug!("Waiting for programmer..."),
+                Err(e) => break Err(e),
+            }
+            if Instant::now() >= deadline || self.cancelled.load(Ordering::SeqCst) {
+                break Err(SinodudeSerialProgrammerError::NotReady(timeout));
+            }
+        };
+        self.port
+            .set_timeout(TIMEOUT)
+            .map_err(std::io::Error::from)?;
+        ready?;
+        debug!("Programmer ready on {}", self.port_name);
         Ok(())
     }
 
+    /// Send a bare ping byte and return whether the firmware answered. 2.x firmware
+    /// answers unframed and framed firmware with a ping frame, which tells them apart
+    /// without sending 2.x firmware a frame it would run as a string of commands.
+    fn probe(&mut self) -> Result<bool, SinodudeSerialProgrammerError> {
+        self.port.write_all(&[Command::Ping as u8])?;
+        self.port.flush()?;
+
+        let answer = self.read_byte().and_then(|first| {
+            let legacy = first != frame::SYNC;
+            let (code, sig) = if legacy {
+                (first, self.read_bytes(2)?)
+            } else {
+                let (_, code, sig) = self.read_frame_body()?;
+                (code, sig)
+            };
+            Ok((legacy, Response::from_u8(code), sig))
+        });
+        match answer {
+            Ok((legacy, Some(Response::Ok), sig)) if sig == [b'S', b'W'] => {
+                self.legacy = legacy;
+                Ok(true)
+            }
+            Ok((_, response, _)) => {
+                debug!("Unexpected ping response {:?}", response);
+                Ok(false)
+            }
+            Err(e) if e.is_transient() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
     fn read_byte(&mut self) -> Result<u8, SinodudeSerialProgrammerError> {
         let mut buf = [0u8; 1];
         self.port.read_exact(&mut buf)?;
-        debug!("Received byte: {:#04x}", buf[0]);
         Ok(buf[0])
     }
 
     fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, SinodudeSerialProgrammerError> {
         let mut buf = vec![0u8; len];
         self.port.read_exact(&mut buf)?;
-        debug!("Received {} bytes", len);
         Ok(buf)
     }
 
-    fn expect_ok(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
-        let response = self.read_byte()?;
-        if response == cmd::RSP_OK {
-            Ok(())
-        } else if response == cmd::RSP_ERR {
-            Err(SinodudeSerialProgrammerError::OperationFailed)
-        } else {
-            Err(SinodudeSerialProgrammerError::InvalidResponse)
+    /// Read one response frame, returning its sequence number, code and payload
+    fn read_frame(&mut self) -> Result<(u8, u8, Vec<u8>), SinodudeSerialProgrammerError> {
+        // Skip anything before the start of the frame
+        while self.read_byte()? != frame::SYNC {}
+        self.read_frame_body()
+    }
+
+    /// Read the response to the request in flight or a FrameError, skipping stale
+    /// responses to earlier requests instead of retransmitting because of them
+    fn read_response(&mut self) -> Result<(u8, u8, Vec<u8>), SinodudeSerialProgrammerError> {
+        loop {
+            let (seq, code, data) = self.read_frame()?;
+            if seq == self.seq || code == Response::FrameError as u8 {
+                return Ok((seq, code, data));
+            }
+            debug!("Skipping stale response seq {} code {:#04x}", seq, code);
+        }
+    }
+
+    /// Read the rest of a response frame after its SYNC byte
+    fn read_frame_body(&mut self) -> Result<(u8, u8, Vec<u8>), SinodudeSerialProgrammerError> {
+        let mut header = [0u8; frame::HEADER_LEN];
+        self.port.read_exact(&mut header)?;
+        let len = frame::Header::decode(header).len as usize;
+        if len > frame::MAX_PAYLOAD {
+            return Err(SinodudeSerialProgrammerError::FrameTooLong(len));
+        }
+
+        let mut received = vec![frame::SYNC];
+        received.extend_from_slice(&header);
+        received.extend_from_slice(&self.read_bytes(len + 2)?);
+        let (header, payload) = frame::decode(&received).map_err(|e| match e {
+            frame::FrameError::CrcMismatch { expected, actual } => {
+                SinodudeSerialProgrammerError::CrcMismatch { expected, actual }
+            }
+            _ => SinodudeSerialProgrammerError::InvalidResponse,
+        })?;
+        let (seq, code, payload) = (header.seq, header.code, payload.to_vec());
+
+        debug!(
+            "Received frame seq {} code {:#04x} ({} bytes)",
+            seq,
+            code,
+            payload.len()
+        );
+        Ok((seq, code, payload))
+    }
+
+    /// Send a request frame and return the response code and payload. The request is
+    /// retransmitted when the response is lost or corrupted, or the firmware reports
+    /// that the request arrived corrupted.
+    fn transact(
+        &mut self,
+        cmd: Command,
+        payload: &[u8],
+    ) -> Result<(Response, Vec<u8>), SinodudeSerialProgrammerError> {
+        self.transact_with(cmd, payload, true)
+    }
+
+    /// Like [`Self::transact`], but with `retry_timeouts` false a response that does not
+    /// arrive in time is returned as an error. The firmware may still be busy with such a
+    /// request and not reading the port, so sending it again would only pile up behind it.
+    fn transact_with(
+        &mut self,
+        cmd: Command,
+        payload: &[u8],
+        retry_timeouts: bool,
+    ) -> Result<(Response, Vec<u8>), SinodudeSerialProgrammerError> {
+        if self.legacy {
+            return self.legacy_transact(cmd, payload);
+        }
+
+        self.seq = self.seq.wrapping_add(1);
+        let request = encode_frame(self.seq, cmd as u8, payload);
+        debug!(
+            "Sending command {:?} seq {} ({} bytes)",
+            cmd,
+            self.seq,
+            payload.len()
+        );
+
+        for attempt in 0..=MAX_RETRIES {
+            if attempt > 0 {
+                debug!("Retransmitting seq {} (attempt {})", self.seq, attempt);
+                // Drop the rest of a garbled response before trying again
+                self.port.clear(serialport::ClearBuffer::Input).ok();
+            }
+            self.port.write_all(&request)?;
+            self.port.flush()?;
+
+            match self.read_response() {
+                // The firmware cannot trust the header of a frame it rejects and answers
+                // with seq 0, so any FrameError refers to the request in flight
+                Ok((_, code, _)) if code == Response::FrameError as u8 => {
+                    debug!("Firmware rejected seq {} as corrupted", self.seq);
+                }
+                Ok((_, code, data)) => match Response::from_u8(code) {
+                    Some(response) => return Ok((response, data)),
+                    None => return Err(SinodudeSerialProgrammerError::InvalidResponse),
+                },
+                Err(e) if e.is_transient() && (retry_timeouts || !e.is_timeout()) => {
+                    debug!("{}", e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(SinodudeSerialProgrammerError::TooManyRetries(MAX_RETRIES))
+    }
+
+    /// Send a request to 2.x firmware as a bare command byte and payload. Its responses
+    /// carry no length or checksum, so their size follows from the command, and a lost
+    /// byte cannot be recovered by retransmitting.
+    fn legacy_transact(
+        &mut self,
+        cmd: Command,
+        payload: &[u8],
+    ) -> Result<(Response, Vec<u8>), SinodudeSerialProgrammerError> {
+        // 2.x firmware would run the payload of a command it does not know as commands
+        if !self.capabilities.supports(cmd) {
+            return Err(SinodudeSerialProgrammerError::UnknownCommand(cmd as u8));
+        }
+        debug!(
+            "Sending unframed command {:?} ({} bytes)",
+            cmd,
+            payload.len()
+        );
+        let mut request = vec![cmd as u8];
+        request.extend_from_slice(payload);
+        self.port.write_all(&request)?;
+        self.port.flush()?;
+
+        let code = self.read_byte()?;
+        let response =
+            Response::from_u8(code).ok_or(SinodudeSerialProgrammerError::InvalidResponse)?;
+        let data = match (response, cmd) {
+            (Response::Ok, Command::Ping) => self.read_bytes(2)?,
+            (Response::Data, Command::GetVersion | Command::GetId) => self.read_bytes(2)?,
+            (Response::Data, Command::GetConfig) => self.read_bytes(1)?,
+            (Response::Data, Command::ReadFlash | Command::ReadCustomRegion) => {
+                let len = self.read_bytes(2)?;
+                self.read_bytes(u16::from_le_bytes([len[0], len[1]]) as usize)?
+            }
+            (Response::Ok | Response::Error, _) => Vec::new(),
+            _ => return Err(SinodudeSerialProgrammerError::InvalidResponse),
+        };
+        Ok((response, data))
+    }
+
+    /// Run a command that answers with Response::Ok
+    fn command(
+        &mut self,
+        cmd: Command,
+        payload: &[u8],
+    ) -> Result<(), SinodudeSerialProgrammerError> {
+        Self::expect_ok(self.transact(cmd, payload)?)
+    }
+
+    fn expect_ok(response: (Response, Vec<u8>)) -> Result<(), SinodudeSerialProgrammerError> {
+        match response {
+            (Response::Ok, _) => Ok(()),
+            (Response::Error, payload) => {
+                Err(SinodudeSerialProgrammerError::from_firmware(&payload))
+            }
+            _ => Err(SinodudeSerialProgrammerError::InvalidResponse),
+        }
+    }
+
+    /// Run a command that answers with Response::Data and a payload
+    fn query(
+        &mut self,
+        cmd: Command,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, SinodudeSerialProgrammerError> {
+        match self.transact(cmd, payload)? {
+            (Response::Data, data) => Ok(data),
+            (Response::Error, payload) => {
+                Err(SinodudeSerialProgrammerError::from_firmware(&payload))
+            }
+            _ => Err(SinodudeSerialProgrammerError::InvalidResponse),
         }
     }
 
     pub fn ping(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
         eprintln!("Pinging programmer...");
-        self.send_command(cmd::CMD_PING)?;
-
-        let response = self.read_byte()?;
-        if response != cmd::RSP_OK {
+        let (response, sig) = self.transact(Command::Ping, &[])?;
+        if response != Response::Ok {
             return Err(SinodudeSerialProgrammerError::NoResponse);
         }
 
-        // Read signature bytes "SW"
-        let sig = self.read_bytes(2)?;
+        // Check signature bytes "SW"
         if sig != [b'S', b'W'] {
             return Err(SinodudeSerialProgrammerError::InvalidResponse);
         }
@@ -225,39 +790,134 @@ impl SinodudeSerialProgrammer {
 
     pub fn get_version(&mut self) -> Result<(u8, u8), SinodudeSerialProgrammerError> {
         debug!("Getting firmware version...");
-        self.send_command(cmd::CMD_GET_VERSION)?;
-
-        let response = self.read_byte()?;
-        if response != cmd::RSP_DATA {
-            return Err(SinodudeSerialProgrammerError::OperationFailed);
-        }
-
-        let major = self.read_byte()?;
-        let minor = self.read_byte()?;
+        let data = self.query(Command::GetVersion, &[])?;
+        let [major, minor] = data[..] else {
+            return Err(SinodudeSerialProgrammerError::InvalidResponse);
+        };
 
         eprintln!("Firmware version: {}.{}", major, minor);
 
         Ok((major, minor))
     }
 
+    /// Check that the firmware speaks a protocol this host knows and learn what it
+    /// supports. 2.x firmware predates framing and capability reporting, and runs with
+    /// its original commands only.
     pub fn check_version(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
         let (major, _minor) = self.get_version()?;
 
-        if major != EXPECTED_VERSION_MAJOR {
+        if self.legacy && major != LEGACY_VERSION_MAJOR {
+            return Err(SinodudeSerialProgrammerError::LegacyVersionMismatch {
+                expected: LEGACY_VERSION_MAJOR,
+                actual: major,
+            });
+        }
+        if !self.legacy && major < MIN_VERSION_MAJOR {
             return Err(SinodudeSerialProgrammerError::VersionMismatch {
-                expected: EXPECTED_VERSION_MAJOR,
+                expected: MIN_VERSION_MAJOR,
                 actual: major,
             });
         }
 
+        self.capabilities = if self.legacy {
+            eprintln!(
+                "Firmware {}.x has no streaming reads, checksums, faster serial speeds or power control, update it for all features",
+                major
+            );
+            legacy_capabilities()
+        } else {
+            let data = self.query(Command::GetCapabilities, &[])?;
+            Capabilities::decode(&data).ok_or(SinodudeSerialProgrammerError::InvalidResponse)?
+        };
+        debug!("Firmware capabilities: {:?}", self.capabilities);
+
+        Ok(())
+    }
+
+    fn check_chip_type(&self, chip_type: u8) -> Result<(), SinodudeSerialProgrammerError> {
+        if self.capabilities.supports_chip_type(chip_type) {
+            Ok(())
+        } else {
+            Err(SinodudeSerialProgrammerError::UnsupportedChipType(
+                chip_type,
+            ))
+        }
+    }
+
+    /// Switch both ends to the fastest rate that passes the ping check, falling back to
+    /// BAUD_RATE. Returns the rate in use.
+    pub fn negotiate_baud(&mut self) -> Result<u32, SinodudeSerialProgrammerError> {
+        let capabilities = self.capabilities;
+        // Faster rates first
+        let rates = BAUD_RATES.into_iter().rev().filter(|&baud| {
+            baud != BAUD_RATE
+                && capabilities.supports(Command::SetBaud)
+                && baud <= capabilities.max_baud
+        });
+        for baud in rates {
+            match self.try_baud(baud) {
+                Ok(()) => {
+                    eprintln!("Serial speed: {} baud", baud);
+                    return Ok(baud);
+                }
+                // The firmware refused the rate, so it never left BAUD_RATE
+                Err(
+                    SinodudeSerialProgrammerError::OperationFailed
+                    | SinodudeSerialProgrammerError::BadParameters(_)
+                    | SinodudeSerialProgrammerError::UnknownCommand(_),
+                ) => {
+                    debug!("Firmware refused {} baud", baud);
+                }
+                Err(e) => {
+                    debug!("{} baud failed: {}", baud, e);
+                    self.revert_baud()?;
+                }
+            }
+        }
+
+        eprintln!("Serial speed: {} baud", BAUD_RATE);
+        Ok(BAUD_RATE)
+    }
+
+    fn try_baud(&mut self, baud: u32) -> Result<(), SinodudeSerialProgrammerError> {
+        self.command(Command::SetBaud, &baud.to_le_bytes())?;
+
+        // The firmware has answered at the old rate and switched, check the new one
+        self.port
+            .set_baud_rate(baud)
+            .map_err(std::io::Error::from)?;
+        self.port
+            .set_timeout(BAUD_CHECK_TIMEOUT)
+            .map_err(std::io::Error::from)?;
+        let check = self.transact(Command::Ping, &[]);
+        self.port
+            .set_timeout(TIMEOUT)
+            .map_err(std::io::Error::from)?;
+
+        match check? {
+            (Response::Ok, sig) if sig == [b'S', b'W'] => Ok(()),
+            _ => Err(SinodudeSerialProgrammerError::InvalidResponse),
+        }
+    }
+
+    /// Return to BAUD_RATE after a failed check, once the firmware has reverted too
+    fn revert_baud(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
+        self.port
+            .set_baud_rate(BAUD_RATE)
+            .map_err(std::io::Error::from)?;
+        std::thread::sleep(BAUD_CHECK_WINDOW);
+        self.port.clear(serialport::ClearBuffer::All).ok();
         Ok(())
     }
 
     pub fn connect(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
         eprintln!("Connecting to target MCU...");
-        self.send_command(cmd::CMD_CONNECT)?;
-        self.expect_ok()
-            .map_err(|_| SinodudeSerialProgrammerError::ConnectionFailed)?;
+        self.command(Command::Connect, &[]).map_err(|e| match e {
+            SinodudeSerialProgrammerError::OperationFailed => {
+                SinodudeSerialProgrammerError::ConnectionFailed
+            }
+            e => e,
+        })?;
         self.connected = true;
         eprintln!("Connected to target MCU");
         Ok(())
@@ -265,128 +925,49 @@ impl SinodudeSerialProgrammer {
 
     pub fn disconnect(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
         eprintln!("Disconnecting from target MCU...");
-        self.send_command(cmd::CMD_DISCONNECT)?;
-        self.expect_ok()?;
+        self.command(Command::Disconnect, &[])?;
         self.connected = false;
         eprintln!("Disconnected from target MCU");
         Ok(())
     }
 
-    pub fn get_id(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
+    pub fn get_id(&mut self) -> Result<u16, SinodudeSerialProgrammerError> {
         debug!("Getting target MCU ID...");
-        self.send_command(cmd::CMD_GET_ID)?;
-
-        // Read response
-        let response = self.read_byte()?;
-        if response != cmd::RSP_DATA {
-            return Err(SinodudeSerialProgrammerError::OperationFailed);
-        }
-
-        let mut id_bytes = [0u8; 2];
-        for byte in &mut id_bytes {
-            *byte = self.read_byte()?;
-        }
-
-        let id = u16::from_le_bytes(id_bytes);
-        eprintln!("Target MCU ID: {:04x}", id);
-
-        let expected_id = self.chip_type.jtag_id;
-        if id != expected_id {
-            let matching_parts = find_parts_by_jtag_id(id);
-            if !matching_parts.is_empty() {
-                eprintln!(
-                    "Parts matching JTAG ID {:#06x}: {}",
-                    id,
-                    matching_parts.join(", ")
-                );
-            }
-            return Err(SinodudeSerialProgrammerError::JtagIdMismatch {
-                expected: expected_id,
-                actual: id,
-            });
-        }
+        let data = self.query(Command::GetId, &[])?;
+        let id_bytes: [u8; 2] = data
+            .try_into()
+            .map_err(|_| SinodudeSerialProgrammerError::InvalidResponse)?;
 
-        Ok(())
+        Ok(u16::from_le_bytes(id_bytes))
     }
 
-    pub fn set_config(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
-        self.send_command(cmd::CMD_SET_CONFIG)?;
-        self.send_bytes(&[self.chip_type.chip_type])?;
-        self.expect_ok()?;
-        debug!(
-            "Configuration set for chip type: {:#04x}",
-            self.chip_type.chip_type
-        );
+    pub fn set_config(&mut self, chip_type: u8) -> Result<(), SinodudeSerialProgrammerError> {
+        self.check_chip_type(chip_type)?;
+        self.command(Command::SetConfig, &[chip_type])?;
+        debug!("Configuration set for chip type: {:#04x}", chip_type);
         Ok(())
     }
 
     pub fn get_config(&mut self) -> Result<u8, SinodudeSerialProgrammerError> {
         debug!("Getting firmware config...");
-        self.send_command(cmd::CMD_GET_CONFIG)?;
-
-        let response = self.read_byte()?;
-        if response != cmd::RSP_DATA {
-            return Err(SinodudeSerialProgrammerError::OperationFailed);
-        }
-
-        let chip_type = self.read_byte()?;
+        let data = self.query(Command::GetConfig, &[])?;
+        let [chip_type] = data[..] else {
+            return Err(SinodudeSerialProgrammerError::InvalidResponse);
+        };
         debug!("Firmware chip type: {:#04x}", chip_type);
 
         Ok(chip_type)
     }
 
-    pub fn get_part_number(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
-        let custom_block_addr: u32 = match self.chip_type.custom_block {
-            0x02 => 0x0A00,
-            0x03 => 0x1200,
-            0x04 => 0x2200,
-            _ => return Err(SinodudeSerialProgrammerError::OperationFailed),
-        };
-        self.send_command(cmd::CMD_READ_CUSTOM_REGION)?;
-        self.send_bytes(&custom_block_addr.to_le_bytes())?; // Address
-        self.send_bytes(&(16u16.to_le_bytes()))?; // Length
-        let response = self.read_byte()?;
-        if response != cmd::RSP_DATA {
-            return Err(SinodudeSerialProgrammerError::OperationFailed);
-        }
-        let recv_len_l = self.read_byte()?; // Data length (low byte)
-        let recv_len_h = self.read_byte()?; // Data length (high byte)
-        let recv_len = u16::from_le_bytes([recv_len_l, recv_len_h]) as usize;
-        if recv_len != 16 {
-            return Err(SinodudeSerialProgrammerError::InvalidResponse);
-        }
-
-        let data = self.read_bytes(16)?;
+    /// Read the 5-byte part number from the custom block at `custom_block_addr`
+    pub fn get_part_number(
+        &mut self,
+        custom_block_addr: u32,
+    ) -> Result<[u8; 5], SinodudeSerialProgrammerError> {
+        let data = self.read_region(Region::Custom, custom_block_addr, 16)?;
         let mut part_number = [0u8; 5];
         part_number.copy_from_slice(&data[9..14]);
-
-        let actual_str = part_number
-            .iter()
-            .map(|b| format!("{:02x}", b))
-            .collect::<String>();
-        eprintln!("Target Part Number: {}", actual_str);
-
-        let expected_part_number = self.chip_type.part_number;
-        if part_number != expected_part_number {
-            let expected_str = expected_part_number
-                .iter()
-                .map(|b| format!("{:02x}", b))
-                .collect::<String>();
-            let matching_parts = find_parts_by_part_number(&part_number);
-            if !matching_parts.is_empty() {
-                eprintln!(
-                    "Parts matching part number {}: {}",
-                    actual_str,
-                    matching_parts.join(", ")
-                );
-            }
-            return Err(SinodudeSerialProgrammerError::PartNumberMismatch {
-                expected: expected_str,
-                actual: actual_str,
-            });
-        }
-
-        Ok(())
+        Ok(part_number)
     }
 
     fn read_region(
@@ -400,208 +981,162 @@ impl SinodudeSerialProgrammer {
             size, region, address
         );
         let cmd = match region {
-            Region::Custom => cmd::CMD_READ_CUSTOM_REGION,
-            Region::Flash => cmd::CMD_READ_FLASH,
+            Region::Custom => Command::ReadCustomRegion,
+            Region::Flash => Command::ReadFlash,
         };
-        self.send_command(cmd)?;
-        self.send_bytes(&address.to_le_bytes())?;
-        self.send_bytes(&(size as u16).to_le_bytes())?;
-        let response = self.read_byte()?;
-        if response != cmd::RSP_DATA {
-            return Err(SinodudeSerialProgrammerError::OperationFailed);
-        }
-        let recv_len_l = self.read_byte()?;
-        let recv_len_h = self.read_byte()?;
-        let recv_len = u16::from_le_bytes([recv_len_l, recv_len_h]) as usize;
-        if recv_len != size {
+        let data = self.query(cmd, &address_and_length(address, size))?;
+        if data.len() != size {
             return Err(SinodudeSerialProgrammerError::InvalidResponse);
         }
-        self.read_bytes(size)
+        Ok(data)
     }
 
-    pub fn get_code_options(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
-        let region = self.chip_type.options_region();
+    /// Read `len` bytes of flash from `addr` as one stream of data frames, calling
+    /// `progress` with the number of bytes received so far. A corrupted or truncated
+    /// stream is resumed after the last good frame. Once cancelled, stops early and
+    /// returns what was read.
+    pub fn read_stream(
+        &mut self,
+        addr: u32,
+        len: usize,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<Vec<u8>, SinodudeSerialProgrammerError> {
+        let mut data = Vec::with_capacity(len);
+        let mut retries = 0;
+        loop {
+            let resume_addr = addr + data.len() as u32;
+            let remaining = len - data.len();
+            match self.stream_range(resume_addr, remaining, &mut data, progress) {
+                Ok(()) => return Ok(data),
+                Err(e) if e.is_transient() && retries < MAX_RETRIES => {
+                    retries += 1;
+                    debug!(
+                        "Stream interrupted at {:#x}: {}, resuming",
+                        addr + data.len() as u32,
+                        e
+                    );
+                    self.abort_stream()?;
+                }
+                Err(e) => {
+                    self.abort_stream().ok();
+                    return Err(e);
+                }
+            }
+        }
+    }
 
-        let customer_id_addr = self.chip_type.customer_id.address;
+    /// Run one streaming read, appending the data to `data`
+    fn stream_range(
+        &mut self,
+        addr: u32,
+        len: usize,
+        data: &mut Vec<u8>,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<(), SinodudeSerialProgrammerError> {
+        self.seq = self.seq.wrapping_add(1);
+        debug!(
+            "Streaming {} bytes from {:#06x} (seq {})",
+            len, addr, self.seq
+        );
+        let params = FlashRange {
+            addr,
+            len: len as u32,
+        };
+        self.port.write_all(&encode_frame(
+            self.seq,
+            Command::ReadFlashStream as u8,
+            &params.encode(),
+        ))?;
+        self.port.flush()?;
 
-        // Read 64 bytes from customer_id address in one transaction
-        const REGION_SIZE: usize = 64;
-        let buffer = self.read_region(region, customer_id_addr, REGION_SIZE)?;
+        let start_len = data.len();
+        loop {
+            let (seq, code, payload) = self.read_frame()?;
+            let response = Response::from_u8(code);
+            if response == Some(Response::FrameError) {
+                return Err(SinodudeSerialProgrammerError::FrameRejected);
+            }
+            if seq != self.seq {
+                debug!("Skipping frame seq {} code {:#04x}", seq, code);
+                continue;
+            }
 
-        // Extract customer_id (at offset 0)
-        let customer_id: [u8; 4] = buffer[0..4].try_into().unwrap();
-        eprintln!(
-            "Customer ID: {}",
-            customer_id
-                .iter()
-                .map(|b| format!("{:02x}", b))
-                .collect::<String>()
-        );
-        self.stored_customer_id = Some(customer_id);
+            let received = data.len() - start_len;
+            match (response, &payload[..]) {
+                (Some(Response::Data), [a0, a1, a2, a3, chunk @ ..]) => {
+                    let chunk_addr = u32::from_le_bytes([*a0, *a1, *a2, *a3]);
+                    let expected = addr + received as u32;
+                    if chunk_addr != expected || received + chunk.len() > len {
+                        return Err(SinodudeSerialProgrammerError::StreamGap {
+                            expected,
+                            actual: chunk_addr,
+                        });
+                    }
+                    data.extend_from_slice(chunk);
+                    progress(data.len());
 
-        // Extract operation_number
-        let field = &self.chip_type.operation_number;
-        let offset = (field.address - customer_id_addr) as usize;
-        if offset + 2 <= REGION_SIZE {
-            let operation_number: [u8; 2] = buffer[offset..offset + 2].try_into().unwrap();
-            eprintln!(
-                "Operation Number: {}",
-                operation_number
-                    .iter()
-                    .map(|b| format!("{:02x}", b))
-                    .collect::<String>()
-            );
-            self.stored_operation_number = Some(operation_number);
+                    if self.cancelled.load(Ordering::SeqCst) {
+                        self.abort_stream()?;
+                        return Ok(());
+                    }
+                }
+                (Some(Response::Ok), [c0, c1, c2, c3]) => {
+                    let sent = u32::from_le_bytes([*c0, *c1, *c2, *c3]) as usize;
+                    if sent != received || received != len {
+                        return Err(SinodudeSerialProgrammerError::StreamTruncated {
+                            expected: len,
+                            received,
+                        });
+                    }
+                    return Ok(());
+                }
+                (Some(Response::Error), payload) => {
+                    return Err(SinodudeSerialProgrammerError::from_firmware(payload))
+                }
+                _ => return Err(SinodudeSerialProgrammerError::InvalidResponse),
+            }
         }
+    }
 
-        // Extract security bits
-        let field = &self.chip_type.security;
-        let offset = (field.address - customer_id_addr) as usize;
-        let security_len = self.chip_type.security_length();
-        if offset + security_len <= REGION_SIZE {
-            let security_bits = buffer[offset..offset + security_len].to_vec();
-            eprintln!(
-                "Security Bits: {}",
-                security_bits
-                    .iter()
-                    .map(|b| format!("{:02x}", b))
-                    .collect::<String>()
-            );
-            self.stored_security = Some(security_bits);
-        }
-
-        // Extract serial_number
-        let field = &self.chip_type.serial_number;
-        let offset = (field.address - customer_id_addr) as usize;
-        if offset + 4 <= REGION_SIZE {
-            let serial_number: [u8; 4] = buffer[offset..offset + 4].try_into().unwrap();
-            eprintln!(
-                "Serial Number: {}",
-                serial_number
-                    .iter()
-                    .map(|b| format!("{:02x}", b))
-                    .collect::<String>()
-            );
-            self.stored_serial_number = Some(serial_number);
-        }
-
-        // Extract and handle code options
-        let field = &self.chip_type.customer_option;
-        let option_byte_count = self.chip_type.option_byte_count;
-        let offset = (field.address - customer_id_addr) as usize;
-        let first_part_size = 4.min(option_byte_count);
-
-        // First part from main buffer
-        let mut code_options = buffer[offset..offset + first_part_size].to_vec();
-
-        // Read upper bytes from 0x1100 in separate transaction if needed
-        if option_byte_count > 4 {
-            let second_part_size = option_byte_count - 4;
-            let second_part = self.read_region(region, 0x1100, second_part_size)?;
-            code_options.extend_from_slice(&second_part);
-        }
-
-        // Store the full customer_option (both parts)
-        self.stored_customer_option = Some(code_options.clone());
-
-        eprintln!(
-            "Code Options: {}",
-            code_options
-                .iter()
-                .map(|b| format!("{:02x}", b))
-                .collect::<String>()
-        );
+    /// Stop a running stream and discard whatever the firmware sent until the line is quiet
+    fn abort_stream(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
+        self.port.write_all(&[STREAM_ABORT])?;
+        self.port.flush()?;
 
-        // Check if non-editable bits differ from defaults (only upper bytes for >4-byte options)
-        if let Some(expected_upper) = self.chip_type.upper_code_option_defaults() {
-            let mask = self.chip_type.code_option_mask;
-            for (i, &expected) in expected_upper.iter().enumerate() {
-                let idx = 4 + i;
-                if idx < code_options.len() {
-                    let current = code_options[idx] & !mask[idx];
-                    if current != expected {
-                        eprintln!(
-                                "Warning: Code option byte {} has non-editable bits that differ from defaults (current: {:#04x}, expected: {:#04x})",
-                                idx, current, expected
-                            );
-                        self.use_alternate_erase = true;
-                    }
-                }
+        self.port
+            .set_timeout(DRAIN_QUIET)
+            .map_err(std::io::Error::from)?;
+        let mut buf = [0u8; 256];
+        let drained = loop {
+            match self.port.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(_) => continue,
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => break Ok(()),
+                Err(e) => break Err(e.into()),
             }
-        }
-
-        // Parse and display options in user-friendly format
-        let options_metadata = (self.chip_type.options)();
-        let parsed = parse_code_options(&code_options, &options_metadata);
-        eprintln!("Code Options (parsed):\n{}", format_parsed_options(&parsed));
-
-        Ok(())
-    }
-
-    pub fn read_init(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
-        self.ping()?;
-        self.check_version()?;
-        self.connect()?;
-        self.get_id()?;
-        self.set_config()?;
-        self.get_part_number()?;
-        self.get_code_options()?;
-        Ok(())
-    }
-
-    pub fn write_init(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
-        self.ping()?;
-        self.check_version()?;
-        self.connect()?;
-        self.get_id()?;
-        self.set_config()?;
-        self.get_part_number()?;
-        self.get_code_options()?;
-        Ok(())
-    }
-
-    pub fn erase_init(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
-        self.ping()?;
-        self.check_version()?;
-        self.connect()?;
-        self.get_id()?;
-        self.set_config()?;
-        self.get_part_number()?;
-        Ok(())
+        };
+        self.port
+            .set_timeout(TIMEOUT)
+            .map_err(std::io::Error::from)?;
+        drained
     }
 
-    pub fn read_flash(&mut self) -> Result<Vec<u8>, SinodudeSerialProgrammerError> {
-        let flash_size: u32 = self.chip_type.flash_size as u32;
-        let mut contents = vec![0u8; 0];
-
-        eprintln!("Reading {} bytes from flash...", flash_size);
-
-        let buffer_size: u16 = CHUNK_SIZE as u16;
-
-        let progress = ProgressBar::new(flash_size as u64);
-        progress.set_style(
-            ProgressStyle::default_bar()
-                .template("{msg} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-                .unwrap()
-                .progress_chars("=>-"),
-        );
-        progress.set_message("Reading");
-
-        let start = Instant::now();
-        for addr in (0..flash_size).step_by(buffer_size as usize) {
-            self.check_cancelled().inspect_err(|_| {
-                progress.abandon_with_message("Cancelled");
-            })?;
-            let result = self.read_chunk(addr, buffer_size).inspect_err(|_| {
-                progress.abandon_with_message("Read failed");
-            })?;
-            contents.extend_from_slice(&result);
-            progress.set_position(addr as u64 + buffer_size as u64);
-        }
-        let elapsed = start.elapsed();
-
-        progress.finish_with_message(format!("Read complete in {:.2?}", elapsed));
-        Ok(contents)
+    /// CRC32 of `len` bytes of flash at `addr`, computed by the firmware as it reads
+    pub fn flash_crc(
+        &mut self,
+        addr: u32,
+        len: usize,
+    ) -> Result<u32, SinodudeSerialProgrammerError> {
+        debug!("Checksumming {} bytes of flash at {:#06x}", len, addr);
+        let params = FlashRange {
+            addr,
+            len: len as u32,
+        };
+        let data = self.query(Command::FlashCrc, &params.encode())?;
+        let crc_bytes: [u8; 4] = data
+            .try_into()
+            .map_err(|_| SinodudeSerialProgrammerError::InvalidResponse)?;
+        Ok(u32::from_le_bytes(crc_bytes))
     }
 
     pub fn read_chunk(
@@ -609,143 +1144,56 @@ impl SinodudeSerialProgrammer {
         addr: u32,
         length: u16,
     ) -> Result<Vec<u8>, SinodudeSerialProgrammerError> {
-        debug!("Reading {} bytes at {:#x}", length, addr);
-        self.send_command(cmd::CMD_READ_FLASH)?;
-
-        // Send address (4 bytes, little endian)
-        let addr_bytes = addr.to_le_bytes();
-        self.send_bytes(&addr_bytes)?;
-
-        // Send length (2 bytes, little endian)
-        let len_bytes = length.to_le_bytes();
-        self.send_bytes(&len_bytes)?;
-
-        // Expect data response
-        let response = self.read_byte()?;
-        if response != cmd::RSP_DATA {
-            return Err(SinodudeSerialProgrammerError::OperationFailed);
-        }
-
-        // Read length
-        let recv_len_l = self.read_byte()?; // Data length (low byte)
-        let recv_len_h = self.read_byte()?; // Data length (high byte)
-        let recv_len = u16::from_le_bytes([recv_len_l, recv_len_h]);
-        if recv_len != length {
-            return Err(SinodudeSerialProgrammerError::InvalidResponse);
-        }
-
-        // Read data
-        let data = self.read_bytes(length as usize)?;
-        Ok(data)
+        self.read_region(Region::Flash, addr, length as usize)
     }
 
     fn erase_sector(&mut self, addr: u32) -> Result<(), SinodudeSerialProgrammerError> {
         debug!("Erasing sector at {:#x}", addr);
-        self.send_command(cmd::CMD_ERASE_FLASH_SECTOR)?;
-
-        // Send address (4 bytes, little endian)
-        let addr_bytes = addr.to_le_bytes();
-        self.send_bytes(&addr_bytes)?;
-
-        self.expect_ok()
-            .map_err(|_| SinodudeSerialProgrammerError::EraseFailed(addr))
+        self.command(Command::EraseFlashSector, &addr.to_le_bytes())
+            .map_err(|e| SinodudeSerialProgrammerError::EraseFailed {
+                addr,
+                reason: Box::new(e),
+            })
     }
 
-    /// Erase sectors covering the given address range
-    pub fn erase_sectors(
-        &mut self,
-        start_addr: u32,
-        end_addr: u32,
-    ) -> Result<(), SinodudeSerialProgrammerError> {
-        let sector_size = self.chip_type.sector_size as u32;
-        let first_sector = start_addr / sector_size;
-        let last_sector = (end_addr.saturating_sub(1)) / sector_size;
-        let num_sectors = last_sector - first_sector + 1;
-
-        eprintln!(
-            "Erasing {} sector(s) from {:#x} to {:#x}...",
-            num_sectors,
-            first_sector * sector_size,
-            (last_sector + 1) * sector_size
-        );
-
-        let progress = ProgressBar::new(num_sectors as u64);
-        progress.set_style(
-            ProgressStyle::default_bar()
-                .template("{msg} [{bar:40.cyan/blue}] {pos}/{len}")
-                .unwrap()
-                .progress_chars("=>-"),
-        );
-        progress.set_message("Erasing");
-
-        let start = Instant::now();
-        for sector in first_sector..=last_sector {
-            self.check_cancelled().inspect_err(|_| {
-                progress.abandon_with_message("Cancelled");
-            })?;
-            let sector_addr = sector * sector_size;
-            self.erase_sector(sector_addr).inspect_err(|_| {
-                progress.abandon_with_message("Erase failed");
-            })?;
-            progress.inc(1);
-        }
-        let elapsed = start.elapsed();
-        progress.finish_with_message(format!("Erase complete in {:.2?}", elapsed));
-
-        Ok(())
-    }
-
-    pub fn mass_erase(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
-        if self.use_alternate_erase {
-            eprintln!("Mass erasing flash (alternate mode due to non-default code options)...");
-        } else {
-            eprintln!("Mass erasing flash...");
+    fn mass_erase(&mut self, alternate: bool) -> Result<(), SinodudeSerialProgrammerError> {
+        // Flag: 1 = alternate erase (0xc3), 0 = normal erase (0x4b)
+        self.port
+            .set_timeout(MASS_ERASE_TIMEOUT)
+            .map_err(std::io::Error::from)?;
+        // A target that wedges the erase also keeps the firmware from reading the port
+        let result = self
+            .transact_with(Command::MassErase, &[if alternate { 1 } else { 0 }], false)
+            .and_then(Self::expect_ok);
+        self.port
+            .set_timeout(TIMEOUT)
+            .map_err(std::io::Error::from)?;
+
+        // Either the firmware gave up waiting for the target, or the host for the firmware
+        let timed_out = result.as_ref().is_err_and(|e| {
+            e.is_timeout() || matches!(e, SinodudeSerialProgrammerError::OperationTimedOut)
+        });
+        match result {
+            Ok(()) => Ok(()),
+            Err(_) if timed_out => {
+                self.power_cycle();
+                Err(SinodudeSerialProgrammerError::MassEraseTimeout)
+            }
+            Err(e) => Err(SinodudeSerialProgrammerError::MassEraseFailed(Box::new(e))),
         }
-        let start = Instant::now();
-
-        self.send_command(cmd::CMD_MASS_ERASE)?;
-        // Send flag: 1 = alternate erase (0xc3), 0 = normal erase (0x4b)
-        self.send_bytes(&[if self.use_alternate_erase { 1 } else { 0 }])?;
-        self.expect_ok()
-            .map_err(|_| SinodudeSerialProgrammerError::MassEraseFailed)?;
-
-        let elapsed = start.elapsed();
-        eprintln!("Mass erase complete in {:.2?}", elapsed);
-
-        // Blank security and set high code option defaults
-        self.blank_security_and_set_code_option_defaults()?;
-
-        Ok(())
     }
 
-    /// Blank security region and set high code option defaults after mass erase.
-    /// For parts with >4-byte code options, writes the non-editable bits from defaults to 0x1100.
-    fn blank_security_and_set_code_option_defaults(
-        &mut self,
-    ) -> Result<(), SinodudeSerialProgrammerError> {
-        // Blank security region
-        {
-            let security = &self.chip_type.security;
-            let security_length = self.chip_type.security_length();
-            eprintln!(
-                "Blanking security region at {:#x} ({} bytes)...",
-                security.address, security_length
-            );
-            let zeros = vec![0u8; security_length];
-            self.write_custom_region(security.address, &zeros)?;
-        }
-
-        // Set high code option defaults for parts with >4 byte options
-        if let Some(upper) = self.chip_type.upper_code_option_defaults() {
-            eprintln!(
-                "Setting high code option defaults at {:#x} ({} bytes)...",
-                0x1100,
-                upper.len()
-            );
-            self.write_custom_region(0x1100, &upper)?;
+    /// Power the target off and reconnect, e.g. after it stopped responding mid-erase.
+    /// Failing to reconnect is only reported, the caller already has an error to return.
+    fn power_cycle(&mut self) {
+        eprintln!("Power-cycling target...");
+        let result = self.disconnect().and_then(|()| {
+            std::thread::sleep(POWER_CYCLE_OFF_TIME);
+            self.connect()
+        });
+        if let Err(e) = result {
+            eprintln!("Failed to reconnect to target: {}", e);
         }
-
-        Ok(())
     }
 
     pub fn write_custom_region(
@@ -758,22 +1206,13 @@ impl SinodudeSerialProgrammer {
             data.len(),
             addr
         );
-        self.send_command(cmd::CMD_WRITE_CUSTOM_REGION)?;
-
-        // Send address (4 bytes, little endian)
-        let addr_bytes = addr.to_le_bytes();
-        self.send_bytes(&addr_bytes)?;
-
-        // Send length (2 bytes, little endian)
-        let len = data.len() as u16;
-        let len_bytes = len.to_le_bytes();
-        self.send_bytes(&len_bytes)?;
-
-        // Send data
-        self.send_bytes(data)?;
-
-        self.expect_ok()
-            .map_err(|_| SinodudeSerialProgrammerError::CustomRegionWriteFailed(addr))?;
+        let mut payload = address_and_length(addr, data.len()).to_vec();
+        payload.extend_from_slice(data);
+        self.command(Command::WriteCustomRegion, &payload)
+            .map_err(|e| SinodudeSerialProgrammerError::CustomRegionWriteFailed {
+                addr,
+                reason: Box::new(e),
+            })?;
 
         // Verify by reading back
         let read_back = self.read_region(Region::Custom, addr, data.len())?;
@@ -790,406 +1229,810 @@ impl SinodudeSerialProgrammer {
         Ok(())
     }
 
-    pub fn write_customer_id(
+    fn write_chunk(&mut self, addr: u32, data: &[u8]) -> Result<(), SinodudeSerialProgrammerError> {
+        debug!("Writing {} bytes at {:#x}", data.len(), addr);
+        let mut payload = address_and_length(addr, data.len()).to_vec();
+        payload.extend_from_slice(data);
+        self.command(Command::WriteFlash, &payload).map_err(|e| {
+            SinodudeSerialProgrammerError::WriteFailed {
+                addr,
+                reason: Box::new(e),
+            }
+        })
+    }
+
+    fn check_debug(&self, cmd: Command) -> Result<(), SinodudeSerialProgrammerError> {
+        if self.capabilities.supports(cmd) {
+            Ok(())
+        } else {
+            Err(SinodudeSerialProgrammerError::DebugNotSupported)
+        }
+    }
+
+    /// Halt the target's core through JTAG, returning its program counter
+    pub fn debug_halt(&mut self) -> Result<u16, SinodudeSerialProgrammerError> {
+        self.check_debug(Command::DebugHalt)?;
+        debug!("Halting target core...");
+        let data = self.query(Command::DebugHalt, &[])?;
+        let pc_bytes: [u8; 2] = data
+            .try_into()
+            .map_err(|_| SinodudeSerialProgrammerError::InvalidResponse)?;
+        Ok(u16::from_le_bytes(pc_bytes))
+    }
+
+    pub fn debug_resume(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
+        self.check_debug(Command::DebugResume)?;
+        debug!("Resuming target core...");
+        self.command(Command::DebugResume, &[])
+    }
+
+    pub fn debug_read_memory(
         &mut self,
-        data: &[u8; 4],
+        space: MemorySpace,
+        addr: u16,
+        len: usize,
+    ) -> Result<Vec<u8>, SinodudeSerialProgrammerError> {
+        self.check_debug(Command::DebugReadMemory)?;
+        debug!("Reading {} bytes of {:?} at {:#06x}", len, space, addr);
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let params = MemoryAccess {
+                space,
+                addr: addr + data.len() as u16,
+                len: (len - data.len()).min(self.chunk_size()) as u16,
+            };
+            let chunk = self.query(Command::DebugReadMemory, &params.encode())?;
+            if chunk.len() != params.len as usize {
+                return Err(SinodudeSerialProgrammerError::InvalidResponse);
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+
+    pub fn debug_write_memory(
+        &mut self,
+        space: MemorySpace,
+        addr: u16,
+        data: &[u8],
     ) -> Result<(), SinodudeSerialProgrammerError> {
-        let field = &self.chip_type.customer_id;
-        eprintln!("Writing customer ID at {:#x}...", field.address);
-        self.write_custom_region(field.address, data)?;
+        self.check_debug(Command::DebugWriteMemory)?;
+        debug!(
+            "Writing {} bytes of {:?} at {:#06x}",
+            data.len(),
+            space,
+            addr
+        );
+        let chunk_size = self.chunk_size();
+        for (i, chunk) in data.chunks(chunk_size).enumerate() {
+            let params = MemoryAccess {
+                space,
+                addr: addr + (i * chunk_size) as u16,
+                len: chunk.len() as u16,
+            };
+            let mut payload = params.encode().to_vec();
+            payload.extend_from_slice(chunk);
+            self.command(Command::DebugWriteMemory, &payload)?;
+        }
         Ok(())
     }
 
-    pub fn write_operation_number(
+    pub fn debug_step(&mut self) -> Result<u16, SinodudeSerialProgrammerError> {
+        self.check_debug(Command::DebugStep)?;
+        let data = self.query(Command::DebugStep, &[])?;
+        let pc_bytes: [u8; 2] = data
+            .try_into()
+            .map_err(|_| SinodudeSerialProgrammerError::InvalidResponse)?;
+        Ok(u16::from_le_bytes(pc_bytes))
+    }
+
+    pub fn debug_status(&mut self) -> Result<CoreStatus, SinodudeSerialProgrammerError> {
+        self.check_debug(Command::DebugStatus)?;
+        let data = self.query(Command::DebugStatus, &[])?;
+        CoreStatus::decode(&data).ok_or(SinodudeSerialProgrammerError::InvalidResponse)
+    }
+
+    pub fn debug_set_pc(&mut self, pc: u16) -> Result<(), SinodudeSerialProgrammerError> {
+        self.check_debug(Command::DebugSetPc)?;
+        debug!("Setting PC to {:#06x}", pc);
+        self.command(Command::DebugSetPc, &pc.to_le_bytes())
+    }
+
+    pub fn debug_set_breakpoint(
         &mut self,
-        data: &[u8; 2],
+        index: u8,
+        addr: u16,
     ) -> Result<(), SinodudeSerialProgrammerError> {
-        let field = &self.chip_type.operation_number;
-        eprintln!("Writing operation number at {:#x}...", field.address);
-        self.write_custom_region(field.address, data)?;
-        Ok(())
+        self.check_debug(Command::DebugSetBreakpoint)?;
+        debug!("Setting breakpoint {} at {:#06x}", index, addr);
+        self.command(
+            Command::DebugSetBreakpoint,
+            &Breakpoint { index, addr }.encode(),
+        )
     }
 
-    pub fn write_customer_option(
+    pub fn debug_clear_breakpoint(
         &mut self,
-        data: &[u8],
+        index: u8,
     ) -> Result<(), SinodudeSerialProgrammerError> {
-        // Validate length
-        if data.len() > self.chip_type.option_byte_count {
-            return Err(
-                SinodudeSerialProgrammerError::CustomerOptionLengthExceeded {
-                    provided: data.len(),
-                    max: self.chip_type.option_byte_count,
-                },
-            );
-        }
+        self.check_debug(Command::DebugClearBreakpoint)?;
+        debug!("Clearing breakpoint {}", index);
+        self.command(Command::DebugClearBreakpoint, &[index])
+    }
 
-        // Validate that non-editable bits match default values
-        let mask = self.chip_type.code_option_mask;
-        let defaults = self.chip_type.default_code_options;
-        for (i, &byte) in data.iter().enumerate() {
-            if i < mask.len() && i < defaults.len() {
-                // Non-editable bits are where mask is 0
-                // Check that (provided & ~mask) == (default & ~mask)
-                let non_editable_mask = !mask[i];
-                let provided_non_editable = byte & non_editable_mask;
-                let default_non_editable = defaults[i] & non_editable_mask;
-                if provided_non_editable != default_non_editable {
-                    return Err(SinodudeSerialProgrammerError::NonEditableBitsModified {
-                        byte: i,
-                        provided: byte,
-                        expected: (byte & mask[i]) | default_non_editable,
-                        mask: mask[i],
-                    });
-                }
-            }
+    /// Check the firmware and switch to the fastest serial speed, once per session
+    fn open_link(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
+        if !self.link_open {
+            self.ping()?;
+            self.check_version()?;
+            self.negotiate_baud()?;
+            self.link_open = true;
         }
+        Ok(())
+    }
 
-        {
-            let field = &self.chip_type.customer_option;
-            // Split write: first 4 bytes to customer_option.address, rest to 0x1100
-            // Write second region first, then the first region
-            let first_part_size = 4.min(data.len());
-            let second_part_size = data.len().saturating_sub(4);
-
-            if second_part_size > 0 {
-                eprintln!(
-                    "Writing customer option ({} bytes) at {:#x}...",
-                    second_part_size, 0x1100
-                );
-                self.write_custom_region(0x1100, &data[first_part_size..])?;
-            }
-
-            eprintln!(
-                "Writing customer option ({} bytes) at {:#x}...",
-                first_part_size, field.address
-            );
-            self.write_custom_region(field.address, &data[..first_part_size])?;
+    /// Switch the target's power. Powering on leaves ICP, so the next operation
+    /// connects again.
+    pub fn set_power(&mut self, on: bool) -> Result<(), SinodudeSerialProgrammerError> {
+        self.open_link()?;
+        let cmd = if on {
+            Command::PowerOn
+        } else {
+            Command::PowerOff
+        };
+        if !self.capabilities.supports(cmd) {
+            return Err(SinodudeSerialProgrammerError::PowerControlNotSupported);
         }
+        eprintln!("Powering target {}", if on { "on" } else { "off" });
+        self.command(cmd, &[])?;
+        self.connected = false;
         Ok(())
     }
 
-    pub fn write_security(&mut self, data: &[u8]) -> Result<(), SinodudeSerialProgrammerError> {
-        // Only security_level 4 and chip_type 0x07 are supported for writing security
-        if self.chip_type.security_level != 4 || self.chip_type.chip_type != 0x07 {
-            return Err(SinodudeSerialProgrammerError::UnsupportedSecurityWrite {
-                security_level: self.chip_type.security_level,
-                chip_type: self.chip_type.chip_type,
-            });
+    /// Disconnect, unless the target was left powered to run its firmware
+    pub fn finish(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
+        if self.connected {
+            self.disconnect()?;
         }
+        self.restore_baud()
+    }
+
+    /// Switch the firmware back to BAUD_RATE, which it keeps until it is reset, so that
+    /// the next session finds it there even with `--reset none`
+    fn restore_baud(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
+        if self.port.baud_rate().map_err(std::io::Error::from)? == BAUD_RATE {
+            return Ok(());
+        }
+        debug!("Restoring {} baud", BAUD_RATE);
+        self.try_baud(BAUD_RATE)
+    }
+}
+
+impl Programmer for SinodudeSerialProgrammer {
+    fn selected_part(&self) -> Option<&'static Part> {
+        self.chip_type
+    }
 
-        let field = &self.chip_type.security;
-        eprintln!("Writing security at {:#x}...", field.address);
-        self.write_custom_region(field.address, data)?;
+    fn set_part(&mut self, part: &'static Part) -> Result<(), ProgrammerError> {
+        Self::check_voltage(part)?;
+        self.check_chip_type(part.chip_type)?;
+        self.chip_type = Some(part);
         Ok(())
     }
 
-    pub fn write_serial_number(
-        &mut self,
-        data: &[u8; 4],
-    ) -> Result<(), SinodudeSerialProgrammerError> {
-        let field = &self.chip_type.serial_number;
-        eprintln!("Writing serial number at {:#x}...", field.address);
-        self.write_custom_region(field.address, data)?;
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn connect_target(&mut self) -> Result<(), ProgrammerError> {
+        if !self.connected {
+            self.open_link()?;
+            if let Some(part) = self.chip_type {
+                self.check_chip_type(part.chip_type)?;
+            }
+            self.connect()?;
+        }
         Ok(())
     }
 
-    /// Write all custom fields in one transaction (from customer_id to +0x50).
-    /// If `use_stored_defaults` is true, uses stored values from device as defaults
-    /// for fields not provided by the user. If false, unprovided fields are left as zeros.
-    pub fn write_custom_fields(
+    fn read_jtag_id(&mut self) -> Result<u16, ProgrammerError> {
+        Ok(self.get_id()?)
+    }
+
+    fn read_part_number(
         &mut self,
-        customer_id: Option<&[u8; 4]>,
-        operation_number: Option<&[u8; 2]>,
-        customer_option: Option<&[u8]>,
-        security: Option<&[u8]>,
-        serial_number: Option<&[u8; 4]>,
-        use_stored_defaults: bool,
-    ) -> Result<(), SinodudeSerialProgrammerError> {
-        let customer_id_addr = self.chip_type.customer_id.address;
+        chip_type: u8,
+        custom_block: u8,
+    ) -> Result<Option<[u8; 5]>, ProgrammerError> {
+        self.set_config(chip_type)?;
+        let Some(addr) = part_number_block_address(custom_block) else {
+            return Ok(None);
+        };
+        Ok(Some(self.get_part_number(addr)?))
+    }
 
-        const REGION_SIZE: usize = 64;
-        let mut buffer = [0u8; REGION_SIZE];
+    fn read_chunk(&mut self, addr: u32, length: u16) -> Result<Vec<u8>, ProgrammerError> {
+        Ok(SinodudeSerialProgrammer::read_chunk(self, addr, length)?)
+    }
 
-        // Calculate offsets relative to customer_id base address
-        // Use provided value or fall back to stored value from device (if use_stored_defaults)
-        {
-            let stored = if use_stored_defaults {
-                self.stored_customer_id.as_ref()
-            } else {
-                None
-            };
-            let data = customer_id.or(stored);
-            if let Some(data) = data {
-                // customer_id is at offset 0
-                buffer[0..4].copy_from_slice(data);
-            }
-        }
+    fn chunk_size(&self) -> usize {
+        self.capabilities.max_data_len.clamp(1, MAX_DATA_LEN as u16) as usize
+    }
 
-        {
-            let stored = if use_stored_defaults {
-                self.stored_operation_number.as_ref()
-            } else {
-                None
-            };
-            let data = operation_number.or(stored);
-            if let Some(data) = data {
-                let field = &self.chip_type.operation_number;
-                let offset = (field.address - customer_id_addr) as usize;
-                if offset + 2 <= REGION_SIZE {
-                    buffer[offset..offset + 2].copy_from_slice(data);
-                }
-            }
+    fn read_flash_stream(
+        &mut self,
+        addr: u32,
+        len: usize,
+        progress: &mut dyn FnMut(usize),
+    ) -> Result<Vec<u8>, ProgrammerError> {
+        if !self.capabilities.supports(Command::ReadFlashStream) {
+            return self.read_flash_chunked(addr, len, progress);
         }
+        let data = self.read_stream(addr, len, progress)?;
+        self.check_cancelled()?;
+        Ok(data)
+    }
 
-        // Track upper part of customer_option for writing to 0x1100
-        let mut customer_option_upper: Option<Vec<u8>> = None;
-        {
-            let stored = if use_stored_defaults {
-                self.stored_customer_option.as_deref()
-            } else {
-                None
-            };
-            let data = customer_option.or(stored);
-            let field = &self.chip_type.customer_option;
-            let offset = (field.address - customer_id_addr) as usize;
-
-            if let Some(data) = data {
-                // First 4 bytes go to the buffer at customer_option offset
-                let first_part_len = data.len().min(4).min(REGION_SIZE - offset);
-                if offset < REGION_SIZE {
-                    buffer[offset..offset + first_part_len]
-                        .copy_from_slice(&data[..first_part_len]);
-                }
-                // Remaining bytes (if any) go to 0x1100
-                if data.len() > 4 {
-                    customer_option_upper = Some(data[4..].to_vec());
-                }
-            }
+    fn flash_checksum(&mut self, addr: u32, len: usize) -> Result<Option<u32>, ProgrammerError> {
+        if !self.capabilities.supports(Command::FlashCrc) {
+            return Ok(None);
         }
+        Ok(Some(self.flash_crc(addr, len)?))
+    }
 
-        {
-            let stored = if use_stored_defaults {
-                self.stored_security.as_deref()
-            } else {
-                None
-            };
-            let data = security.or(stored);
-            if let Some(data) = data {
-                let field = &self.chip_type.security;
-                let offset = (field.address - customer_id_addr) as usize;
-                let len = data.len().min(REGION_SIZE - offset);
-                if offset < REGION_SIZE {
-                    buffer[offset..offset + len].copy_from_slice(&data[..len]);
-                }
-            }
-        }
+    fn write_chunk(&mut self, addr: u32, data: &[u8]) -> Result<(), ProgrammerError> {
+        Ok(SinodudeSerialProgrammer::write_chunk(self, addr, data)?)
+    }
 
-        {
-            let stored = if use_stored_defaults {
-                self.stored_serial_number.as_ref()
-            } else {
-                None
-            };
-            let data = serial_number.or(stored);
-            if let Some(data) = data {
-                let field = &self.chip_type.serial_number;
-                let offset = (field.address - customer_id_addr) as usize;
-                if offset + 4 <= REGION_SIZE {
-                    buffer[offset..offset + 4].copy_from_slice(data);
-                }
-            }
-        }
+    fn erase_sector(&mut self, addr: u32) -> Result<(), ProgrammerError> {
+        Ok(SinodudeSerialProgrammer::erase_sector(self, addr)?)
+    }
 
-        eprintln!(
-            "Writing custom fields region ({} bytes) at {:#x}...",
-            REGION_SIZE, customer_id_addr
-        );
-        self.write_custom_region(customer_id_addr, &buffer)?;
+    fn mass_erase(&mut self, alternate: bool) -> Result<(), ProgrammerError> {
+        Ok(SinodudeSerialProgrammer::mass_erase(self, alternate)?)
+    }
 
-        // Write upper part of customer_option to 0x1100 if present
-        if let Some(upper) = customer_option_upper {
-            eprintln!(
-                "Writing customer option upper ({} bytes) at {:#x}...",
-                upper.len(),
-                0x1100
-            );
-            self.write_custom_region(0x1100, &upper)?;
-        }
+    fn read_region(
+        &mut self,
+        region: Region,
+        address: u32,
+        size: usize,
+    ) -> Result<Vec<u8>, ProgrammerError> {
+        Ok(SinodudeSerialProgrammer::read_region(
+            self, region, address, size,
+        )?)
+    }
 
-        Ok(())
+    fn write_custom_region(&mut self, addr: u32, data: &[u8]) -> Result<(), ProgrammerError> {
+        Ok(SinodudeSerialProgrammer::write_custom_region(
+            self, addr, data,
+        )?)
     }
 
-    fn write_chunk(&mut self, addr: u32, data: &[u8]) -> Result<(), SinodudeSerialProgrammerError> {
-        debug!("Writing {} bytes at {:#x}", data.len(), addr);
-        self.send_command(cmd::CMD_WRITE_FLASH)?;
+    fn halt(&mut self) -> Result<u16, ProgrammerError> {
+        Ok(self.debug_halt()?)
+    }
 
-        // Send address (4 bytes, little endian)
-        let addr_bytes = addr.to_le_bytes();
-        self.send_bytes(&addr_bytes)?;
+    fn resume(&mut self) -> Result<(), ProgrammerError> {
+        Ok(self.debug_resume()?)
+    }
 
-        // Send length (2 bytes, little endian)
-        let len = data.len() as u16;
-        let len_bytes = len.to_le_bytes();
-        self.send_bytes(&len_bytes)?;
+    fn read_memory(
+        &mut self,
+        space: MemorySpace,
+        addr: u16,
+        len: usize,
+    ) -> Result<Vec<u8>, ProgrammerError> {
+        check_memory_access(space, addr, len, false)?;
+        Ok(self.debug_read_memory(space, addr, len)?)
+    }
 
-        // Send data
-        self.send_bytes(data)?;
+    fn write_memory(
+        &mut self,
+        space: MemorySpace,
+        addr: u16,
+        data: &[u8],
+    ) -> Result<(), ProgrammerError> {
+        check_memory_access(space, addr, data.len(), true)?;
+        Ok(self.debug_write_memory(space, addr, data)?)
+    }
 
-        self.expect_ok()
-            .map_err(|_| SinodudeSerialProgrammerError::WriteFailed(addr))
+    fn step(&mut self) -> Result<u16, ProgrammerError> {
+        Ok(self.debug_step()?)
     }
 
-    pub fn write_flash(&mut self, firmware: &[u8]) -> Result<(), SinodudeSerialProgrammerError> {
-        let flash_size = self.chip_type.flash_size.min(firmware.len());
+    fn core_status(&mut self) -> Result<CoreStatus, ProgrammerError> {
+        Ok(self.debug_status()?)
+    }
 
-        eprintln!("Writing {} bytes to flash...", flash_size);
+    fn set_pc(&mut self, pc: u16) -> Result<(), ProgrammerError> {
+        Ok(self.debug_set_pc(pc)?)
+    }
 
-        let style = ProgressStyle::default_bar()
-            .template("{msg} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap()
-            .progress_chars("=>-");
+    fn set_breakpoint(&mut self, index: usize, addr: u16) -> Result<(), ProgrammerError> {
+        check_breakpoint_index(index)?;
+        Ok(self.debug_set_breakpoint(index as u8, addr)?)
+    }
 
-        // Then write data in chunks
-        let write_progress = ProgressBar::new(flash_size as u64);
-        write_progress.set_style(style.clone());
-        write_progress.set_message("Writing");
+    fn clear_breakpoint(&mut self, index: usize) -> Result<(), ProgrammerError> {
+        check_breakpoint_index(index)?;
+        Ok(self.debug_clear_breakpoint(index as u8)?)
+    }
 
-        let start = Instant::now();
-        for addr in (0..flash_size).step_by(CHUNK_SIZE) {
-            self.check_cancelled().inspect_err(|_| {
-                write_progress.abandon_with_message("Cancelled");
-            })?;
-            let end = (addr + CHUNK_SIZE).min(flash_size);
-            let chunk = &firmware[addr..end];
-            self.write_chunk(addr as u32, chunk).inspect_err(|_| {
-                write_progress.abandon_with_message("Write failed");
-            })?;
-            write_progress.set_position(end as u64);
-        }
-        let elapsed = start.elapsed();
-        write_progress.finish_with_message(format!("Write complete in {:.2?}", elapsed));
-
-        // Verify
-        let verify_progress = ProgressBar::new(flash_size as u64);
-        verify_progress.set_style(style);
-        verify_progress.set_message("Verifying");
-
-        let start = Instant::now();
-        for addr in (0..flash_size).step_by(CHUNK_SIZE) {
-            self.check_cancelled().inspect_err(|_| {
-                verify_progress.abandon_with_message("Cancelled");
-            })?;
-            let end = (addr + CHUNK_SIZE).min(flash_size);
-            let expected = &firmware[addr..end];
-            let actual = self.read_chunk(addr as u32, (end - addr) as u16)?;
-
-            if expected != actual.as_slice() {
-                verify_progress.abandon_with_message("Verify failed");
-                eprintln!("Verification failed at address {:#x}", addr);
-                eprintln!("Expected: {:02x?}", expected);
-                eprintln!("Actual:   {:02x?}", actual);
-                return Err(SinodudeSerialProgrammerError::VerificationFailed(
-                    addr as u32,
-                ));
-            }
-            verify_progress.set_position(end as u64);
-        }
-        let elapsed = start.elapsed();
-        verify_progress.finish_with_message(format!("Verify complete in {:.2?}", elapsed));
+    fn power_on(&mut self) -> Result<(), ProgrammerError> {
+        Ok(self.set_power(true)?)
+    }
 
-        Ok(())
+    fn power_off(&mut self) -> Result<(), ProgrammerError> {
+        Ok(self.set_power(false)?)
     }
 
-    /// Write a specific range of flash (addresses are inclusive of start, exclusive of end)
-    pub fn write_flash_range(
-        &mut self,
-        firmware: &[u8],
-        start_addr: usize,
-        end_addr: usize,
-    ) -> Result<(), SinodudeSerialProgrammerError> {
-        let flash_size = self.chip_type.flash_size.min(firmware.len());
-        let start_addr = start_addr.min(flash_size);
-        let end_addr = end_addr.min(flash_size);
-        let range_size = end_addr.saturating_sub(start_addr);
+    fn finish(&mut self) -> Result<(), ProgrammerError> {
+        Ok(SinodudeSerialProgrammer::finish(self)?)
+    }
+}
 
-        if range_size == 0 {
-            eprintln!("Nothing to write (empty range)");
-            return Ok(());
+impl Drop for SinodudeSerialProgrammer {
+    fn drop(&mut self) {
+        // Best effort cleanup, only if needed
+        if self.connected {
+            let _ = self.disconnect();
         }
+        let _ = self.restore_baud();
+    }
+}
 
-        eprintln!(
-            "Writing {} bytes to flash (range {:#x}-{:#x})...",
-            range_size, start_addr, end_addr
-        );
+#[test]
+fn test_firmware_error_reason() {
+    assert!(matches!(
+        SinodudeSerialProgrammerError::from_firmware(&Failure::new(Reason::NoAck, 0x11).encode()),
+        SinodudeSerialProgrammerError::NoAck(0x11)
+    ));
+    assert!(matches!(
+        SinodudeSerialProgrammerError::from_firmware(
+            &Failure::new(Reason::UnknownCommand, 0x42).encode()
+        ),
+        SinodudeSerialProgrammerError::UnknownCommand(0x42)
+    ));
+    assert!(matches!(
+        SinodudeSerialProgrammerError::from_firmware(&[0x7F, 0x00, 0x00]),
+        SinodudeSerialProgrammerError::UnknownReason { reason: 0x7F, .. }
+    ));
+    assert!(matches!(
+        SinodudeSerialProgrammerError::from_firmware(&[]),
+        SinodudeSerialProgrammerError::OperationFailed
+    ));
+}
 
-        let style = ProgressStyle::default_bar()
-            .template("{msg} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap()
-            .progress_chars("=>-");
+#[test]
+fn test_legacy_capabilities() {
+    let legacy = legacy_capabilities();
+    assert!(legacy.supports(Command::WriteCustomRegion));
+    assert!(!legacy.supports(Command::SetBaud));
+    assert!(!legacy.supports(Command::ReadFlashStream));
+    assert!(!legacy.supports(Command::GetCapabilities));
+    assert_eq!(legacy.max_baud, BAUD_RATE);
+}
 
-        // Write data in chunks
-        let write_progress = ProgressBar::new(range_size as u64);
-        write_progress.set_style(style.clone());
-        write_progress.set_message("Writing");
+#[test]
+fn test_encode_frame() {
+    let encoded = encode_frame(7, Command::SetConfig as u8, &[0x02]);
+    let (header, payload) = frame::decode(&encoded).unwrap();
+    assert_eq!((header.seq, header.code), (7, Command::SetConfig as u8));
+    assert_eq!(payload, [0x02]);
+}
 
-        let start = Instant::now();
-        for addr in (start_addr..end_addr).step_by(CHUNK_SIZE) {
-            self.check_cancelled().inspect_err(|_| {
-                write_progress.abandon_with_message("Cancelled");
-            })?;
-            let end = (addr + CHUNK_SIZE).min(end_addr);
-            let chunk = &firmware[addr..end];
-            self.write_chunk(addr as u32, chunk).inspect_err(|_| {
-                write_progress.abandon_with_message("Write failed");
-            })?;
-            write_progress.set_position((end - start_addr) as u64);
+/// What a [`MockPort`] has been sent and will answer
+#[cfg(test)]
+#[derive(Default)]
+struct MockLine {
+    /// Bytes waiting to be read
+    input: std::collections::VecDeque<u8>,
+    /// Bytes queued as input after each write, in order
+    replies: std::collections::VecDeque<Vec<u8>>,
+    /// Every write, in order
+    written: Vec<Vec<u8>>,
+    /// Rate the port is set to
+    baud: u32,
+    /// Rate the firmware listens at, if it only answers writes at that rate. SetBaud
+    /// frames move it.
+    firmware_baud: Option<u32>,
+}
+
+/// Serial port answering each write with the next scripted reply. Reads time out once
+/// the input runs dry, like a firmware that went quiet.
+#[cfg(test)]
+struct MockPort(Arc<std::sync::Mutex<MockLine>>);
+
+#[cfg(test)]
+impl Read for MockPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut line = self.0.lock().unwrap();
+        if line.input.is_empty() {
+            return Err(std::io::ErrorKind::TimedOut.into());
         }
-        let elapsed = start.elapsed();
-        write_progress.finish_with_message(format!("Write complete in {:.2?}", elapsed));
-
-        // Verify
-        let verify_progress = ProgressBar::new(range_size as u64);
-        verify_progress.set_style(style);
-        verify_progress.set_message("Verifying");
-
-        let start = Instant::now();
-        for addr in (start_addr..end_addr).step_by(CHUNK_SIZE) {
-            self.check_cancelled().inspect_err(|_| {
-                verify_progress.abandon_with_message("Cancelled");
-            })?;
-            let end = (addr + CHUNK_SIZE).min(end_addr);
-            let expected = &firmware[addr..end];
-            let actual = self.read_chunk(addr as u32, (end - addr) as u16)?;
-
-            if expected != actual.as_slice() {
-                verify_progress.abandon_with_message("Verify failed");
-                eprintln!("Verification failed at address {:#x}", addr);
-                eprintln!("Expected: {:02x?}", expected);
-                eprintln!("Actual:   {:02x?}", actual);
-                return Err(SinodudeSerialProgrammerError::VerificationFailed(
-                    addr as u32,
-                ));
+        let len = buf.len().min(line.input.len());
+        for (dst, src) in buf.iter_mut().zip(line.input.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+impl Write for MockPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut line = self.0.lock().unwrap();
+        line.written.push(buf.to_vec());
+        if line.firmware_baud.is_some_and(|baud| baud != line.baud) {
+            return Ok(buf.len());
+        }
+        if let Some(reply) = line.replies.pop_front() {
+            line.input.extend(reply);
+        }
+        if let Ok((header, payload)) = frame::decode(buf) {
+            if header.code == Command::SetBaud as u8 && line.firmware_baud.is_some() {
+                line.firmware_baud = Some(u32::from_le_bytes(payload.try_into().unwrap()));
             }
-            verify_progress.set_position((end - start_addr) as u64);
         }
-        let elapsed = start.elapsed();
-        verify_progress.finish_with_message(format!("Verify complete in {:.2?}", elapsed));
+        Ok(buf.len())
+    }
 
+    fn flush(&mut self) -> std::io::Result<()> {
         Ok(())
     }
+}
 
-    pub fn finish(&mut self) -> Result<(), SinodudeSerialProgrammerError> {
-        self.disconnect()?;
+#[cfg(test)]
+impl serialport::SerialPort for MockPort {
+    fn name(&self) -> Option<String> {
+        None
+    }
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.0.lock().unwrap().baud)
+    }
+    fn data_bits(&self) -> serialport::Result<serialport::DataBits> {
+        Ok(serialport::DataBits::Eight)
+    }
+    fn flow_control(&self) -> serialport::Result<serialport::FlowControl> {
+        Ok(serialport::FlowControl::None)
+    }
+    fn parity(&self) -> serialport::Result<serialport::Parity> {
+        Ok(serialport::Parity::None)
+    }
+    fn stop_bits(&self) -> serialport::Result<serialport::StopBits> {
+        Ok(serialport::StopBits::One)
+    }
+    fn timeout(&self) -> Duration {
+        TIMEOUT
+    }
+    fn set_baud_rate(&mut self, baud: u32) -> serialport::Result<()> {
+        self.0.lock().unwrap().baud = baud;
+        Ok(())
+    }
+    fn set_data_bits(&mut self, _: serialport::DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_flow_control(&mut self, _: serialport::FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_parity(&mut self, _: serialport::Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_stop_bits(&mut self, _: serialport::StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_timeout(&mut self, _: Duration) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.0.lock().unwrap().input.len() as u32)
+    }
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+    fn clear(&self, buffer_to_clear: serialport::ClearBuffer) -> serialport::Result<()> {
+        if buffer_to_clear != serialport::ClearBuffer::Output {
+            self.0.lock().unwrap().input.clear();
+        }
+        Ok(())
+    }
+    fn try_clone(&self) -> serialport::Result<Box<dyn serialport::SerialPort>> {
+        Ok(Box::new(MockPort(self.0.clone())))
+    }
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn clear_break(&self) -> serialport::Result<()> {
         Ok(())
     }
 }
 
-impl Drop for SinodudeSerialProgrammer {
-    fn drop(&mut self) {
-        // Best effort cleanup, only if needed
-        if self.connected {
-            let _ = self.disconnect();
-        }
+/// A programmer connected to a target, talking to a [`MockPort`] that answers with `replies`
+#[cfg(test)]
+fn mock_programmer(
+    replies: Vec<Vec<u8>>,
+) -> (SinodudeSerialProgrammer, Arc<std::sync::Mutex<MockLine>>) {
+    let line = Arc::new(std::sync::Mutex::new(MockLine {
+        replies: replies.into(),
+        baud: BAUD_RATE,
+        ..Default::default()
+    }));
+    let programmer = SinodudeSerialProgrammer {
+        port: Box::new(MockPort(line.clone())),
+        port_name: "mock".to_string(),
+        seq: 0,
+        capabilities: Capabilities {
+            commands: u32::MAX,
+            ..legacy_capabilities()
+        },
+        legacy: false,
+        chip_type: None,
+        link_open: true,
+        connected: true,
+        cancelled: Arc::new(AtomicBool::new(false)),
+    };
+    (programmer, line)
+}
+
+/// A data frame of a flash stream
+#[cfg(test)]
+fn stream_chunk(seq: u8, addr: u32, chunk: &[u8]) -> Vec<u8> {
+    let mut payload = addr.to_le_bytes().to_vec();
+    payload.extend_from_slice(chunk);
+    encode_frame(seq, Response::Data as u8, &payload)
+}
+
+/// The frame ending a flash stream after `sent` bytes
+#[cfg(test)]
+fn stream_end(seq: u8, sent: u32) -> Vec<u8> {
+    encode_frame(seq, Response::Ok as u8, &sent.to_le_bytes())
+}
+
+#[cfg(test)]
+fn stream_request(seq: u8, addr: u32, len: u32) -> Vec<u8> {
+    encode_frame(
+        seq,
+        Command::ReadFlashStream as u8,
+        &FlashRange { addr, len }.encode(),
+    )
+}
+
+#[test]
+fn test_read_stream_resumes_after_gap() {
+    let (mut programmer, line) = mock_programmer(vec![
+        // The second chunk skips 4 bytes
+        [stream_chunk(1, 0, &[1; 4]), stream_chunk(1, 8, &[3; 4])].concat(),
+        // Still in flight when the stream is aborted
+        [stream_chunk(1, 12, &[4; 4]), stream_end(1, 12)].concat(),
+        [
+            stream_chunk(2, 4, &[2; 4]),
+            stream_chunk(2, 8, &[3; 4]),
+            stream_chunk(2, 12, &[4; 4]),
+            stream_end(2, 12),
+        ]
+        .concat(),
+    ]);
+
+    let data = programmer.read_stream(0, 16, &mut |_| {}).unwrap();
+    assert_eq!(data, [[1; 4], [2; 4], [3; 4], [4; 4]].concat());
+    let written = &line.lock().unwrap().written;
+    assert_eq!(
+        written[..],
+        [
+            stream_request(1, 0, 16),
+            vec![STREAM_ABORT],
+            stream_request(2, 4, 12)
+        ]
+    );
+}
+
+#[test]
+fn test_read_stream_truncated() {
+    // Every attempt ends before sending all data
+    let mut replies = vec![
+        [stream_chunk(1, 0, &[1; 4]), stream_end(1, 4)].concat(),
+        Vec::new(),
+    ];
+    for seq in 2..=(MAX_RETRIES as u8 + 1) {
+        replies.extend([stream_end(seq, 0), Vec::new()]);
     }
+    let (mut programmer, line) = mock_programmer(replies);
+
+    assert!(matches!(
+        programmer.read_stream(0, 8, &mut |_| {}),
+        Err(SinodudeSerialProgrammerError::StreamTruncated {
+            expected: 4,
+            received: 0
+        })
+    ));
+    // Each attempt, including the last, is followed by an abort
+    let written = &line.lock().unwrap().written;
+    assert_eq!(written.len(), 2 * (MAX_RETRIES + 1));
+    assert_eq!(written.last().unwrap(), &[STREAM_ABORT]);
+    assert_eq!(written[2], stream_request(2, 4, 4));
+}
+
+#[test]
+fn test_read_stream_cancelled() {
+    let (mut programmer, line) = mock_programmer(vec![
+        [stream_chunk(1, 0, &[1; 4]), stream_chunk(1, 4, &[2; 4])].concat(),
+        [stream_chunk(1, 8, &[3; 4]), stream_end(1, 12)].concat(),
+    ]);
+    programmer.cancelled.store(true, Ordering::SeqCst);
+
+    // Stops after the first chunk and drains the rest of the stream
+    assert_eq!(programmer.read_stream(0, 12, &mut |_| {}).unwrap(), [1; 4]);
+    let line = line.lock().unwrap();
+    assert_eq!(line.written[1], [STREAM_ABORT]);
+    assert!(line.input.is_empty());
+}
+
+#[test]
+fn test_frame_error_retransmits() {
+    // A stale response is skipped, then the firmware rejects the request with seq 0 and
+    // answers the retransmission. Mass erase does not retry timeouts, so only the
+    // FrameError can cause the retransmission.
+    let (mut programmer, line) = mock_programmer(vec![
+        [
+            encode_frame(7, Response::Ok as u8, &[]),
+            encode_frame(0, Response::FrameError as u8, &[]),
+        ]
+        .concat(),
+        encode_frame(1, Response::Ok as u8, &[]),
+    ]);
+
+    programmer.mass_erase(false).unwrap();
+    let request = encode_frame(1, Command::MassErase as u8, &[0]);
+    assert_eq!(line.lock().unwrap().written, [request.clone(), request]);
+}
+
+#[test]
+fn test_mass_erase_host_timeout() {
+    // The mass erase is never answered, the disconnect and connect of the power cycle are
+    let (mut programmer, line) = mock_programmer(vec![
+        Vec::new(),
+        encode_frame(2, Response::Ok as u8, &[]),
+        encode_frame(3, Response::Ok as u8, &[]),
+    ]);
+
+    assert!(matches!(
+        programmer.mass_erase(false),
+        Err(SinodudeSerialProgrammerError::MassEraseTimeout)
+    ));
+    assert!(programmer.connected);
+    let written = &line.lock().unwrap().written;
+    assert_eq!(
+        written[..],
+        [
+            encode_frame(1, Command::MassErase as u8, &[0]),
+            encode_frame(2, Command::Disconnect as u8, &[]),
+            encode_frame(3, Command::Connect as u8, &[]),
+        ]
+    );
+}
+
+#[test]
+fn test_finish_restores_baud() {
+    let (mut programmer, line) = mock_programmer(vec![
+        encode_frame(1, Response::Ok as u8, &[]),
+        encode_frame(2, Response::Ok as u8, b"SW"),
+    ]);
+    programmer.connected = false;
+    line.lock().unwrap().baud = 1_000_000;
+
+    programmer.finish().unwrap();
+    let line = line.lock().unwrap();
+    assert_eq!(line.baud, BAUD_RATE);
+    assert_eq!(
+        line.written[0],
+        encode_frame(1, Command::SetBaud as u8, &BAUD_RATE.to_le_bytes())
+    );
+}
+
+#[test]
+fn test_wait_ready_finds_other_rate() {
+    // Left at 500000 baud by a session that did not end cleanly
+    let (mut programmer, line) = mock_programmer(vec![
+        encode_frame(0, Response::Ok as u8, b"SW"),
+        encode_frame(1, Response::Ok as u8, &[]),
+        encode_frame(2, Response::Ok as u8, b"SW"),
+    ]);
+    line.lock().unwrap().firmware_baud = Some(500_000);
+
+    programmer
+        .wait_ready(Duration::from_secs(1), &BAUD_RATES)
+        .unwrap();
+    let line = line.lock().unwrap();
+    assert_eq!(line.baud, BAUD_RATE);
+    assert_eq!(line.firmware_baud, Some(BAUD_RATE));
+    // One unanswered ping at each slower rate first
+    assert_eq!(line.written.len(), 5);
+}
+
+#[test]
+fn test_probe() {
+    // 2.x firmware answers the bare ping byte unframed
+    let (mut programmer, line) = mock_programmer(vec![vec![0x00, b'S', b'W']]);
+    assert!(programmer.probe().unwrap());
+    assert!(programmer.legacy);
+    assert_eq!(line.lock().unwrap().written, [[Command::Ping as u8]]);
+
+    let (mut programmer, _) = mock_programmer(vec![encode_frame(0, Response::Ok as u8, b"SW")]);
+    programmer.legacy = true;
+    assert!(programmer.probe().unwrap());
+    assert!(!programmer.legacy);
+
+    // Still in the bootloader
+    let (mut programmer, _) = mock_programmer(vec![]);
+    assert!(!programmer.probe().unwrap());
+}
+
+#[test]
+fn test_legacy_transact() {
+    let (mut programmer, line) =
+        mock_programmer(vec![vec![0x01, 4, 0, 0xAA, 0xBB, 0xCC, 0xDD], vec![0xFF]]);
+    programmer.legacy = true;
+    programmer.capabilities = legacy_capabilities();
+
+    assert_eq!(
+        programmer.read_region(Region::Flash, 0x1234, 4).unwrap(),
+        [0xAA, 0xBB, 0xCC, 0xDD]
+    );
+    assert!(matches!(
+        programmer.erase_sector(0x200),
+        Err(SinodudeSerialProgrammerError::EraseFailed { addr: 0x200, .. })
+    ));
+    // Commands 2.x firmware does not know are never sent
+    assert!(matches!(
+        programmer.flash_crc(0, 0x100),
+        Err(SinodudeSerialProgrammerError::UnknownCommand(_))
+    ));
+    assert_eq!(
+        line.lock().unwrap().written,
+        [
+            vec![Command::ReadFlash as u8, 0x34, 0x12, 0, 0, 4, 0],
+            vec![Command::EraseFlashSector as u8, 0x00, 0x02, 0, 0],
+        ]
+    );
+}
+
+#[test]
+fn test_check_version() {
+    let (mut programmer, _) = mock_programmer(vec![vec![0x01, 1, 4]]);
+    programmer.legacy = true;
+    assert!(matches!(
+        programmer.check_version(),
+        Err(SinodudeSerialProgrammerError::LegacyVersionMismatch {
+            expected: LEGACY_VERSION_MAJOR,
+            actual: 1
+        })
+    ));
+
+    let (mut programmer, _) = mock_programmer(vec![encode_frame(1, Response::Data as u8, &[2, 9])]);
+    assert!(matches!(
+        programmer.check_version(),
+        Err(SinodudeSerialProgrammerError::VersionMismatch {
+            expected: MIN_VERSION_MAJOR,
+            actual: 2
+        })
+    ));
 }